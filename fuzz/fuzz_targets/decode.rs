@@ -0,0 +1,46 @@
+//! Feed arbitrary bytes to `Decoder::decode`, as a hostile peer could
+//!
+//! Run with `cargo +nightly fuzz run decode` from the repository root.
+//! The first byte picks the decoder's setup: before or after the
+//! handshake, and which protocol version.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../../src/protocol/mod.rs"]
+mod protocol;
+
+use protocol::{Decoder, MAX_HANDSHAKE_MESSAGE_SIZE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&setup, data)) = data.split_first() else {
+        return;
+    };
+
+    let version = if setup & 1 == 0 { PROTOCOL_VERSION } else { MIN_PROTOCOL_VERSION };
+    let mut decoder = Decoder::for_version(version);
+    if setup & 2 == 0 {
+        decoder.set_max_size(MAX_HANDSHAKE_MESSAGE_SIZE);
+    }
+
+    // Arrive in pieces, as reads off a socket do
+    let chunk = usize::from(setup >> 2).max(1);
+    let mut buf = BytesMut::new();
+    for piece in data.chunks(chunk) {
+        buf.extend_from_slice(piece);
+        loop {
+            match decoder.decode(&mut buf) {
+                Ok(Some(frame)) => {
+                    // Whatever decodes must encode again
+                    let mut out = BytesMut::new();
+                    protocol::Encoder::new().encode(&frame.message, &mut out).unwrap();
+                }
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+    }
+});
@@ -140,17 +140,105 @@ pub struct NetworkConfig {
     /// Port to listen on
     #[serde(default = "default_port")]
     pub port: u16,
-    /// Interface to bind to (default: all)
-    pub bind_address: Option<String>,
+    /// Addresses to listen on, e.g. `"192.168.1.5"`, `"[::]"` or
+    /// `"10.0.0.2:24801"` (default: all interfaces, IPv4 and IPv6).
+    /// A single string is accepted too.
+    #[serde(default, alias = "bind_address", deserialize_with = "string_or_list")]
+    pub bind_addresses: Vec<String>,
+    /// Unix domain socket to listen on (server) or connect to (client)
+    /// instead of TCP
+    #[serde(default)]
+    pub socket_path: Option<PathBuf>,
+    /// Also accept QUIC on the same port (server), or connect over QUIC
+    /// (client), so clipboard transfers don't delay input
+    #[serde(default)]
+    pub quic: bool,
+    /// Send pointer motion over UDP, where it can be dropped instead of
+    /// delayed; buttons and keys stay on the main connection
+    #[serde(default)]
+    pub udp_motion: bool,
+    /// Accept WebSocket connections on the same port (server), or connect
+    /// over WebSocket (client) to get through HTTP-only networks
+    #[serde(default)]
+    pub websocket: bool,
+    /// HTTP proxy to reach the server through over WebSocket, as
+    /// `host:port` or `http://host:port`; unset uses `HTTPS_PROXY` (client)
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Clients the server connects out to, as `host` or `host:port`, for
+    /// clients behind NAT or a firewall that run `corenet client --listen`
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub reverse_clients: Vec<String>,
+    /// Wait for the server to connect in instead of connecting to it (client)
+    #[serde(default)]
+    pub listen: bool,
+    /// Relay that the server registers with and clients reach it through,
+    /// as `host` or `host:port`, for hosts that can't reach each other
+    #[serde(default)]
+    pub relay: Option<String>,
+    /// Secret the server and its clients present to the relay
+    #[serde(default)]
+    pub relay_token: Option<String>,
     /// Connection timeout in ms
     #[serde(default = "default_connect_timeout")]
     pub connect_timeout_ms: u64,
     /// Heartbeat interval in ms
     #[serde(default = "default_heartbeat_interval")]
     pub heartbeat_interval_ms: u64,
+    /// Disconnect a peer after this many heartbeat intervals without
+    /// hearing from it (0 = never)
+    #[serde(default = "default_missed_heartbeats")]
+    pub missed_heartbeats: u32,
     /// Enable mDNS discovery
     #[serde(default = "default_true")]
     pub enable_discovery: bool,
+    /// How long the server keeps a dropped client's session (layout slot,
+    /// control) for it to resume, in ms (0 = don't keep sessions)
+    #[serde(default = "default_resume_grace")]
+    pub resume_grace_ms: u64,
+    /// Drop a client that hasn't finished its handshake within this many ms
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout_ms: u64,
+    /// Most clients the server holds at once, including dropped sessions
+    /// awaiting resume (0 = unlimited)
+    #[serde(default = "default_max_clients")]
+    pub max_clients: usize,
+    /// Most handshakes the server runs at once (0 = unlimited)
+    #[serde(default = "default_max_pending_handshakes")]
+    pub max_pending_handshakes: usize,
+    /// Connections one address may open per minute (0 = unlimited)
+    #[serde(default = "default_max_connections_per_minute")]
+    pub max_connections_per_minute: u32,
+    /// A client connecting with a host ID that is already connected:
+    /// "replace" drops the old session, "reject" turns the newcomer away
+    #[serde(default = "default_duplicate_hosts")]
+    pub duplicate_hosts: String,
+    /// Reconnect to the server automatically when the connection drops
+    #[serde(default = "default_true")]
+    pub reconnect: bool,
+    /// Longest wait between reconnect attempts in ms
+    #[serde(default = "default_reconnect_max_delay")]
+    pub reconnect_max_delay_ms: u64,
+    /// Give up after this many failed reconnect attempts (default: never)
+    pub reconnect_max_attempts: Option<u32>,
+}
+
+/// Accept either `key = "value"` or `key = ["a", "b"]`
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::One(s) => vec![s],
+        StringOrList::Many(v) => v,
+    })
 }
 
 fn default_port() -> u16 {
@@ -165,6 +253,38 @@ fn default_heartbeat_interval() -> u64 {
     1000
 }
 
+fn default_missed_heartbeats() -> u32 {
+    3
+}
+
+fn default_resume_grace() -> u64 {
+    10_000
+}
+
+fn default_handshake_timeout() -> u64 {
+    10_000
+}
+
+fn default_max_clients() -> usize {
+    16
+}
+
+fn default_max_pending_handshakes() -> usize {
+    32
+}
+
+fn default_max_connections_per_minute() -> u32 {
+    60
+}
+
+fn default_duplicate_hosts() -> String {
+    "replace".to_string()
+}
+
+fn default_reconnect_max_delay() -> u64 {
+    30_000
+}
+
 fn default_true() -> bool {
     true
 }
@@ -173,10 +293,29 @@ impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
             port: default_port(),
-            bind_address: None,
+            bind_addresses: Vec::new(),
+            socket_path: None,
+            quic: false,
+            udp_motion: false,
+            websocket: false,
+            proxy: None,
+            reverse_clients: Vec::new(),
+            listen: false,
+            relay: None,
+            relay_token: None,
             connect_timeout_ms: default_connect_timeout(),
             heartbeat_interval_ms: default_heartbeat_interval(),
+            missed_heartbeats: default_missed_heartbeats(),
             enable_discovery: default_true(),
+            resume_grace_ms: default_resume_grace(),
+            handshake_timeout_ms: default_handshake_timeout(),
+            max_clients: default_max_clients(),
+            max_pending_handshakes: default_max_pending_handshakes(),
+            max_connections_per_minute: default_max_connections_per_minute(),
+            duplicate_hosts: default_duplicate_hosts(),
+            reconnect: default_true(),
+            reconnect_max_delay_ms: default_reconnect_max_delay(),
+            reconnect_max_attempts: None,
         }
     }
 }
@@ -191,9 +330,18 @@ pub struct SecurityConfig {
     pub certificate: Option<PathBuf>,
     /// Path to TLS private key
     pub key: Option<PathBuf>,
-    /// Allowed hosts (empty = allow all)
+    /// Path to the CA certificate clients use to verify the server
+    pub ca_certificate: Option<PathBuf>,
+    /// Only accept clients paired with `corenet pair`
+    #[serde(default = "default_true")]
+    pub require_pairing: bool,
+    /// Allowed hosts (empty = allow all). Each entry is an IP address or
+    /// CIDR network, a `SHA256:` certificate fingerprint, or a host ID.
     #[serde(default)]
     pub allowed_hosts: Vec<String>,
+    /// Denied hosts, same format as `allowed_hosts`; takes precedence
+    #[serde(default)]
+    pub denied_hosts: Vec<String>,
 }
 
 impl Default for SecurityConfig {
@@ -202,7 +350,10 @@ impl Default for SecurityConfig {
             require_tls: false, // Disabled by default for easier setup
             certificate: None,
             key: None,
+            ca_certificate: None,
+            require_pairing: true,
             allowed_hosts: Vec::new(),
+            denied_hosts: Vec::new(),
         }
     }
 }
@@ -341,6 +492,17 @@ impl Config {
     }
 }
 
+/// Directory holding the config file, host identity and known hosts
+pub fn config_dir(config_path: Option<&Path>) -> PathBuf {
+    match config_path.and_then(|p| p.parent()) {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        Some(_) => PathBuf::from("."),
+        None => dirs::config_dir()
+            .map(|p| p.join("corenet"))
+            .unwrap_or_else(|| PathBuf::from(".")),
+    }
+}
+
 /// Generate a sample configuration file
 pub fn generate_sample_config() -> String {
     let config = Config {
@@ -384,6 +546,16 @@ mod tests {
         assert_eq!(loaded.network.port, config.network.port);
     }
 
+    #[test]
+    fn test_bind_address_string_or_list() {
+        let config: NetworkConfig = toml::from_str(r#"bind_address = "192.168.1.5""#).unwrap();
+        assert_eq!(config.bind_addresses, vec!["192.168.1.5"]);
+
+        let config: NetworkConfig =
+            toml::from_str(r#"bind_addresses = ["0.0.0.0", "[::]"]"#).unwrap();
+        assert_eq!(config.bind_addresses, vec!["0.0.0.0", "[::]"]);
+    }
+
     #[test]
     fn test_sample_config() {
         let sample = generate_sample_config();
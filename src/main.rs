@@ -9,8 +9,9 @@ mod network;
 mod protocol;
 mod screen;
 
+use std::io::Write;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
@@ -19,8 +20,11 @@ use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 use config::Config;
 use input::{InputCapture, InputEvent, InputInjector};
-use network::{Client, ClientEvent, NetworkConfig as NetConfig, Server, ServerEvent};
-use protocol::{Message, ScreenEdge, ScreenInfo};
+use network::{
+    Client, ClientEvent, ConnectionStats, Identity, NetworkConfig as NetConfig, ReconnectPolicy, Relay, Server, ServerEvent,
+    DEFAULT_RELAY_PORT, KNOWN_HOSTS_FILE, PAIRED_HOSTS_FILE,
+};
+use protocol::{Message, MouseButton, ScreenEdge, ScreenInfo};
 use screen::{get_screen_dimensions, EdgeDetectResult, EdgeDetector, EdgeDetectorConfig, ScreenLayout};
 
 /// CoreNet - Cross-host I/O device sharing
@@ -54,14 +58,44 @@ enum Commands {
         #[arg(short, long)]
         name: Option<String>,
 
+        /// Address to listen on (repeatable, e.g. --bind 192.168.1.5 --bind [::])
+        #[arg(long = "bind", value_name = "ADDR")]
+        bind: Vec<String>,
+
+        /// Listen on this Unix domain socket instead of TCP
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+
+        /// Also accept QUIC on the same port
+        #[arg(long)]
+        quic: bool,
+
+        /// Also accept WebSocket connections on the same port
+        #[arg(long)]
+        websocket: bool,
+
+        /// Connect out to a client running with --listen (repeatable, e.g.
+        /// --connect-to 10.0.0.7 --connect-to laptop:24801)
+        #[arg(long = "connect-to", value_name = "ADDR")]
+        connect_to: Vec<String>,
+
+        /// Also take clients through this relay (needs relay_token in the config)
+        #[arg(long, value_name = "ADDR")]
+        relay: Option<String>,
+
         /// Disable TLS (not recommended)
         #[arg(long)]
         no_tls: bool,
+
+        /// Show a pairing code and let one new client pair while serving
+        #[arg(long)]
+        pair: bool,
     },
 
     /// Run as client (connect to a server)
     Client {
-        /// Server address to connect to
+        /// Server host name or address to connect to (with --relay, the
+        /// server's host ID)
         #[arg(short, long)]
         server: Option<String>,
 
@@ -72,6 +106,58 @@ enum Commands {
         /// Use auto-discovery to find servers
         #[arg(short, long)]
         discover: bool,
+
+        /// Connect through this Unix domain socket instead of TCP
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+
+        /// Connect over QUIC instead of TCP
+        #[arg(long)]
+        quic: bool,
+
+        /// Connect over WebSocket, for networks that only allow HTTP
+        #[arg(long)]
+        websocket: bool,
+
+        /// Tunnel the WebSocket connection through this HTTP proxy
+        /// (defaults to HTTPS_PROXY from the environment)
+        #[arg(long, value_name = "HOST:PORT")]
+        proxy: Option<String>,
+
+        /// Wait for a server started with --connect-to to connect in,
+        /// listening on the port (and --bind addresses from the config)
+        #[arg(long)]
+        listen: bool,
+
+        /// Reach the server through this relay (needs relay_token in the config)
+        #[arg(long, value_name = "ADDR")]
+        relay: Option<String>,
+
+        /// Disable TLS (not recommended)
+        #[arg(long)]
+        no_tls: bool,
+    },
+
+    /// Run a relay that joins servers and clients that can't reach each other
+    Relay {
+        /// Port to listen on
+        #[arg(short, long, default_value_t = DEFAULT_RELAY_PORT)]
+        port: u16,
+
+        /// Address to listen on (repeatable)
+        #[arg(long = "bind", value_name = "ADDR")]
+        bind: Vec<String>,
+    },
+
+    /// Pair a client with a server using a short code
+    Pair {
+        /// Server to pair with (omit on the server to wait for a client)
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Server port
+        #[arg(short, long, default_value_t = protocol::DEFAULT_PORT)]
+        port: u16,
     },
 
     /// Show current configuration
@@ -113,22 +199,74 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     // Load configuration
-    let config = if let Some(config_path) = &cli.config {
+    let mut config = if let Some(config_path) = &cli.config {
         Config::load(config_path)?
     } else {
         Config::load_default().unwrap_or_default()
     };
+    let config_dir = config::config_dir(cli.config.as_deref());
 
     match cli.command {
-        Commands::Server { port, name, no_tls } => {
-            run_server(config, port, name, !no_tls).await?;
+        Commands::Server {
+            port,
+            name,
+            bind,
+            socket,
+            quic,
+            websocket,
+            connect_to,
+            relay,
+            no_tls,
+            pair,
+        } => {
+            if !bind.is_empty() {
+                config.network.bind_addresses = bind;
+            }
+            if socket.is_some() {
+                config.network.socket_path = socket;
+            }
+            if !connect_to.is_empty() {
+                config.network.reverse_clients = connect_to;
+            }
+            if relay.is_some() {
+                config.network.relay = relay;
+            }
+            config.network.quic |= quic;
+            config.network.websocket |= websocket;
+            run_server(config, &config_dir, port, name, !no_tls, pair).await?;
         }
         Commands::Client {
             server,
             port,
             discover,
+            socket,
+            quic,
+            websocket,
+            proxy,
+            listen,
+            relay,
+            no_tls,
         } => {
-            run_client(config, server, port, discover).await?;
+            if socket.is_some() {
+                config.network.socket_path = socket;
+            }
+            if proxy.is_some() {
+                config.network.proxy = proxy;
+            }
+            if relay.is_some() {
+                config.network.relay = relay;
+            }
+            config.network.quic |= quic;
+            config.network.websocket |= websocket;
+            config.network.listen |= listen;
+            run_client(config, &config_dir, server, port, discover, !no_tls).await?;
+        }
+        Commands::Relay { port, bind } => {
+            let listeners = network::bind_listeners(&bind, port)?;
+            Relay::new().serve(listeners).await?;
+        }
+        Commands::Pair { server, port } => {
+            run_pair(config, &config_dir, server, port).await?;
         }
         Commands::Config { generate, output } => {
             if generate {
@@ -211,21 +349,135 @@ fn input_event_to_message(event: &InputEvent) -> Option<Message> {
     }
 }
 
+/// Build the network configuration from the config file and CLI flags.
+/// Without a configured certificate, this host's self-signed identity is used.
+fn network_config(
+    config: &Config,
+    config_dir: &Path,
+    host_name: &str,
+    port: u16,
+    use_tls: bool,
+) -> anyhow::Result<NetConfig> {
+    let security = &config.security;
+    let mut net_config = NetConfig::new(port).require_tls(security.require_tls);
+    net_config.bind_addresses = config.network.bind_addresses.clone();
+    net_config.socket_path = config
+        .network
+        .socket_path
+        .as_ref()
+        .map(|path| path.to_string_lossy().to_string());
+    net_config.quic = config.network.quic;
+    net_config.udp_motion = config.network.udp_motion;
+    net_config.websocket = config.network.websocket;
+    net_config.proxy = config.network.proxy.clone();
+    net_config.reverse_clients = config.network.reverse_clients.clone();
+    net_config.relay = config.network.relay.clone();
+    net_config.relay_token = config.network.relay_token.clone();
+    net_config.connect_timeout_ms = config.network.connect_timeout_ms;
+    net_config.heartbeat_interval_ms = config.network.heartbeat_interval_ms;
+    net_config.missed_heartbeats = config.network.missed_heartbeats;
+    net_config.resume_grace_ms = config.network.resume_grace_ms;
+    net_config.handshake_timeout_ms = config.network.handshake_timeout_ms;
+    net_config.max_clients = config.network.max_clients;
+    net_config.max_pending_handshakes = config.network.max_pending_handshakes;
+    net_config.max_connections_per_minute = config.network.max_connections_per_minute;
+    net_config.duplicate_hosts = config
+        .network
+        .duplicate_hosts
+        .parse()
+        .map_err(|e: String| anyhow::anyhow!(e))?;
+    net_config.known_hosts_path = Some(
+        config_dir.join(KNOWN_HOSTS_FILE).to_string_lossy().to_string(),
+    );
+    net_config.require_pairing = security.require_pairing;
+    net_config.allowed_hosts = security.allowed_hosts.clone();
+    net_config.denied_hosts = security.denied_hosts.clone();
+    net_config.paired_hosts_path = Some(
+        config_dir.join(PAIRED_HOSTS_FILE).to_string_lossy().to_string(),
+    );
+
+    if !use_tls {
+        return Ok(net_config.without_tls());
+    }
+
+    if let (Some(cert), Some(key)) = (&security.certificate, &security.key) {
+        net_config = net_config.with_tls(
+            cert.to_string_lossy().to_string(),
+            key.to_string_lossy().to_string(),
+        );
+    } else {
+        let identity = Identity::load_or_generate(config_dir, host_name)?;
+        net_config = net_config.with_tls(
+            identity.cert_path.to_string_lossy().to_string(),
+            identity.key_path.to_string_lossy().to_string(),
+        );
+    }
+    if let Some(ca) = &security.ca_certificate {
+        net_config = net_config.with_ca(ca.to_string_lossy().to_string());
+    }
+
+    Ok(net_config)
+}
+
+/// Ask on the terminal whether to trust a server seen for the first time
+fn prompt_trust_server(host: &str, fingerprint: &str) -> bool {
+    println!("\nThe server at {} is not known.", host);
+    println!("Its certificate fingerprint is:\n  {}", fingerprint);
+    println!("Compare it with the fingerprint shown by the server.");
+    print!("Trust this server and remember it? [y/N] ");
+    let _ = std::io::stdout().flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// How often link statistics are logged
+const LINK_STATS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Summarize RTT, jitter, loss and send queue for the log
+fn format_link_stats(stats: &ConnectionStats) -> String {
+    let ms = |us: u64| us as f64 / 1000.0;
+    format!(
+        "rtt {:.1} ms (min {:.1}, avg {:.1}, p99 {:.1}), jitter {:.1} ms, loss {:.1}%, \
+         {} queued, {} motion events merged",
+        ms(stats.rtt_us),
+        ms(stats.rtt_min_us),
+        ms(stats.rtt_avg_us),
+        ms(stats.rtt_p99_us),
+        ms(stats.jitter_us),
+        stats.loss() * 100.0,
+        stats.send_queue_depth,
+        stats.motion_coalesced
+    )
+}
+
 /// State for tracking which host has control
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum ControlState {
     /// Input is going to local machine
     Local,
     /// Input is being sent to a remote host
-    Remote(usize), // Index of the client
+    Remote(String), // Host ID of the client
+}
+
+impl ControlState {
+    /// Check whether input is going to the client `host_id`
+    fn is_remote(&self, host_id: &str) -> bool {
+        matches!(self, ControlState::Remote(id) if id == host_id)
+    }
 }
 
 /// Run the server (primary host)
 async fn run_server(
     config: Config,
+    config_dir: &Path,
     port: u16,
     name: Option<String>,
-    _use_tls: bool,
+    use_tls: bool,
+    pair: bool,
 ) -> anyhow::Result<()> {
     let (width, height) = get_screen_dimensions();
 
@@ -243,8 +495,28 @@ async fn run_server(
     );
     tracing::info!("Screen: {}x{}", screen_info.width, screen_info.height);
 
-    let net_config = NetConfig::new(port);
+    if !use_tls && config.security.require_pairing {
+        anyhow::bail!(
+            "Pairing requires TLS. Set security.require_pairing = false to run without TLS."
+        );
+    }
+    if config.network.relay.is_some() && config.general.host_id.is_none() {
+        anyhow::bail!("Clients find the server at the relay by host ID. Set general.host_id.");
+    }
+
+    let net_config = network_config(&config, config_dir, &screen_info.host_name, port, use_tls)?;
+    let fingerprint = match &net_config.cert_path {
+        Some(cert) => Some(network::certificate_fingerprint(Path::new(cert))?),
+        None => None,
+    };
     let mut server = Server::new(net_config, screen_info.clone());
+    let pairing_code = if pair {
+        let code = network::generate_code();
+        server.allow_pairing(code.clone())?;
+        Some(code)
+    } else {
+        None
+    };
 
     let mut event_rx = server.take_event_receiver().unwrap();
 
@@ -288,20 +560,38 @@ async fn run_server(
     }
 
     // Track connected clients and control state
-    let mut clients: Vec<(SocketAddr, ScreenInfo)> = Vec::new();
+    let mut clients: Vec<ScreenInfo> = Vec::new();
     let mut control_state = ControlState::Local;
     let mut last_edge: Option<ScreenEdge> = None;
+    // Where the cursor last entered a client, to re-enter it on resume
+    let mut last_entry: Option<(ScreenEdge, f32)> = None;
+    // Host ID of the client whose connection dropped while it had control
+    let mut suspended_owner: Option<String> = None;
 
     println!("\n========================================");
     println!("  CoreNet Server Running");
     println!("========================================");
     println!("  Host: {}", screen_info.host_name);
-    println!("  Port: {}", port);
+    match &config.network.socket_path {
+        Some(path) => println!("  Socket: {}", path.display()),
+        None => println!("  Port: {}", port),
+    }
     println!("  Screen: {}x{}", screen_info.width, screen_info.height);
+    if let Some(fingerprint) = &fingerprint {
+        println!("  Fingerprint: {}", fingerprint);
+    }
+    if let Some(code) = &pairing_code {
+        println!("  Pairing code: {} {}", &code[..3], &code[3..]);
+    }
     println!("========================================");
     println!("\nWaiting for clients to connect...");
     println!("Press Ctrl+C to stop.\n");
 
+    let mut link_stats_timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + LINK_STATS_INTERVAL,
+        LINK_STATS_INTERVAL,
+    );
+
     // Main event loop
     loop {
         tokio::select! {
@@ -327,19 +617,78 @@ async fn run_server(
                             layout_guard.add_host(&client_screen);
                         }
 
-                        clients.push((addr, client_screen.clone()));
+                        match clients.iter().position(|s| s.host_id == client_screen.host_id) {
+                            Some(idx) => clients[idx] = client_screen.clone(),
+                            None => clients.push(client_screen.clone()),
+                        }
                         println!("+ Client connected: {} ({})", client_screen.host_name, addr);
                         println!("  Clients: {}", clients.len());
                     }
-                    ServerEvent::ClientDisconnected { addr, reason } => {
-                        tracing::info!("Client disconnected: {} - {}", addr, reason);
+                    ServerEvent::ClientRejected { addr, host_id, code, reason } => {
+                        let host = host_id.unwrap_or_else(|| "unknown".to_string());
+                        tracing::warn!("Rejected client {} ({}): {} (code {})", host, addr, reason, code);
+                        println!("! Client rejected: {} ({}) - {}", host, addr, reason);
+                    }
+                    ServerEvent::ClientPaired { addr, peer } => {
+                        println!("+ Paired with {} ({})", peer.host_name, addr);
+                        println!("  Fingerprint: {}", peer.fingerprint);
+                    }
+                    ServerEvent::PairingFailed { addr, reason } => {
+                        println!("! Pairing with {} failed: {}", addr, reason);
+                    }
+                    ServerEvent::ClientSuspended { addr, host_id, reason } => {
+                        tracing::info!("Client connection lost: {} ({}) - {}", host_id, addr, reason);
+
+                        // Keep its layout slot, but give input back to us until it returns
+                        if control_state.is_remote(&host_id) {
+                            control_state = ControlState::Local;
+                            input_capture.set_suppress(false);
+                            suspended_owner = Some(host_id.clone());
+                        }
+
+                        println!("~ Client connection lost: {} ({}), waiting for it to return", host_id, addr);
+                    }
+                    ServerEvent::ClientResumed { addr, previous_addr, screen_info: client_screen } => {
+                        tracing::info!(
+                            "Client resumed: {} ({}, was {})",
+                            client_screen.host_name,
+                            addr,
+                            previous_addr
+                        );
+
+                        let host_id = client_screen.host_id.clone();
+                        if let Some(idx) = clients.iter().position(|s| s.host_id == host_id) {
+                            clients[idx] = client_screen.clone();
+
+                            // Hand control back if it had it when the connection dropped
+                            if suspended_owner.as_deref() == Some(host_id.as_str())
+                                && control_state == ControlState::Local
+                            {
+                                let (edge, position) = last_entry.unwrap_or((ScreenEdge::Left, 0.5));
+                                if server.send_to(&host_id, Message::EnterScreen { edge, position }).await.is_ok() {
+                                    control_state = ControlState::Remote(host_id);
+                                    input_capture.set_suppress(true);
+                                    edge_detector.reset();
+                                    println!("-> Cursor moved to: {}", client_screen.host_name);
+                                }
+                            }
+                        }
+                        suspended_owner = None;
+
+                        println!("+ Client resumed: {} ({})", client_screen.host_name, addr);
+                    }
+                    ServerEvent::ClientDisconnected { addr, host_id, reason } => {
+                        tracing::info!("Client disconnected: {} ({}) - {}", host_id, addr, reason);
+                        if suspended_owner.as_deref() == Some(host_id.as_str()) {
+                            suspended_owner = None;
+                        }
                         
                         // Remove from client list
-                        if let Some(idx) = clients.iter().position(|(a, _)| *a == addr) {
-                            let (_, screen) = clients.remove(idx);
+                        if let Some(idx) = clients.iter().position(|s| s.host_id == host_id) {
+                            let screen = clients.remove(idx);
                             
                             // If we were sending to this client, return to local
-                            if control_state == ControlState::Remote(idx) {
+                            if control_state.is_remote(&host_id) {
                                 control_state = ControlState::Local;
                                 input_capture.set_suppress(false);
                             }
@@ -351,14 +700,14 @@ async fn run_server(
                             }
                         }
                         
-                        println!("- Client disconnected: {} ({})", addr, reason);
+                        println!("- Client disconnected: {} ({}) - {}", host_id, addr, reason);
                     }
-                    ServerEvent::MessageReceived { addr, message } => {
-                        tracing::debug!("Message from {}: {:?}", addr, message);
+                    ServerEvent::MessageReceived { addr, host_id, message } => {
+                        tracing::debug!("Message from {} ({}): {:?}", host_id, addr, message);
                         
                         // Handle messages from clients (e.g., when cursor returns)
                         match message {
-                            Message::LeaveScreen { edge, position } => {
+                            Message::LeaveScreen { edge, position } if control_state.is_remote(&host_id) => {
                                 // Client is returning control to us
                                 tracing::info!("Cursor returning from client via {:?} edge", edge);
                                 control_state = ControlState::Local;
@@ -406,8 +755,7 @@ async fn run_server(
                                         if !clients.is_empty() {
                                             // For now, just use the first client for right edge
                                             if edge == ScreenEdge::Right && !clients.is_empty() {
-                                                let client_idx = 0;
-                                                let (client_addr, ref client_screen) = clients[client_idx];
+                                                let client_screen = &clients[0];
                                                 
                                                 tracing::info!(
                                                     "Transitioning to {} via {:?} edge at position {}",
@@ -416,13 +764,18 @@ async fn run_server(
                                                     position
                                                 );
                                                 
-                                                // Send enter screen message
-                                                let _ = server.send_to(&client_addr, Message::EnterScreen {
-                                                    edge: screen::opposite_edge(edge),
-                                                    position,
-                                                }).await;
+                                                // Send enter screen message; skip clients
+                                                // whose connection is down
+                                                let entry = (screen::opposite_edge(edge), position);
+                                                if server.send_to(&client_screen.host_id, Message::EnterScreen {
+                                                    edge: entry.0,
+                                                    position: entry.1,
+                                                }).await.is_err() {
+                                                    continue;
+                                                }
                                                 
-                                                control_state = ControlState::Remote(client_idx);
+                                                last_entry = Some(entry);
+                                                control_state = ControlState::Remote(client_screen.host_id.clone());
                                                 input_capture.set_suppress(true);
                                                 edge_detector.reset();
                                                 
@@ -449,13 +802,11 @@ async fn run_server(
                         }
                     }
                     
-                    ControlState::Remote(client_idx) => {
+                    ControlState::Remote(ref host_id) => {
                         // Send input to the remote client
-                        if client_idx < clients.len() {
-                            let (client_addr, _) = &clients[client_idx];
-                            
+                        if clients.iter().any(|s| &s.host_id == host_id) {
                             if let Some(message) = input_event_to_message(&input_event) {
-                                let _ = server.send_to(client_addr, message).await;
+                                let _ = server.send_to(host_id, message).await;
                             }
                         } else {
                             // Client no longer exists, return to local
@@ -466,6 +817,17 @@ async fn run_server(
                 }
             }
             
+            // Report link quality to each client
+            _ = link_stats_timer.tick() => {
+                for client in server.clients().await {
+                    tracing::info!(
+                        "Link to {}: {}",
+                        client.screen_info.host_name,
+                        format_link_stats(&client.stats())
+                    );
+                }
+            }
+            
             // Handle Ctrl+C
             _ = tokio::signal::ctrl_c() => {
                 println!("\nShutting down...");
@@ -474,9 +836,16 @@ async fn run_server(
         }
     }
 
+    // Let clients release what we held down on them and disconnect
+    // before local input is torn down
+    server.stop().await?;
+    while let Some(event) = event_rx.recv().await {
+        if let ServerEvent::Stopped = event {
+            break;
+        }
+    }
     input_capture.stop().await?;
     input_injector.shutdown().await?;
-    server.stop().await?;
     tracing::info!("Server stopped");
 
     Ok(())
@@ -485,9 +854,11 @@ async fn run_server(
 /// Run the client (secondary host)
 async fn run_client(
     config: Config,
+    config_dir: &Path,
     server_addr: Option<String>,
     port: u16,
     discover: bool,
+    use_tls: bool,
 ) -> anyhow::Result<()> {
     let (width, height) = get_screen_dimensions();
 
@@ -498,28 +869,54 @@ async fn run_client(
         config.screen.height.unwrap_or(height),
     );
 
-    let server_socket_addr = if let Some(addr) = server_addr {
-        let addr: SocketAddr = if addr.contains(':') {
-            addr.parse()?
-        } else {
-            format!("{}:{}", addr, port).parse()?
-        };
-        addr
+    let listen = config.network.listen;
+    let relay = config.network.relay.clone();
+    if relay.is_some() && server_addr.is_none() {
+        anyhow::bail!("With a relay, --server names the host ID of the server to reach");
+    }
+    let server_socket_addr = if listen || relay.is_some() {
+        // The server connects in, or the relay finds it; the address is
+        // not used
+        SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, port))
+    } else if let Some(addr) = &server_addr {
+        network::resolve_target(addr, port).await?
+    } else if config.network.socket_path.is_some() {
+        // The socket decides where we connect; the address only names the
+        // server in known_hosts
+        SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, port))
     } else if discover {
         println!("Discovering CoreNet servers...");
         anyhow::bail!("Auto-discovery not yet fully implemented. Please specify --server address.");
     } else {
-        anyhow::bail!("Please specify --server address or use --discover");
+        anyhow::bail!("Please specify --server address, --socket path or use --discover");
+    };
+    let server_label = match &config.network.socket_path {
+        _ if listen => format!("port {} (server connects in)", port),
+        _ if relay.is_some() => format!(
+            "{} via relay {}",
+            server_addr.as_deref().unwrap_or_default(),
+            relay.as_deref().unwrap_or_default()
+        ),
+        Some(path) => path.display().to_string(),
+        None => server_socket_addr.to_string(),
     };
 
     tracing::info!(
         "Connecting to server at {} as '{}'",
-        server_socket_addr,
+        server_label,
         screen_info.host_name
     );
 
-    let net_config = NetConfig::new(port);
-    let mut client = Client::new(net_config, screen_info.clone());
+    let net_config = network_config(&config, config_dir, &screen_info.host_name, port, use_tls)?;
+    let mut client = Client::new(net_config, screen_info.clone())
+        .with_trust_prompt(Arc::new(prompt_trust_server));
+    if config.network.reconnect {
+        client = client.with_reconnect(ReconnectPolicy {
+            max_delay: std::time::Duration::from_millis(config.network.reconnect_max_delay_ms),
+            max_attempts: config.network.reconnect_max_attempts,
+            ..Default::default()
+        });
+    }
 
     let mut event_rx = client.take_event_receiver().unwrap();
 
@@ -543,21 +940,37 @@ async fn run_client(
     let mut entry_edge = ScreenEdge::Left;
     let mut mouse_x: i32 = 0;
     let mut mouse_y: i32 = 0;
-
-    // Connect to server
-    println!("Connecting to {}...", server_socket_addr);
-    client.connect(server_socket_addr).await?;
+    // Keys and buttons pressed on our behalf, released if the server goes away
+    let mut held_keys: Vec<u32> = Vec::new();
+    let mut held_buttons: Vec<MouseButton> = Vec::new();
+
+    // Connect to server, or wait for it to connect in
+    if listen {
+        println!("Waiting for the server to connect in on port {}...", port);
+        client.listen().await?;
+    } else if let (Some(relay), Some(server_id)) = (&relay, &server_addr) {
+        println!("Connecting to {}...", server_label);
+        client.connect_relay(relay, server_id).await?;
+    } else {
+        println!("Connecting to {}...", server_label);
+        client.connect(server_socket_addr).await?;
+    }
 
     println!("\n========================================");
     println!("  CoreNet Client Connected");
     println!("========================================");
     println!("  Local: {}", screen_info.host_name);
-    println!("  Server: {}", server_socket_addr);
+    println!("  Server: {}", server_label);
     println!("  Screen: {}x{}", screen_info.width, screen_info.height);
     println!("========================================");
     println!("\nReceiving input from server...");
     println!("Press Ctrl+C to disconnect.\n");
 
+    let mut link_stats_timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + LINK_STATS_INTERVAL,
+        LINK_STATS_INTERVAL,
+    );
+
     // Main event loop
     loop {
         tokio::select! {
@@ -577,6 +990,31 @@ async fn run_client(
                         println!("Disconnected: {}", reason);
                         break;
                     }
+                    ClientEvent::Reconnecting { attempt, delay, reason } => {
+                        if attempt == 1 {
+                            println!("Connection lost: {}", reason);
+
+                            // The server no longer drives this screen
+                            has_control = false;
+                            edge_detector.reset();
+                            for keycode in held_keys.drain(..) {
+                                let _ = input_injector.key_up(keycode, Default::default()).await;
+                            }
+                            for button in held_buttons.drain(..) {
+                                let _ = input_injector.mouse_button(button, false).await;
+                            }
+                        }
+                        println!("Reconnecting in {:.1}s (attempt {})...", delay.as_secs_f64(), attempt);
+                    }
+                    ClientEvent::Reconnected { server_addr, server_screen, attempts, resumed } => {
+                        tracing::info!("Reconnected to {} after {} attempt(s)", server_addr, attempts);
+                        println!(
+                            "Reconnected to {} ({}){}",
+                            server_screen.host_name,
+                            server_addr,
+                            if resumed { ", session resumed" } else { "" }
+                        );
+                    }
                     ClientEvent::MessageReceived { message } => {
                         match message {
                             Message::EnterScreen { edge, position } => {
@@ -643,6 +1081,10 @@ async fn run_client(
                             }
                             
                             Message::MouseButton { button, pressed } if has_control => {
+                                held_buttons.retain(|b| *b != button);
+                                if pressed {
+                                    held_buttons.push(button);
+                                }
                                 if let Err(e) = input_injector.mouse_button(button, pressed).await {
                                     tracing::warn!("Failed to inject mouse button: {}", e);
                                 }
@@ -655,12 +1097,16 @@ async fn run_client(
                             }
                             
                             Message::KeyDown { keycode, modifiers, .. } if has_control => {
+                                if !held_keys.contains(&keycode) {
+                                    held_keys.push(keycode);
+                                }
                                 if let Err(e) = input_injector.key_down(keycode, modifiers).await {
                                     tracing::warn!("Failed to inject key down: {}", e);
                                 }
                             }
                             
                             Message::KeyUp { keycode, modifiers } if has_control => {
+                                held_keys.retain(|k| *k != keycode);
                                 if let Err(e) = input_injector.key_up(keycode, modifiers).await {
                                     tracing::warn!("Failed to inject key up: {}", e);
                                 }
@@ -677,6 +1123,13 @@ async fn run_client(
                 }
             }
             
+            // Report link quality to the server
+            _ = link_stats_timer.tick() => {
+                if let Some(stats) = client.stats().await {
+                    tracing::info!("Link to server: {}", format_link_stats(&stats));
+                }
+            }
+            
             _ = tokio::signal::ctrl_c() => {
                 println!("\nDisconnecting...");
                 break;
@@ -691,6 +1144,83 @@ async fn run_client(
     Ok(())
 }
 
+/// Pair with a server (client side) or wait for a client to pair (server side)
+async fn run_pair(
+    config: Config,
+    config_dir: &Path,
+    server_addr: Option<String>,
+    port: u16,
+) -> anyhow::Result<()> {
+    let (width, height) = get_screen_dimensions();
+
+    let screen_info = ScreenInfo::new(
+        config.host_id(),
+        config.general.name.clone(),
+        config.screen.width.unwrap_or(width),
+        config.screen.height.unwrap_or(height),
+    );
+
+    let net_config = network_config(&config, config_dir, &screen_info.host_name, port, true)?;
+
+    match server_addr {
+        None => {
+            let code = network::generate_code();
+
+            println!("\n========================================");
+            println!("  CoreNet Pairing");
+            println!("========================================");
+            println!("  Code: {} {}", &code[..3], &code[3..]);
+            println!("  Port: {}", port);
+            println!("========================================");
+            println!("\nOn the new machine run:");
+            println!("  corenet pair --server <this host>");
+            println!("and enter the code above. Waiting...\n");
+
+            let mut server = Server::new(net_config, screen_info);
+            let mut event_rx = server.take_event_receiver().unwrap();
+            server.allow_pairing(code)?;
+            server.start().await?;
+
+            let outcome = loop {
+                match event_rx.recv().await {
+                    Some(ServerEvent::ClientPaired { peer, .. }) => break Ok(peer),
+                    Some(ServerEvent::PairingFailed { reason, .. }) => break Err(reason),
+                    Some(ServerEvent::Stopped) | None => break Err("Server stopped".to_string()),
+                    Some(_) => {}
+                }
+            };
+            server.stop().await?;
+
+            let peer = outcome.map_err(|reason| anyhow::anyhow!("Pairing failed: {}", reason))?;
+            println!("Paired with {} ({})", peer.host_name, peer.host_id);
+            println!("  Fingerprint: {}", peer.fingerprint);
+        }
+        Some(addr) => {
+            let server_socket_addr = network::resolve_target(&addr, port).await?;
+
+            print!("Enter the pairing code shown on the server: ");
+            std::io::stdout().flush()?;
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input)?;
+            let code = network::normalize_code(&input)
+                .ok_or_else(|| anyhow::anyhow!("Pairing codes are 6 digits"))?;
+
+            let peer = network::request_pairing(
+                &net_config,
+                server_socket_addr,
+                &server_socket_addr.ip().to_string(),
+                &screen_info,
+                &code,
+            )
+            .await?;
+            println!("Paired with server {}", peer.host_id);
+            println!("  Fingerprint: {}", peer.fingerprint);
+        }
+    }
+
+    Ok(())
+}
+
 /// Run host discovery
 async fn run_discovery(timeout_secs: u64) -> anyhow::Result<()> {
     println!("Scanning for CoreNet hosts ({} seconds)...\n", timeout_secs);
@@ -777,7 +1307,8 @@ fn print_system_info() {
         println!("  - May require running as Administrator for global hooks");
     }
 
-    println!("\nProtocol Version: {}", protocol::PROTOCOL_VERSION);
+    println!("\nProtocol Versions: {}", protocol::VersionRange::SUPPORTED);
+    println!("Features: {}", protocol::Features::SUPPORTED);
     println!("Default Port: {}", protocol::DEFAULT_PORT);
 }
 
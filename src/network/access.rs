@@ -0,0 +1,168 @@
+//! Host access rules for the server
+//!
+//! Rules are written as strings in the `security` config section:
+//! - `192.168.1.20`, `10.0.0.0/8`, `fd00::/8` match the source address;
+//!   they never match peers without one, like Unix socket peers
+//! - `SHA256:<hex>` matches the fingerprint of the client's certificate
+//! - anything else matches the client's `host_id`
+//!
+//! Deny rules win over allow rules. An empty allow list admits everyone
+//! not denied.
+
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// A single allow/deny rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostRule {
+    /// Source address inside a network (a single address is a /32 or /128)
+    Network(IpNet),
+    /// Pinned certificate fingerprint
+    Fingerprint(String),
+    /// Host identifier announced in Hello
+    HostId(String),
+}
+
+impl HostRule {
+    /// Parse a rule from its config string
+    pub fn parse(rule: &str) -> Result<Self, String> {
+        let rule = rule.trim();
+        if rule.is_empty() {
+            return Err("empty host rule".to_string());
+        }
+
+        if rule.starts_with("SHA256:") {
+            return Ok(HostRule::Fingerprint(rule.to_string()));
+        }
+        if let Ok(net) = rule.parse::<IpNet>() {
+            return Ok(HostRule::Network(net));
+        }
+        if let Ok(ip) = rule.parse::<IpAddr>() {
+            return Ok(HostRule::Network(IpNet::from(ip)));
+        }
+        if rule.contains('/') {
+            return Err(format!("invalid network '{}'", rule));
+        }
+
+        Ok(HostRule::HostId(rule.to_string()))
+    }
+
+    /// Check whether a peer matches this rule
+    pub fn matches(&self, peer: &PeerIdentity<'_>) -> bool {
+        match self {
+            HostRule::Network(net) => peer.ip.is_some_and(|ip| net.contains(&canonical_ip(ip))),
+            HostRule::Fingerprint(fp) => peer.fingerprint == Some(fp.as_str()),
+            HostRule::HostId(id) => peer.host_id == id,
+        }
+    }
+}
+
+/// Treat IPv4-mapped IPv6 addresses (from dual-stack sockets) as IPv4
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+/// What the server knows about a peer when deciding whether to admit it
+#[derive(Debug, Clone, Copy)]
+pub struct PeerIdentity<'a> {
+    /// Source address of the connection, if the transport has one
+    pub ip: Option<IpAddr>,
+    /// Host identifier from Hello
+    pub host_id: &'a str,
+    /// Fingerprint of the client certificate, if one was presented
+    pub fingerprint: Option<&'a str>,
+}
+
+/// Allow and deny rules applied to incoming clients
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    allow: Vec<HostRule>,
+    deny: Vec<HostRule>,
+}
+
+impl AccessList {
+    /// Parse allow and deny rules from their config strings
+    pub fn parse(allow: &[String], deny: &[String]) -> Result<Self, String> {
+        Ok(Self {
+            allow: allow.iter().map(|r| HostRule::parse(r)).collect::<Result<_, _>>()?,
+            deny: deny.iter().map(|r| HostRule::parse(r)).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Decide whether to admit a peer, returning the reason if not
+    pub fn check(&self, peer: &PeerIdentity<'_>) -> Result<(), String> {
+        if self.deny.iter().any(|rule| rule.matches(peer)) {
+            return Err(format!("Host '{}' is denied by server policy", peer.host_id));
+        }
+
+        if !self.allow.is_empty() && !self.allow.iter().any(|rule| rule.matches(peer)) {
+            return Err(format!("Host '{}' is not in the allowed hosts", peer.host_id));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer<'a>(ip: &str, host_id: &'a str, fingerprint: Option<&'a str>) -> PeerIdentity<'a> {
+        PeerIdentity {
+            ip: Some(ip.parse().unwrap()),
+            host_id,
+            fingerprint,
+        }
+    }
+
+    #[test]
+    fn test_rule_parsing() {
+        assert!(matches!(HostRule::parse("10.0.0.0/8"), Ok(HostRule::Network(_))));
+        assert!(matches!(HostRule::parse("::1"), Ok(HostRule::Network(_))));
+        assert!(matches!(HostRule::parse("SHA256:ab"), Ok(HostRule::Fingerprint(_))));
+        assert!(matches!(HostRule::parse("desk-pc"), Ok(HostRule::HostId(_))));
+        assert!(HostRule::parse("10.0.0.0/99").is_err());
+        assert!(HostRule::parse("  ").is_err());
+    }
+
+    #[test]
+    fn test_allow_and_deny() {
+        let acl = AccessList::parse(
+            &["192.168.1.0/24".to_string(), "laptop".to_string()],
+            &["192.168.1.66".to_string(), "SHA256:bad".to_string()],
+        )
+        .unwrap();
+
+        assert!(acl.check(&peer("192.168.1.10", "desk", None)).is_ok());
+        assert!(acl.check(&peer("::ffff:192.168.1.10", "desk", None)).is_ok());
+        assert!(acl.check(&peer("10.1.1.1", "laptop", None)).is_ok());
+        assert!(acl.check(&peer("10.1.1.1", "desk", None)).is_err());
+        assert!(acl.check(&peer("192.168.1.66", "laptop", None)).is_err());
+        assert!(acl.check(&peer("192.168.1.10", "desk", Some("SHA256:bad"))).is_err());
+    }
+
+    #[test]
+    fn test_address_rules_skip_peers_without_address() {
+        let peer = PeerIdentity {
+            ip: None,
+            host_id: "vm",
+            fingerprint: None,
+        };
+        let deny_loopback = AccessList::parse(&[], &["127.0.0.0/8".to_string()]).unwrap();
+        assert!(deny_loopback.check(&peer).is_ok());
+
+        let allow_loopback = AccessList::parse(&["127.0.0.0/8".to_string()], &[]).unwrap();
+        assert!(allow_loopback.check(&peer).is_err());
+        let allow_host = AccessList::parse(&["127.0.0.0/8".to_string(), "vm".to_string()], &[]).unwrap();
+        assert!(allow_host.check(&peer).is_ok());
+    }
+
+    #[test]
+    fn test_empty_list_allows_all() {
+        let acl = AccessList::parse(&[], &[]).unwrap();
+        assert!(acl.check(&peer("203.0.113.9", "anyone", None)).is_ok());
+    }
+}
@@ -3,15 +3,23 @@
 //! Connects to a CoreNet server and handles message exchange.
 
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
-use tokio::net::TcpStream;
 use tokio::sync::{mpsc, RwLock};
+use tokio_rustls::TlsConnector;
 
-use super::connection::{Connection, ConnectionError, ConnectionHandle};
+use super::connection::{Connection, ConnectionError, ConnectionHandle, ConnectionStats, Outbox};
+use super::listener;
+use super::quic::QuicConnector;
+use super::relay::{RelayConnector, DEFAULT_RELAY_PORT};
+use super::tls;
+use super::transport::{Connector, InboundConnector, TcpConnector, Transport};
+use super::trust::{self, HostTrust, KnownHosts, TrustPrompt};
+use super::websocket;
 use super::NetworkConfig;
-use crate::protocol::{Message, ScreenInfo};
+use crate::protocol::{error_codes, Message, ScreenInfo};
 
 /// Client errors
 #[derive(Error, Debug)]
@@ -30,6 +38,39 @@ pub enum ClientError {
     
     #[error("Connection timeout")]
     Timeout,
+    
+    #[error("TLS configuration error: {0}")]
+    TlsConfig(String),
+    
+    #[error("Relay configuration error: {0}")]
+    RelayConfig(String),
+}
+
+impl ClientError {
+    /// Whether retrying the connection later might succeed. Trust and
+    /// handshake failures are permanent until someone changes the config,
+    /// as are refusals other than for load or a host ID still held.
+    fn is_transient(&self) -> bool {
+        match self {
+            ClientError::Io(_) | ClientError::Timeout => true,
+            ClientError::Connection(e) => match e {
+                ConnectionError::Io(_)
+                | ConnectionError::Closed
+                | ConnectionError::Timeout
+                | ConnectionError::Tls(_) => true,
+                ConnectionError::Rejected { code, .. } => matches!(
+                    *code,
+                    error_codes::RATE_LIMITED
+                        | error_codes::SERVER_BUSY
+                        | error_codes::SERVER_FULL
+                        | error_codes::DUPLICATE_HOST
+                        | error_codes::HANDSHAKE_TIMEOUT
+                ),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
 }
 
 pub type ClientResult<T> = Result<T, ClientError>;
@@ -46,6 +87,20 @@ pub enum ClientEvent {
     Disconnected {
         reason: String,
     },
+    /// Lost the server; waiting `delay` before reconnect attempt `attempt`
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+        reason: String,
+    },
+    /// Connection re-established after a loss
+    Reconnected {
+        server_addr: SocketAddr,
+        server_screen: ScreenInfo,
+        attempts: u32,
+        /// The server restored the previous session rather than starting a new one
+        resumed: bool,
+    },
     /// Received a message from the server
     MessageReceived {
         message: Message,
@@ -62,6 +117,46 @@ pub enum ClientState {
     Disconnected,
     Connecting,
     Connected,
+    Reconnecting,
+}
+
+/// How the client retries after losing the server
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Delay before the first attempt
+    pub initial_delay: Duration,
+    /// Upper bound on the delay between attempts
+    pub max_delay: Duration,
+    /// Factor the delay grows by after each failed attempt
+    pub multiplier: f64,
+    /// Fraction of each delay that is randomized (0.0 - 1.0), so clients
+    /// dropped together don't all come back at the same instant
+    pub jitter: f64,
+    /// Give up after this many failed attempts (None = retry forever)
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay to wait before the given attempt (starting at 1)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(63) as i32;
+        let base = self.initial_delay.as_secs_f64() * self.multiplier.max(1.0).powi(exponent);
+        let base = base.min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::random::<f64>();
+        Duration::from_secs_f64(base * (1.0 - jitter))
+    }
 }
 
 /// CoreNet Client
@@ -82,6 +177,12 @@ pub struct Client {
     connection_handle: Arc<RwLock<Option<ConnectionHandle>>>,
     /// Shutdown signal
     shutdown_tx: Arc<RwLock<Option<mpsc::Sender<()>>>>,
+    /// Asked whether to trust a server certificate seen for the first time
+    trust_prompt: Option<TrustPrompt>,
+    /// Reconnect automatically when the connection drops (None = give up)
+    reconnect: Option<ReconnectPolicy>,
+    /// Opens the transport to the server (None = TCP, or `socket_path`)
+    connector: Option<Arc<dyn Connector>>,
 }
 
 impl Client {
@@ -98,9 +199,32 @@ impl Client {
             event_rx: Some(event_rx),
             connection_handle: Arc::new(RwLock::new(None)),
             shutdown_tx: Arc::new(RwLock::new(None)),
+            trust_prompt: None,
+            reconnect: None,
+            connector: None,
         }
     }
 
+    /// Set the callback that decides whether to pin an unknown server's certificate
+    pub fn with_trust_prompt(mut self, prompt: TrustPrompt) -> Self {
+        self.trust_prompt = Some(prompt);
+        self
+    }
+
+    /// Keep reconnecting to the server after the connection drops
+    pub fn with_reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Reach the server over a transport of the test's choosing, such as
+    /// the in-memory one
+    #[cfg(test)]
+    pub fn with_connector(mut self, connector: Arc<dyn Connector>) -> Self {
+        self.connector = Some(connector);
+        self
+    }
+
     /// Take the event receiver (can only be called once)
     pub fn take_event_receiver(&mut self) -> Option<mpsc::Receiver<ClientEvent>> {
         self.event_rx.take()
@@ -108,6 +232,85 @@ impl Client {
 
     /// Connect to a server by address
     pub async fn connect(&self, server_addr: SocketAddr) -> ClientResult<()> {
+        self.connect_named(server_addr, &server_addr.ip().to_string()).await
+    }
+
+    /// Connect to a server, verifying its certificate against `server_name`
+    async fn connect_named(&self, server_addr: SocketAddr, server_name: &str) -> ClientResult<()> {
+        let tls_connector = self.tls_connector()?;
+        let dialer = Dialer {
+            config: self.config.clone(),
+            screen_info: self.screen_info.clone(),
+            trust_prompt: self.trust_prompt.clone(),
+            connector: self.connector(tls_connector.as_ref(), server_name)?,
+            tls_connector,
+            inbound: false,
+            server_addr,
+            server_name: server_name.to_string(),
+        };
+
+        tracing::info!("Connecting to {}", server_addr);
+        self.start(dialer).await
+    }
+
+    /// Connect through the relay at `relay` to the server registered there
+    /// as `server_id`, presenting the configured relay token. The server's
+    /// certificate is pinned under its host ID.
+    pub async fn connect_relay(&self, relay: &str, server_id: &str) -> ClientResult<()> {
+        let token = self.config.relay_token.clone().ok_or_else(|| {
+            ClientError::RelayConfig("No relay token is configured".to_string())
+        })?;
+        let relay_addr = super::resolve_target(relay, DEFAULT_RELAY_PORT).await?;
+
+        let dialer = Dialer {
+            config: self.config.clone(),
+            screen_info: self.screen_info.clone(),
+            trust_prompt: self.trust_prompt.clone(),
+            connector: Arc::new(RelayConnector::new(relay, server_id, token)),
+            tls_connector: self.tls_connector()?,
+            inbound: false,
+            server_addr: relay_addr,
+            server_name: server_id.to_string(),
+        };
+
+        tracing::info!("Connecting to {} through relay {}", server_id, relay_addr);
+        self.start(dialer).await
+    }
+
+    /// Wait for a server in reverse-connect mode to connect in over TCP, on
+    /// the configured bind addresses and port. Roles are unchanged: the
+    /// server still owns the keyboard, and this side still starts TLS.
+    pub async fn listen(&self) -> ClientResult<()> {
+        let listeners = listener::bind_listeners(&self.config.bind_addresses, self.config.port)?;
+        self.listen_on(InboundConnector::new(listeners)).await
+    }
+
+    /// Wait for a server in reverse-connect mode to connect in through `connector`
+    pub async fn listen_on(&self, connector: InboundConnector) -> ClientResult<()> {
+        let local_addrs = connector.local_addrs();
+        for addr in &local_addrs {
+            tracing::info!("Waiting for the server to connect on {}", addr);
+        }
+
+        let dialer = Dialer {
+            config: self.config.clone(),
+            screen_info: self.screen_info.clone(),
+            trust_prompt: self.trust_prompt.clone(),
+            connector: Arc::new(connector),
+            tls_connector: self.tls_connector()?,
+            inbound: true,
+            server_addr: local_addrs
+                .first()
+                .copied()
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], self.config.port))),
+            server_name: String::new(),
+        };
+        self.start(dialer).await
+    }
+
+    /// Establish the first connection through `dialer`, then hand it to a
+    /// supervisor task
+    async fn start(&self, dialer: Dialer) -> ClientResult<()> {
         {
             let state = self.state.read().await;
             if *state != ClientState::Disconnected {
@@ -120,47 +323,25 @@ impl Client {
             *state = ClientState::Connecting;
         }
 
-        tracing::info!("Connecting to {}", server_addr);
-
-        // Connect with timeout
-        let stream = match tokio::time::timeout(
-            Duration::from_millis(self.config.connect_timeout_ms),
-            TcpStream::connect(server_addr),
-        )
-        .await
-        {
-            Ok(Ok(stream)) => stream,
-            Ok(Err(e)) => {
+        let conn = match dialer.dial(None).await {
+            Ok(conn) => conn,
+            Err(e) => {
                 let mut state = self.state.write().await;
                 *state = ClientState::Disconnected;
-                return Err(ClientError::Io(e));
-            }
-            Err(_) => {
-                let mut state = self.state.write().await;
-                *state = ClientState::Disconnected;
-                return Err(ClientError::Timeout);
+                return Err(e);
             }
         };
 
-        let mut conn = Connection::new(stream, server_addr);
-        
-        // Perform handshake
-        if let Err(e) = conn.handshake_client(&self.screen_info).await {
-            let mut state = self.state.write().await;
-            *state = ClientState::Disconnected;
-            return Err(ClientError::Connection(e));
-        }
-
+        let server_addr = conn.remote_addr();
         let server_screen = conn.remote_screen_info().cloned().unwrap();
-        
+
         {
             let mut ss = self.server_screen.write().await;
             *ss = Some(server_screen.clone());
         }
-
-        // Create message channel
-        let (msg_tx, mut msg_rx) = mpsc::channel::<Message>(256);
-        let handle = ConnectionHandle::new(msg_tx);
+        
+        // Create the send queue
+        let (handle, outbox) = ConnectionHandle::new();
 
         {
             let mut ch = self.connection_handle.write().await;
@@ -168,7 +349,7 @@ impl Client {
         }
 
         // Create shutdown channel
-        let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
+        let (shutdown_tx, shutdown_rx) = mpsc::channel::<()>(1);
         {
             let mut st = self.shutdown_tx.write().await;
             *st = Some(shutdown_tx);
@@ -184,95 +365,17 @@ impl Client {
             server_screen,
         }).await;
 
-        // Spawn the message loop
-        let event_tx = self.event_tx.clone();
-        let state = self.state.clone();
-        let connection_handle = self.connection_handle.clone();
-        let heartbeat_interval = Duration::from_millis(self.config.heartbeat_interval_ms);
-
-        tokio::spawn(async move {
-            let mut heartbeat_timer = tokio::time::interval(heartbeat_interval);
-            
-            let disconnect_reason = loop {
-                tokio::select! {
-                    // Receive messages from the server
-                    result = conn.recv() => {
-                        match result {
-                            Ok(Some(frame)) => {
-                                match &frame.message {
-                                    Message::Disconnect { reason } => {
-                                        break reason.clone();
-                                    }
-                                    Message::Heartbeat { timestamp } => {
-                                        let _ = conn.send(&Message::HeartbeatAck {
-                                            timestamp: *timestamp,
-                                        }).await;
-                                    }
-                                    Message::HeartbeatAck { .. } => {
-                                        // Update RTT
-                                        handle.update_rtt(conn.stats().rtt_us);
-                                    }
-                                    _ => {
-                                        let _ = event_tx.send(ClientEvent::MessageReceived {
-                                            message: frame.message,
-                                        }).await;
-                                    }
-                                }
-                            }
-                            Ok(None) => {
-                                break "Connection closed".to_string();
-                            }
-                            Err(e) => {
-                                break format!("Error: {}", e);
-                            }
-                        }
-                    }
-                    
-                    // Send messages to the server
-                    Some(message) = msg_rx.recv() => {
-                        if let Err(e) = conn.send(&message).await {
-                            break format!("Send error: {}", e);
-                        }
-                    }
-                    
-                    // Send heartbeats
-                    _ = heartbeat_timer.tick() => {
-                        if let Err(e) = conn.send(&Message::Heartbeat {
-                            timestamp: std::time::SystemTime::now()
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .unwrap()
-                                .as_micros() as u64,
-                        }).await {
-                            break format!("Heartbeat error: {}", e);
-                        }
-                    }
-                    
-                    // Shutdown signal
-                    _ = shutdown_rx.recv() => {
-                        break "Client shutdown requested".to_string();
-                    }
-                }
-            };
+        let supervisor = Supervisor {
+            dialer,
+            reconnect: self.reconnect.clone(),
+            state: self.state.clone(),
+            server_screen: self.server_screen.clone(),
+            connection_handle: self.connection_handle.clone(),
+            event_tx: self.event_tx.clone(),
+            shutdown_rx,
+        };
 
-            // Clean up
-            handle.mark_disconnected();
-            
-            {
-                let mut ch = connection_handle.write().await;
-                *ch = None;
-            }
-            
-            {
-                let mut s = state.write().await;
-                *s = ClientState::Disconnected;
-            }
-            
-            let _ = conn.close(&disconnect_reason).await;
-            
-            let _ = event_tx.send(ClientEvent::Disconnected {
-                reason: disconnect_reason,
-            }).await;
-        });
+        tokio::spawn(supervisor.run(conn, handle, outbox));
 
         Ok(())
     }
@@ -280,7 +383,72 @@ impl Client {
     /// Connect to a server by hostname
     pub async fn connect_hostname(&self, hostname: &str, port: u16) -> ClientResult<()> {
         let addr = super::resolve_host(hostname, port).await?;
-        self.connect(addr).await
+        self.connect_named(addr, hostname).await
+    }
+
+    /// The transport connector: the one set with `with_connector`, else the
+    /// configured Unix socket, else QUIC if enabled, else TCP, through the
+    /// proxy if WebSocket is enabled and one is set
+    fn connector(
+        &self,
+        tls_connector: Option<&TlsConnector>,
+        server_name: &str,
+    ) -> ClientResult<Arc<dyn Connector>> {
+        if let Some(connector) = &self.connector {
+            return Ok(connector.clone());
+        }
+        match &self.config.socket_path {
+            #[cfg(unix)]
+            Some(path) => Ok(Arc::new(super::unix::UnixConnector::new(path))),
+            #[cfg(not(unix))]
+            Some(path) => Err(ClientError::Io(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("Cannot connect to {}: Unix domain sockets are not supported on this platform", path),
+            ))),
+            None if self.config.quic => {
+                let tls_connector = tls_connector.ok_or_else(|| {
+                    ClientError::TlsConfig("QUIC is enabled but TLS is disabled".to_string())
+                })?;
+                let connector = QuicConnector::new(tls_connector, server_name)
+                    .map_err(|e| ClientError::TlsConfig(e.to_string()))?;
+                Ok(Arc::new(connector))
+            }
+            None if self.config.websocket => {
+                match websocket::ProxyConnector::configured(self.config.proxy.as_deref())? {
+                    Some(proxy) => Ok(Arc::new(proxy)),
+                    None => Ok(Arc::new(TcpConnector)),
+                }
+            }
+            None => Ok(Arc::new(TcpConnector)),
+        }
+    }
+
+    /// Build the TLS connector from the configured CA, if TLS is enabled
+    fn tls_connector(&self) -> ClientResult<Option<TlsConnector>> {
+        if !self.config.use_tls {
+            if self.config.require_tls {
+                return Err(ClientError::TlsConfig(
+                    "TLS is required but disabled".to_string(),
+                ));
+            }
+            return Ok(None);
+        }
+
+        let identity = match (&self.config.cert_path, &self.config.key_path) {
+            (Some(cert), Some(key)) => Some((Path::new(cert), Path::new(key))),
+            _ => None,
+        };
+
+        let Some(ca_path) = &self.config.ca_path else {
+            let connector = trust::pinning_connector(identity)
+                .map_err(|e| ClientError::TlsConfig(e.to_string()))?;
+            return Ok(Some(connector));
+        };
+
+        let connector = tls::client_connector(Path::new(ca_path), identity)
+            .map_err(|e| ClientError::TlsConfig(format!("{}: {}", ca_path, e)))?;
+
+        Ok(Some(connector))
     }
 
     /// Disconnect from the server
@@ -296,7 +464,7 @@ impl Client {
         if let Some(handle) = &*self.connection_handle.read().await {
             let _ = handle.send(Message::Disconnect {
                 reason: "Client disconnecting".to_string(),
-            }).await;
+            });
         }
 
         // Signal shutdown
@@ -311,7 +479,7 @@ impl Client {
     pub async fn send(&self, message: Message) -> ClientResult<()> {
         let handle = self.connection_handle.read().await;
         if let Some(h) = &*handle {
-            h.send(message).await?;
+            h.send(message)?;
             Ok(())
         } else {
             Err(ClientError::NotConnected)
@@ -328,15 +496,410 @@ impl Client {
         self.server_screen.read().await.clone()
     }
 
+    /// Latest link statistics (RTT, jitter, heartbeat loss), if connected
+    pub async fn stats(&self) -> Option<ConnectionStats> {
+        self.connection_handle.read().await.as_ref().map(|h| h.stats())
+    }
+
     /// Check if connected
     pub async fn is_connected(&self) -> bool {
         *self.state.read().await == ClientState::Connected
     }
 }
 
+/// Everything needed to (re)establish a connection to one server
+#[derive(Clone)]
+struct Dialer {
+    config: NetworkConfig,
+    screen_info: ScreenInfo,
+    trust_prompt: Option<TrustPrompt>,
+    connector: Arc<dyn Connector>,
+    tls_connector: Option<TlsConnector>,
+    /// The server connects in, so wait for it rather than time out, and
+    /// learn its address from the connection
+    inbound: bool,
+    /// Server address, or for inbound connections the local listen address
+    server_addr: SocketAddr,
+    server_name: String,
+}
+
+impl Dialer {
+    /// Open the transport, run TLS and certificate checks, then the CoreNet
+    /// handshake, asking to resume the session behind `resume_token`
+    async fn dial(&self, resume_token: Option<&str>) -> ClientResult<Connection> {
+        // Connect with timeout; a server connecting in may take its time
+        let connect = self.connector.connect(self.server_addr);
+        let stream = if self.inbound {
+            connect.await?
+        } else {
+            match tokio::time::timeout(Duration::from_millis(self.config.connect_timeout_ms), connect).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => return Err(ClientError::Io(e)),
+                Err(_) => return Err(ClientError::Timeout),
+            }
+        };
+
+        // A server connecting in comes from an arbitrary port, so it is
+        // known by its address and the port we listen on
+        let (server_addr, server_name) = if self.inbound {
+            let addr = stream.peer_addr()?;
+            tracing::info!("Server connected in from {}", addr);
+            (addr, addr.ip().to_string())
+        } else {
+            (self.server_addr, self.server_name.clone())
+        };
+
+        // Transports with their own encryption, like QUIC, already did TLS
+        let stream: Box<dyn Transport> = match &self.tls_connector {
+            Some(connector) if !stream.is_encrypted() => {
+                let name = tls::server_name(&server_name)?;
+                Box::new(tls::connect_tls(connector, name, stream).await?)
+            }
+            _ => stream,
+        };
+        if self.tls_connector.is_some() && self.config.ca_path.is_none() {
+            let host = trust::known_host_key(&server_name, self.server_addr.port());
+            self.verify_pinned(stream.peer_fingerprint(), host).await?;
+        }
+        let stream: Box<dyn Transport> = if self.config.websocket {
+            let port = server_addr.port();
+            Box::new(websocket::connect_websocket(&server_name, port, stream).await?)
+        } else {
+            stream
+        };
+
+        let mut conn = Connection::new(stream, server_addr);
+        // Motion needs the server's real address, which a Unix socket or
+        // a relay hides
+        if self.config.udp_motion && self.config.socket_path.is_none() && self.config.relay.is_none() {
+            if let Err(e) = conn.offer_motion_channel().await {
+                tracing::warn!("Pointer motion will use the main connection: {}", e);
+            }
+        }
+
+        // Perform handshake
+        conn.handshake_client_resume(&self.screen_info, resume_token).await?;
+
+        Ok(conn)
+    }
+
+    /// Check the fingerprint of the server's certificate against the known-hosts file,
+    /// asking the user to pin it if the server has never been seen
+    async fn verify_pinned(&self, fingerprint: Option<String>, host: String) -> ClientResult<()> {
+        let actual = fingerprint.ok_or_else(|| {
+            ConnectionError::CertificateRejected("Server presented no certificate".to_string())
+        })?;
+
+        let mut known_hosts = match &self.config.known_hosts_path {
+            Some(path) => KnownHosts::load(Path::new(path))?,
+            None => KnownHosts::in_memory(),
+        };
+
+        match known_hosts.check(&host, &actual) {
+            HostTrust::Trusted => Ok(()),
+            HostTrust::Mismatch { expected } => Err(ConnectionError::FingerprintMismatch {
+                host,
+                expected,
+                actual,
+            }
+            .into()),
+            HostTrust::Unknown => {
+                let accepted = match self.trust_prompt.clone() {
+                    Some(prompt) => {
+                        let (h, fp) = (host.clone(), actual.clone());
+                        tokio::task::spawn_blocking(move || prompt(&h, &fp))
+                            .await
+                            .unwrap_or(false)
+                    }
+                    None => false,
+                };
+
+                if !accepted {
+                    return Err(ConnectionError::HandshakeFailed(format!(
+                        "Certificate {} for {} was not trusted",
+                        actual, host
+                    ))
+                    .into());
+                }
+
+                known_hosts.pin(&host, &actual)?;
+                tracing::info!("Pinned {} for {}", actual, host);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// How a session with the server ended
+struct SessionEnd {
+    reason: String,
+    /// The local side asked to disconnect, so don't reconnect
+    requested: bool,
+}
+
+/// Background task owning the connection: runs the message loop and,
+/// if a reconnect policy is set, re-dials the server when it drops
+struct Supervisor {
+    dialer: Dialer,
+    reconnect: Option<ReconnectPolicy>,
+    state: Arc<RwLock<ClientState>>,
+    server_screen: Arc<RwLock<Option<ScreenInfo>>>,
+    connection_handle: Arc<RwLock<Option<ConnectionHandle>>>,
+    event_tx: mpsc::Sender<ClientEvent>,
+    shutdown_rx: mpsc::Receiver<()>,
+}
+
+impl Supervisor {
+    async fn run(
+        mut self,
+        mut conn: Connection,
+        mut handle: ConnectionHandle,
+        mut outbox: Outbox,
+    ) {
+        let disconnect_reason = loop {
+            let end = self.run_session(&mut conn, &handle, &mut outbox).await;
+
+            // Clean up
+            handle.mark_disconnected();
+
+            {
+                let mut ch = self.connection_handle.write().await;
+                *ch = None;
+            }
+
+            // Only a requested disconnect says goodbye: the server ends the
+            // session on one, where a dropped link leaves it to be resumed
+            if end.requested {
+                let heartbeat_interval = Duration::from_millis(self.dialer.config.heartbeat_interval_ms);
+                let _ = tokio::time::timeout(heartbeat_interval, conn.close(&end.reason)).await;
+            }
+
+            let policy = match &self.reconnect {
+                Some(policy) if !end.requested => policy.clone(),
+                _ => break end.reason,
+            };
+
+            {
+                let mut s = self.state.write().await;
+                *s = ClientState::Reconnecting;
+            }
+
+            let resume_token = conn.session().resume_token.clone();
+            drop(conn);
+            let attempts = match self.redial(&policy, &end.reason, resume_token.as_deref()).await {
+                Ok((new_conn, attempts)) => {
+                    conn = new_conn;
+                    attempts
+                }
+                Err(reason) => break reason,
+            };
+
+            let server_screen = conn.remote_screen_info().cloned().unwrap();
+            {
+                let mut ss = self.server_screen.write().await;
+                *ss = Some(server_screen.clone());
+            }
+
+            (handle, outbox) = ConnectionHandle::new();
+            {
+                let mut ch = self.connection_handle.write().await;
+                *ch = Some(handle.clone());
+            }
+
+            {
+                let mut s = self.state.write().await;
+                *s = ClientState::Connected;
+            }
+
+            let resumed = conn.session().resumed;
+            tracing::info!(
+                "Reconnected to {} after {} attempt(s){}",
+                conn.remote_addr(),
+                attempts,
+                if resumed { ", session resumed" } else { "" }
+            );
+            let _ = self.event_tx.send(ClientEvent::Reconnected {
+                server_addr: conn.remote_addr(),
+                server_screen,
+                attempts,
+                resumed,
+            }).await;
+        };
+
+        {
+            let mut s = self.state.write().await;
+            *s = ClientState::Disconnected;
+        }
+
+        let _ = self.event_tx.send(ClientEvent::Disconnected {
+            reason: disconnect_reason,
+        }).await;
+    }
+
+    /// Exchange messages until the connection drops or shutdown is requested
+    async fn run_session(
+        &mut self,
+        conn: &mut Connection,
+        handle: &ConnectionHandle,
+        outbox: &mut Outbox,
+    ) -> SessionEnd {
+        let heartbeat_interval = Duration::from_millis(self.dialer.config.heartbeat_interval_ms);
+        let missed_heartbeats = self.dialer.config.missed_heartbeats;
+        let mut heartbeat_timer = tokio::time::interval(heartbeat_interval);
+
+        let lost = |reason: String| SessionEnd {
+            reason,
+            requested: false,
+        };
+
+        loop {
+            tokio::select! {
+                // Receive messages from the server
+                result = conn.recv() => {
+                    match result {
+                        Ok(Some(frame)) => {
+                            match &frame.message {
+                                Message::Disconnect { reason } => {
+                                    return lost(reason.clone());
+                                }
+                                Message::Heartbeat { timestamp } => {
+                                    let _ = conn.send(&Message::HeartbeatAck {
+                                        timestamp: *timestamp,
+                                    }).await;
+                                }
+                                Message::HeartbeatAck { timestamp } => {
+                                    if let Some(rtt) = conn.heartbeat_acked(*timestamp) {
+                                        tracing::trace!("RTT to {}: {:?}", conn.remote_addr(), rtt);
+                                    }
+                                    handle.update_stats(conn.stats());
+                                }
+                                _ => {
+                                    let _ = self.event_tx.send(ClientEvent::MessageReceived {
+                                        message: frame.message,
+                                    }).await;
+                                }
+                            }
+                        }
+                        Ok(None) => {
+                            return lost("Connection closed".to_string());
+                        }
+                        Err(e) => {
+                            return lost(format!("Error: {}", e));
+                        }
+                    }
+                }
+
+                // Send messages to the server
+                batch = outbox.recv_batch() => {
+                    let batch = match batch {
+                        Ok(batch) => batch,
+                        Err(e) => return lost(format!("Link stalled: {}", e)),
+                    };
+                    // A write that stays stuck until the queue fills is abandoned
+                    let sent = tokio::select! {
+                        sent = conn.send_batch(&batch) => sent,
+                        e = outbox.full() => return lost(format!("Link stalled: {}", e)),
+                    };
+                    if let Err(e) = sent {
+                        return lost(format!("Send error: {}", e));
+                    }
+                }
+
+                // Send heartbeats, giving up on a server that went quiet
+                _ = heartbeat_timer.tick() => {
+                    match conn.heartbeat(heartbeat_interval, missed_heartbeats).await {
+                        Ok(()) => {}
+                        Err(e @ ConnectionError::PeerTimeout { .. }) => {
+                            tracing::warn!("Server stopped responding: {}", e);
+                            return lost(format!("Server stopped responding: {}", e));
+                        }
+                        Err(e) => return lost(format!("Heartbeat error: {}", e)),
+                    }
+                }
+
+                // Shutdown signal
+                _ = self.shutdown_rx.recv() => {
+                    return SessionEnd {
+                        reason: "Client shutdown requested".to_string(),
+                        requested: true,
+                    };
+                }
+            }
+        }
+    }
+
+    /// Dial the server with exponential backoff until it answers, the
+    /// policy gives up, or shutdown is requested. Returns the new connection
+    /// and the number of attempts it took.
+    async fn redial(
+        &mut self,
+        policy: &ReconnectPolicy,
+        reason: &str,
+        resume_token: Option<&str>,
+    ) -> Result<(Connection, u32), String> {
+        let mut attempt = 0;
+        let mut last_error = reason.to_string();
+
+        loop {
+            attempt += 1;
+            if policy.max_attempts.is_some_and(|max| attempt > max) {
+                return Err(format!(
+                    "Gave up reconnecting after {} attempts: {}",
+                    attempt - 1,
+                    last_error
+                ));
+            }
+
+            let delay = policy.delay(attempt);
+            tracing::info!(
+                "Connection lost ({}), reconnect attempt {} in {:?}",
+                last_error,
+                attempt,
+                delay
+            );
+            let _ = self.event_tx.send(ClientEvent::Reconnecting {
+                attempt,
+                delay,
+                reason: last_error.clone(),
+            }).await;
+
+            let result = tokio::select! {
+                result = async {
+                    tokio::time::sleep(delay).await;
+                    self.dialer.dial(resume_token).await
+                } => result,
+                _ = self.shutdown_rx.recv() => {
+                    return Err("Client shutdown requested".to_string());
+                }
+            };
+
+            match result {
+                Ok(conn) => return Ok((conn, attempt)),
+                Err(e) if e.is_transient() => last_error = e.to_string(),
+                Err(e) => return Err(format!("Reconnect failed: {}", e)),
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::network::{Server, ServerEvent};
+
+    /// Start a server on `config`, returning it with the loopback address
+    /// of its first listener
+    async fn start_server(config: NetworkConfig) -> (Server, SocketAddr) {
+        let info = ScreenInfo::new("server".into(), "Server".into(), 1920, 1080);
+        let mut server = Server::new(config, info);
+        let mut events = server.take_event_receiver().unwrap();
+        server.start().await.unwrap();
+        let port = match events.recv().await {
+            Some(ServerEvent::Started { bind_addrs }) => bind_addrs[0].port(),
+            other => panic!("unexpected event: {:?}", other),
+        };
+        (server, SocketAddr::from(([127, 0, 0, 1], port)))
+    }
 
     #[tokio::test]
     async fn test_client_creation() {
@@ -351,4 +914,309 @@ mod tests {
         let client = Client::new(config, screen_info);
         assert!(!client.is_connected().await);
     }
+
+    #[tokio::test]
+    async fn test_pinned_fingerprint_change_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let known_hosts = dir.path().join(trust::KNOWN_HOSTS_FILE);
+
+        let start_tls_server = |identity_dir: std::path::PathBuf| {
+            let identity = trust::Identity::load_or_generate(&identity_dir, "server").unwrap();
+            start_server(NetworkConfig::new(0).with_tls(
+                identity.cert_path.to_string_lossy().to_string(),
+                identity.key_path.to_string_lossy().to_string(),
+            ))
+        };
+
+        let new_client = || {
+            let config = NetworkConfig {
+                known_hosts_path: Some(known_hosts.to_string_lossy().to_string()),
+                ..Default::default()
+            };
+            let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+            Client::new(config, info).with_trust_prompt(Arc::new(|_, _| true))
+        };
+
+        // First contact pins the fingerprint
+        let (_server, addr) = start_tls_server(dir.path().join("a")).await;
+        let client = new_client();
+        client.connect(addr).await.unwrap();
+        assert!(client.is_connected().await);
+
+        // Same address, different identity
+        let (_other, other_addr) = start_tls_server(dir.path().join("b")).await;
+        let mut pinned = KnownHosts::load(&known_hosts).unwrap();
+        let fp = std::fs::read_to_string(&known_hosts).unwrap();
+        let fp = fp.split_whitespace().nth(1).unwrap().to_string();
+        pinned
+            .pin(&trust::known_host_key("127.0.0.1", other_addr.port()), &fp)
+            .unwrap();
+
+        let result = new_client().connect(other_addr).await;
+        assert!(matches!(
+            result,
+            Err(ClientError::Connection(ConnectionError::FingerprintMismatch { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_only_load_refusals_are_transient() {
+        let rejected = |code| ClientError::Connection(ConnectionError::Rejected {
+            code,
+            reason: String::new(),
+        });
+        assert!(rejected(error_codes::SERVER_FULL).is_transient());
+        assert!(rejected(error_codes::RATE_LIMITED).is_transient());
+        assert!(rejected(error_codes::DUPLICATE_HOST).is_transient());
+        assert!(!rejected(error_codes::AUTHENTICATION_FAILED).is_transient());
+        assert!(!rejected(error_codes::PROTOCOL_MISMATCH).is_transient());
+
+        assert!(ClientError::Connection(ConnectionError::Tls("eof".into())).is_transient());
+        let bad_cert = ConnectionError::CertificateRejected("unknown issuer".into());
+        assert!(!ClientError::Connection(bad_cert).is_transient());
+    }
+
+    #[tokio::test]
+    async fn test_full_server_rejection_carries_code() {
+        let config = NetworkConfig {
+            max_clients: 1,
+            ..NetworkConfig::new(0).without_tls()
+        };
+        let (_server, addr) = start_server(config).await;
+
+        let new_client = |host_id: &str| {
+            let info = ScreenInfo::new(host_id.into(), "Client".into(), 1280, 800);
+            Client::new(NetworkConfig::default().without_tls(), info)
+        };
+        let first = new_client("first");
+        first.connect(addr).await.unwrap();
+
+        let err = new_client("second").connect(addr).await.unwrap_err();
+        assert!(
+            matches!(err, ClientError::Connection(ConnectionError::Rejected { code: error_codes::SERVER_FULL, .. })),
+            "{:?}",
+            err
+        );
+        assert!(err.is_transient());
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_ca_is_permanent() {
+        let dir = tempfile::tempdir().unwrap();
+        let server_id = trust::Identity::load_or_generate(&dir.path().join("server"), "server").unwrap();
+        let other_id = trust::Identity::load_or_generate(&dir.path().join("other"), "other").unwrap();
+        let (_server, addr) = start_server(NetworkConfig::new(0).with_tls(
+            server_id.cert_path.to_string_lossy().to_string(),
+            server_id.key_path.to_string_lossy().to_string(),
+        ))
+        .await;
+
+        let config = NetworkConfig::default().with_ca(other_id.cert_path.to_string_lossy().to_string());
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let err = Client::new(config, info).connect(addr).await.unwrap_err();
+        assert!(matches!(err, ClientError::Connection(ConnectionError::CertificateRejected(_))), "{:?}", err);
+        assert!(!err.is_transient());
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: None,
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert_eq!(policy.delay(10), Duration::from_millis(1000));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_millis(1000));
+
+        let jittered = ReconnectPolicy { jitter: 0.5, ..policy };
+        for _ in 0..100 {
+            let delay = jittered.delay(2);
+            assert!(delay > Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_server_restart() {
+        let (mut server, addr) = start_server(NetworkConfig::new(0).without_tls()).await;
+
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let mut client = Client::new(NetworkConfig::default().without_tls(), info)
+            .with_reconnect(ReconnectPolicy {
+                initial_delay: Duration::from_millis(50),
+                max_delay: Duration::from_millis(200),
+                ..Default::default()
+            });
+        let mut events = client.take_event_receiver().unwrap();
+        client.connect(addr).await.unwrap();
+        assert!(matches!(events.recv().await, Some(ClientEvent::Connected { .. })));
+
+        server.stop().await.unwrap();
+        drop(server);
+        assert!(matches!(events.recv().await, Some(ClientEvent::Reconnecting { attempt: 1, .. })));
+        assert_eq!(client.state().await, ClientState::Reconnecting);
+
+        let (_server, _) = start_server(NetworkConfig::new(addr.port()).without_tls()).await;
+        let reconnected = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match events.recv().await {
+                    Some(ClientEvent::Reconnecting { .. }) => continue,
+                    other => return other,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(reconnected, Some(ClientEvent::Reconnected { .. })));
+        assert!(client.is_connected().await);
+
+        client.disconnect().await.unwrap();
+        assert!(matches!(events.recv().await, Some(ClientEvent::Disconnected { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_gives_up_after_max_attempts() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let dialer = Dialer {
+            config: NetworkConfig::default().without_tls(),
+            screen_info: info,
+            trust_prompt: None,
+            connector: Arc::new(TcpConnector),
+            tls_connector: None,
+            inbound: false,
+            server_addr: addr,
+            server_name: "127.0.0.1".to_string(),
+        };
+        drop(listener);
+
+        let (event_tx, mut events) = mpsc::channel(16);
+        let (_shutdown_tx, shutdown_rx) = mpsc::channel(1);
+        let mut supervisor = Supervisor {
+            dialer,
+            reconnect: None,
+            state: Arc::new(RwLock::new(ClientState::Reconnecting)),
+            server_screen: Arc::new(RwLock::new(None)),
+            connection_handle: Arc::new(RwLock::new(None)),
+            event_tx,
+            shutdown_rx,
+        };
+        let policy = ReconnectPolicy {
+            initial_delay: Duration::from_millis(1),
+            max_attempts: Some(3),
+            ..Default::default()
+        };
+
+        let result = supervisor.redial(&policy, "Connection closed", None).await;
+        assert!(matches!(result, Err(reason) if reason.starts_with("Gave up reconnecting after 3 attempts")));
+        for attempt in 1..=3 {
+            assert!(matches!(events.recv().await, Some(ClientEvent::Reconnecting { attempt: a, .. }) if a == attempt));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_link_resumes_session() {
+        use tokio::net::{TcpListener, TcpStream};
+
+        let (server, server_addr) = start_server(NetworkConfig::new(0).without_tls()).await;
+
+        // Proxy whose first connection stops passing the server's side
+        // once told to, so heartbeats go unanswered
+        let stall = Arc::new(tokio::sync::Notify::new());
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = proxy.local_addr().unwrap();
+        let stalled = stall.clone();
+        tokio::spawn(async move {
+            let mut first = true;
+            loop {
+                let (client, _) = proxy.accept().await.unwrap();
+                let upstream = TcpStream::connect(server_addr).await.unwrap();
+                let (mut client_rx, mut client_tx) = client.into_split();
+                let (mut server_rx, mut server_tx) = upstream.into_split();
+                tokio::spawn(async move {
+                    let _ = tokio::io::copy(&mut client_rx, &mut server_tx).await;
+                });
+                let stall = first.then(|| stalled.clone());
+                first = false;
+                tokio::spawn(async move {
+                    let copy = tokio::io::copy(&mut server_rx, &mut client_tx);
+                    match stall {
+                        Some(stall) => tokio::select! {
+                            _ = copy => {}
+                            _ = stall.notified() => std::future::pending().await,
+                        },
+                        None => drop(copy.await),
+                    }
+                });
+            }
+        });
+
+        let config = NetworkConfig {
+            heartbeat_interval_ms: 50,
+            missed_heartbeats: 2,
+            ..NetworkConfig::default().without_tls()
+        };
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let mut client = Client::new(config, info).with_reconnect(ReconnectPolicy {
+            initial_delay: Duration::from_millis(50),
+            max_delay: Duration::from_millis(200),
+            ..Default::default()
+        });
+        let mut events = client.take_event_receiver().unwrap();
+        client.connect(addr).await.unwrap();
+        assert!(matches!(events.recv().await, Some(ClientEvent::Connected { .. })));
+
+        stall.notify_one();
+        let reconnected = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match events.recv().await {
+                    Some(ClientEvent::Reconnecting { .. }) => continue,
+                    other => return other,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(reconnected, Some(ClientEvent::Reconnected { resumed: true, .. })));
+        assert_eq!(server.clients().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_silent_server_is_dropped() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Completes the handshake, then never answers again
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let mut conn = Connection::new(stream, peer);
+            let info = ScreenInfo::new("server".into(), "Server".into(), 1920, 1080);
+            conn.handshake_server(&info).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let config = NetworkConfig {
+            heartbeat_interval_ms: 20,
+            missed_heartbeats: 2,
+            ..NetworkConfig::default().without_tls()
+        };
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let mut client = Client::new(config, info);
+        let mut events = client.take_event_receiver().unwrap();
+        client.connect(addr).await.unwrap();
+        assert!(matches!(events.recv().await, Some(ClientEvent::Connected { .. })));
+
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await;
+        match event.unwrap() {
+            Some(ClientEvent::Disconnected { reason }) => {
+                assert!(reason.contains("stopped responding"), "{}", reason);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(!client.is_connected().await);
+    }
 }
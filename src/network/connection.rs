@@ -4,18 +4,30 @@
 //! - Message encoding/decoding
 //! - Heartbeat handling
 //! - Connection state management
+//! - Spreading messages over extra streams on transports that have them
+//! - Sending pointer motion over UDP when both ends agree
+//! - Queueing outgoing messages without blocking the sender, merging
+//!   pointer motion that backs up behind a congested link and giving up
+//!   on a link that stalls
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::io;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::{JoinHandle, JoinSet};
 
-use crate::protocol::{Decoder, Encoder, Frame, Message, ScreenInfo, PROTOCOL_VERSION};
+use super::motion::{self, MotionChannel};
+use super::transport::{Multiplex, RecvLane, SendLane, Transport};
+use crate::protocol::{
+    error_codes, Decoder, Encoder, Features, Frame, Message, MotionGrant, ScreenInfo, VersionRange,
+    MAX_HANDSHAKE_MESSAGE_SIZE, MAX_MESSAGE_SIZE, PROTOCOL_VERSION,
+};
 
 /// Connection errors
 #[derive(Error, Debug)]
@@ -33,17 +45,74 @@ pub enum ConnectionError {
     HandshakeFailed(String),
     
     #[error("Protocol version mismatch: local={local}, remote={remote}")]
-    VersionMismatch { local: u32, remote: u32 },
+    VersionMismatch { local: VersionRange, remote: VersionRange },
     
     #[error("Connection timeout")]
     Timeout,
     
-    #[error("Send channel closed")]
-    SendChannelClosed,
+    #[error("TLS error: {0}")]
+    Tls(String),
+    
+    #[error("Certificate verification failed: {0}")]
+    CertificateRejected(String),
+    
+    #[error("WebSocket error: {0}")]
+    WebSocket(String),
+    
+    #[error("Connection rejected: {reason}")]
+    Rejected { code: u32, reason: String },
+    
+    #[error("Pairing failed: {0}")]
+    PairingFailed(String),
+    
+    #[error("Peer missed {missed} heartbeats (nothing received for {silence:?})")]
+    PeerTimeout { missed: u32, silence: Duration },
+    
+    #[error("Send queue full ({depth} messages, {bytes} bytes of clipboard data waiting)")]
+    SendQueueFull { depth: usize, bytes: usize },
+    
+    #[error("Certificate fingerprint for {host} changed: expected {expected}, got {actual}")]
+    FingerprintMismatch {
+        host: String,
+        expected: String,
+        actual: String,
+    },
 }
 
 pub type ConnectionResult<T> = Result<T, ConnectionError>;
 
+/// Why the server turned a client away during the handshake
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    /// Error code from `protocol::error_codes`
+    pub code: u32,
+    /// Human-readable reason sent to the client
+    pub reason: String,
+}
+
+impl Rejection {
+    pub fn new(code: u32, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+
+    /// The client failed an authentication or access check
+    pub fn authentication(reason: impl Into<String>) -> Self {
+        Self::new(error_codes::AUTHENTICATION_FAILED, reason)
+    }
+}
+
+/// What the server grants a client it admits
+#[derive(Debug, Clone, Default)]
+pub struct SessionGrant {
+    /// Token the client can present to resume the session
+    pub resume_token: Option<String>,
+    /// The client's previous session was resumed
+    pub resumed: bool,
+}
+
 /// State of a connection
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConnectionState {
@@ -63,22 +132,40 @@ pub enum ConnectionState {
 pub struct Connection {
     /// Remote peer address
     remote_addr: SocketAddr,
-    /// The TCP stream
-    stream: TcpStream,
+    /// The underlying transport (TCP, TLS, ...)
+    stream: Box<dyn Transport>,
     /// Protocol encoder
     encoder: Encoder,
     /// Protocol decoder
     decoder: Decoder,
     /// Read buffer
     read_buf: BytesMut,
-    /// Write buffer
+    /// Frames waiting to be written to the main stream
     write_buf: BytesMut,
+    /// Frames waiting to be written to the input stream
+    input_buf: BytesMut,
+    /// Extra streams, once the handshake is done, if the transport has them
+    lanes: Option<Lanes>,
+    /// UDP channel for pointer motion
+    motion: MotionSetup,
     /// Remote screen info (populated after handshake)
     remote_screen_info: Option<ScreenInfo>,
     /// Connection state
     state: ConnectionState,
+    /// Session granted by the server in HelloAck
+    session: SessionGrant,
+    /// Protocol version agreed in the handshake
+    protocol_version: u32,
+    /// Features agreed in the handshake
+    features: Features,
     /// Last activity timestamp
     last_activity: Instant,
+    /// When a frame was last received from the peer
+    last_received: Instant,
+    /// Reference point for heartbeat timestamps
+    epoch: Instant,
+    /// Heartbeats awaiting an ack and recent RTT samples
+    rtt_window: RttWindow,
     /// Statistics
     stats: ConnectionStats,
 }
@@ -90,31 +177,152 @@ pub struct ConnectionStats {
     pub messages_sent: u64,
     /// Messages received
     pub messages_received: u64,
+    /// Messages of a type this build doesn't know, skipped
+    pub messages_skipped: u64,
     /// Bytes sent
     pub bytes_sent: u64,
     /// Bytes received
     pub bytes_received: u64,
     /// Round-trip time (microseconds)
     pub rtt_us: u64,
+    /// Lowest RTT over the recent window (microseconds)
+    pub rtt_min_us: u64,
+    /// Mean RTT over the recent window (microseconds)
+    pub rtt_avg_us: u64,
+    /// 99th percentile RTT over the recent window (microseconds)
+    pub rtt_p99_us: u64,
+    /// Smoothed variation between consecutive RTTs, as in RFC 3550 (microseconds)
+    pub jitter_us: u64,
+    /// Heartbeats sent
+    pub heartbeats_sent: u64,
+    /// Heartbeats acknowledged by the peer
+    pub heartbeats_acked: u64,
+    /// Heartbeats never acknowledged
+    pub heartbeats_lost: u64,
+    /// Pointer motion datagrams ignored as stale or forged
+    pub motion_dropped: u64,
+    /// Messages queued on the handle, not yet written
+    pub send_queue_depth: u64,
+    /// Motion events merged into motion already queued
+    pub motion_coalesced: u64,
+}
+
+impl ConnectionStats {
+    /// Fraction of settled heartbeats that were lost (0.0 - 1.0)
+    pub fn loss(&self) -> f64 {
+        let settled = self.heartbeats_acked + self.heartbeats_lost;
+        if settled == 0 {
+            0.0
+        } else {
+            self.heartbeats_lost as f64 / settled as f64
+        }
+    }
+}
+
+/// Free space made in the read buffer before each read
+const READ_CHUNK: usize = 4096;
+
+/// Number of RTT samples kept for min/avg/p99
+const RTT_WINDOW: usize = 128;
+
+/// Heartbeats give up on an ack after this many newer ones are outstanding
+const MAX_PENDING_HEARTBEATS: usize = 32;
+
+/// Messages a `ConnectionHandle` holds for a link that isn't keeping up
+/// before the connection is given up on
+const MAX_SEND_QUEUE_DEPTH: usize = 4096;
+
+/// Clipboard data a `ConnectionHandle` holds, likewise
+const MAX_SEND_QUEUE_BYTES: usize = 4 * MAX_MESSAGE_SIZE;
+
+/// Clipboard frames waiting for the bulk stream writer; sending more
+/// waits, so a slow transfer backs up into the send queue and its limits
+const BULK_QUEUE_DEPTH: usize = 2;
+
+/// Rolling RTT measurements fed by heartbeat acks
+#[derive(Debug, Default)]
+struct RttWindow {
+    /// Timestamps of heartbeats not yet acknowledged, oldest first
+    pending: VecDeque<u64>,
+    /// Recent RTT samples (microseconds), oldest first
+    samples: VecDeque<u64>,
+}
+
+impl RttWindow {
+    fn sent(&mut self, timestamp: u64, stats: &mut ConnectionStats) {
+        stats.heartbeats_sent += 1;
+        self.pending.push_back(timestamp);
+        if self.pending.len() > MAX_PENDING_HEARTBEATS {
+            self.pending.pop_front();
+            stats.heartbeats_lost += 1;
+        }
+    }
+
+    /// Settle the heartbeat `timestamp`; earlier ones still pending were lost.
+    /// Returns whether it was one of ours.
+    fn acked(&mut self, timestamp: u64, rtt_us: u64, stats: &mut ConnectionStats) -> bool {
+        if !self.pending.contains(&timestamp) {
+            return false;
+        }
+        while let Some(sent) = self.pending.pop_front() {
+            if sent == timestamp {
+                break;
+            }
+            stats.heartbeats_lost += 1;
+        }
+        stats.heartbeats_acked += 1;
+
+        if !self.samples.is_empty() {
+            let delta = rtt_us.abs_diff(stats.rtt_us) as i64;
+            let jitter = stats.jitter_us as i64;
+            stats.jitter_us = (jitter + (delta - jitter) / 16) as u64;
+        }
+        stats.rtt_us = rtt_us;
+
+        self.samples.push_back(rtt_us);
+        if self.samples.len() > RTT_WINDOW {
+            self.samples.pop_front();
+        }
+
+        let mut sorted: Vec<u64> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        stats.rtt_min_us = sorted[0];
+        stats.rtt_avg_us = sorted.iter().sum::<u64>() / sorted.len() as u64;
+        stats.rtt_p99_us = sorted[(sorted.len() * 99).div_ceil(100) - 1];
+        true
+    }
 }
 
 impl Connection {
-    /// Create a new connection from an established TCP stream
-    pub fn new(stream: TcpStream, remote_addr: SocketAddr) -> Self {
-        if let Err(e) = stream.set_nodelay(true) {
-            tracing::warn!("Failed to enable TCP_NODELAY on {}: {}", remote_addr, e);
+    /// Create a new connection over an established transport
+    pub fn new(stream: impl Transport + 'static, remote_addr: SocketAddr) -> Self {
+        if let Err(e) = stream.set_low_latency() {
+            tracing::warn!("Failed to enable low latency mode on {}: {}", remote_addr, e);
         }
 
+        // Raised once the handshake is done
+        let mut decoder = Decoder::new();
+        decoder.set_max_size(MAX_HANDSHAKE_MESSAGE_SIZE);
+
         Self {
             remote_addr,
-            stream,
+            stream: Box::new(stream),
             encoder: Encoder::new(),
-            decoder: Decoder::new(),
+            decoder,
             read_buf: BytesMut::with_capacity(4096),
             write_buf: BytesMut::with_capacity(4096),
+            input_buf: BytesMut::new(),
+            lanes: None,
+            motion: MotionSetup::Off,
             remote_screen_info: None,
             state: ConnectionState::Connecting,
+            session: SessionGrant::default(),
+            protocol_version: PROTOCOL_VERSION,
+            features: Features::NONE,
             last_activity: Instant::now(),
+            last_received: Instant::now(),
+            epoch: Instant::now(),
+            rtt_window: RttWindow::default(),
             stats: ConnectionStats::default(),
         }
     }
@@ -134,22 +342,79 @@ impl Connection {
         self.remote_screen_info.as_ref()
     }
 
+    /// Get the session granted during the handshake
+    pub fn session(&self) -> &SessionGrant {
+        &self.session
+    }
+
+    /// Protocol version the session uses; the newest this build speaks
+    /// until the handshake is done
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    /// Features the session uses; none until the handshake is done
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
     /// Get connection statistics
     pub fn stats(&self) -> &ConnectionStats {
         &self.stats
     }
 
+    /// Whether the connection is protected by TLS
+    pub fn is_encrypted(&self) -> bool {
+        self.stream.is_encrypted()
+    }
+
+    /// Fingerprint of the peer's TLS certificate, if it presented one
+    pub fn peer_fingerprint(&self) -> Option<String> {
+        self.stream.peer_fingerprint()
+    }
+
     /// Perform the server-side handshake
     pub async fn handshake_server(&mut self, local_screen: &ScreenInfo) -> ConnectionResult<()> {
+        self.handshake_server_with(local_screen, |_, _| Ok(SessionGrant::default())).await
+    }
+
+    /// Perform the server-side handshake, letting `admit` turn the client
+    /// away once its Hello has been read. `admit` also receives the resume
+    /// token the client presented and decides which session it gets.
+    pub async fn handshake_server_with<F>(
+        &mut self,
+        local_screen: &ScreenInfo,
+        admit: F,
+    ) -> ConnectionResult<()>
+    where
+        F: FnOnce(&ScreenInfo, Option<&str>) -> Result<SessionGrant, Rejection>,
+    {
         // Wait for Hello from client
         let frame = self.recv().await?.ok_or_else(|| {
             ConnectionError::HandshakeFailed("Connection closed during handshake".to_string())
         })?;
+        self.handshake_server_from(frame.message, local_screen, admit).await
+    }
 
-        let (remote_version, remote_screen) = match frame.message {
-            Message::Hello { protocol_version, screen_info } => {
-                (protocol_version, screen_info)
-            }
+    /// Continue the server-side handshake from the client's first message,
+    /// for callers that read it themselves
+    pub async fn handshake_server_from<F>(
+        &mut self,
+        first: Message,
+        local_screen: &ScreenInfo,
+        admit: F,
+    ) -> ConnectionResult<()>
+    where
+        F: FnOnce(&ScreenInfo, Option<&str>) -> Result<SessionGrant, Rejection>,
+    {
+        let (remote_versions, offered, remote_screen, resume_token, motion_port) = match first {
+            Message::Hello {
+                versions,
+                features,
+                screen_info,
+                resume_token,
+                motion_port,
+            } => (versions, features, screen_info, resume_token, motion_port),
             _ => {
                 return Err(ConnectionError::HandshakeFailed(
                     "Expected Hello message".to_string(),
@@ -157,53 +422,149 @@ impl Connection {
             }
         };
 
-        // Check protocol version
-        if remote_version != PROTOCOL_VERSION {
-            // Send rejection
-            self.send(&Message::HelloAck {
-                protocol_version: PROTOCOL_VERSION,
-                screen_info: local_screen.clone(),
-                accepted: false,
-                reason: Some(format!(
-                    "Protocol version mismatch: expected {}, got {}",
-                    PROTOCOL_VERSION, remote_version
-                )),
-            })
-            .await?;
-
+        // Settle on the highest version both ends speak
+        let Some(version) = VersionRange::SUPPORTED.highest_common(&remote_versions) else {
+            self.remote_screen_info = Some(remote_screen);
+            let rejection = Rejection::new(
+                error_codes::PROTOCOL_MISMATCH,
+                format!(
+                    "Protocol version mismatch: server speaks {}, client speaks {}",
+                    VersionRange::SUPPORTED,
+                    remote_versions
+                ),
+            );
+            self.send_rejection(local_screen, &rejection).await?;
             return Err(ConnectionError::VersionMismatch {
-                local: PROTOCOL_VERSION,
-                remote: remote_version,
+                local: VersionRange::SUPPORTED,
+                remote: remote_versions,
             });
+        };
+
+        let session = match admit(&remote_screen, resume_token.as_deref()) {
+            Ok(session) => session,
+            Err(rejection) => {
+                self.remote_screen_info = Some(remote_screen);
+                self.send_rejection(local_screen, &rejection).await?;
+                return Err(ConnectionError::Rejected {
+                    code: rejection.code,
+                    reason: rejection.reason,
+                });
+            }
+        };
+
+        self.use_version(version);
+        let mut features = self.local_features().intersection(offered);
+        let motion = match motion_port {
+            Some(port) if features.contains(Features::UDP_MOTION) => self.grant_motion(port).await,
+            _ => None,
+        };
+        if motion.is_none() {
+            features.remove(Features::UDP_MOTION);
         }
 
         // Send acceptance
         self.send(&Message::HelloAck {
-            protocol_version: PROTOCOL_VERSION,
+            protocol_version: version,
+            features,
             screen_info: local_screen.clone(),
             accepted: true,
             reason: None,
+            resume_token: session.resume_token.clone(),
+            resumed: session.resumed,
+            motion,
         })
         .await?;
 
         self.remote_screen_info = Some(remote_screen);
+        self.session = session;
+        self.features = features;
+        self.decoder.set_max_size(MAX_MESSAGE_SIZE);
         self.state = ConnectionState::Connected;
+        self.open_lanes();
         
         tracing::info!(
-            "Handshake complete with {} ({})",
+            "Handshake complete with {} ({}), protocol {}, features: {}",
             self.remote_screen_info.as_ref().unwrap().host_name,
-            self.remote_addr
+            self.remote_addr,
+            self.protocol_version,
+            self.features
         );
 
         Ok(())
     }
 
+    /// Read the client's Hello and turn it away
+    pub async fn reject_handshake(
+        &mut self,
+        local_screen: &ScreenInfo,
+        rejection: &Rejection,
+    ) -> ConnectionResult<()> {
+        // Drain the Hello so the rejection is read as its response
+        if let Some(frame) = self.recv().await? {
+            if let Message::Hello { screen_info, .. } = frame.message {
+                self.remote_screen_info = Some(screen_info);
+            }
+        }
+
+        self.send_rejection(local_screen, rejection).await
+    }
+
+    /// Answer Hello with a refusal followed by the error code, then hang up
+    async fn send_rejection(
+        &mut self,
+        local_screen: &ScreenInfo,
+        rejection: &Rejection,
+    ) -> ConnectionResult<()> {
+        self.send(&Message::HelloAck {
+            protocol_version: PROTOCOL_VERSION,
+            features: Features::NONE,
+            screen_info: local_screen.clone(),
+            accepted: false,
+            reason: Some(rejection.reason.clone()),
+            resume_token: None,
+            resumed: false,
+            motion: None,
+        })
+        .await?;
+
+        self.send(&Message::Error {
+            code: rejection.code,
+            message: rejection.reason.clone(),
+        })
+        .await?;
+
+        self.state = ConnectionState::Closed;
+        let _ = self.stream.shutdown().await;
+
+        tracing::info!("Rejected {}: {}", self.remote_addr, rejection.reason);
+
+        Ok(())
+    }
+
     /// Perform the client-side handshake
     pub async fn handshake_client(&mut self, local_screen: &ScreenInfo) -> ConnectionResult<()> {
+        self.handshake_client_resume(local_screen, None).await
+    }
+
+    /// Perform the client-side handshake, asking to resume the session
+    /// identified by `resume_token` if one is given
+    pub async fn handshake_client_resume(
+        &mut self,
+        local_screen: &ScreenInfo,
+        resume_token: Option<&str>,
+    ) -> ConnectionResult<()> {
         // Send Hello
+        let motion_port = match &self.motion {
+            MotionSetup::Offered(socket) => socket.local_addr().ok().map(|a| a.port()),
+            _ => None,
+        };
+        let offered = self.local_features();
         self.send(&Message::Hello {
-            protocol_version: PROTOCOL_VERSION,
+            versions: VersionRange::SUPPORTED,
+            features: offered,
             screen_info: local_screen.clone(),
+            resume_token: resume_token.map(str::to_string),
+            motion_port,
         })
         .await?;
 
@@ -215,30 +576,64 @@ impl Connection {
         match frame.message {
             Message::HelloAck {
                 protocol_version,
+                features,
                 screen_info,
                 accepted,
                 reason,
+                resume_token,
+                resumed,
+                motion,
             } => {
                 if !accepted {
-                    return Err(ConnectionError::HandshakeFailed(
-                        reason.unwrap_or_else(|| "Connection rejected".to_string()),
-                    ));
+                    // The server follows a refusal with an Error frame
+                    // carrying its code
+                    let reason = reason.unwrap_or_else(|| "Connection rejected".to_string());
+                    return Err(match self.recv().await {
+                        Ok(Some(Frame { message: Message::Error { code, .. }, .. })) => {
+                            ConnectionError::Rejected { code, reason }
+                        }
+                        _ => ConnectionError::HandshakeFailed(reason),
+                    });
                 }
 
-                if protocol_version != PROTOCOL_VERSION {
+                if !VersionRange::SUPPORTED.contains(protocol_version) {
                     return Err(ConnectionError::VersionMismatch {
-                        local: PROTOCOL_VERSION,
-                        remote: protocol_version,
+                        local: VersionRange::SUPPORTED,
+                        remote: VersionRange {
+                            min: protocol_version,
+                            max: protocol_version,
+                        },
                     });
                 }
 
                 self.remote_screen_info = Some(screen_info);
+                self.session = SessionGrant {
+                    resume_token,
+                    resumed,
+                };
+                self.use_version(protocol_version);
+                self.motion = match (std::mem::take(&mut self.motion), motion) {
+                    (MotionSetup::Offered(socket), Some(grant)) => {
+                        let peer = SocketAddr::new(self.remote_addr.ip(), grant.port);
+                        MotionSetup::Active(MotionChannel::new(socket, peer, grant.key, protocol_version))
+                    }
+                    _ => MotionSetup::Off,
+                };
+                // Only what we offered, whatever the server says
+                self.features = offered.intersection(features);
+                if !self.has_motion_channel() {
+                    self.features.remove(Features::UDP_MOTION);
+                }
+                self.decoder.set_max_size(MAX_MESSAGE_SIZE);
                 self.state = ConnectionState::Connected;
+                self.open_lanes();
                 
                 tracing::info!(
-                    "Handshake complete with {} ({})",
+                    "Handshake complete with {} ({}), protocol {}, features: {}",
                     self.remote_screen_info.as_ref().unwrap().host_name,
-                    self.remote_addr
+                    self.remote_addr,
+                    self.protocol_version,
+                    self.features
                 );
 
                 Ok(())
@@ -249,34 +644,200 @@ impl Connection {
         }
     }
 
+    /// Offer the server a UDP channel for pointer motion. Call before the
+    /// client handshake; the server may decline.
+    pub async fn offer_motion_channel(&mut self) -> ConnectionResult<()> {
+        let socket = MotionChannel::bind_for(self.remote_addr).await?;
+        self.motion = MotionSetup::Offered(socket);
+        Ok(())
+    }
+
+    /// Grant a UDP channel for pointer motion to a client that offers one.
+    /// Call before the server handshake.
+    pub fn allow_motion_channel(&mut self) {
+        self.motion = MotionSetup::Allowed;
+    }
+
+    /// Whether pointer motion goes over the UDP channel
+    pub fn has_motion_channel(&self) -> bool {
+        matches!(self.motion, MotionSetup::Active(_))
+    }
+
+    /// Features to offer in the handshake: everything this build
+    /// implements, less the motion channel unless it was offered or allowed
+    fn local_features(&self) -> Features {
+        let mut features = Features::SUPPORTED;
+        if matches!(self.motion, MotionSetup::Off | MotionSetup::Active(_)) {
+            features.remove(Features::UDP_MOTION);
+        }
+        features
+    }
+
+    /// Open the server end of the motion channel for a client on UDP `port`
+    async fn grant_motion(&mut self, port: u16) -> Option<MotionGrant> {
+        let peer = SocketAddr::new(self.remote_addr.ip(), port);
+        let socket = match MotionChannel::bind_for(peer).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                tracing::warn!("Failed to open motion channel for {}: {}", self.remote_addr, e);
+                return None;
+            }
+        };
+        let port = socket.local_addr().ok()?.port();
+        let key = MotionChannel::generate_key();
+        let channel = MotionChannel::new(socket, peer, key.clone(), self.protocol_version);
+        self.motion = MotionSetup::Active(channel);
+        Some(MotionGrant { port, key })
+    }
+
+    /// Give up on the motion channel and send motion on the stream. The
+    /// peer stops hearing acks from us and follows.
+    fn drop_motion_channel(&mut self, why: std::fmt::Arguments<'_>) {
+        tracing::warn!("Motion channel with {} {}, sending motion on the stream", self.remote_addr, why);
+        self.motion = MotionSetup::Off;
+        self.features.remove(Features::UDP_MOTION);
+    }
+
+    /// Speak `version` of the protocol from now on
+    fn use_version(&mut self, version: u32) {
+        self.protocol_version = version;
+        self.encoder.set_protocol_version(version);
+        self.decoder.set_protocol_version(version);
+    }
+
+    /// Start using the transport's extra streams, if it has any
+    fn open_lanes(&mut self) {
+        let version = self.protocol_version;
+        self.lanes = self.stream.multiplex().map(|mux| Lanes::open(mux, version));
+    }
+
     /// Send a message
     pub async fn send(&mut self, message: &Message) -> ConnectionResult<()> {
+        self.send_batch(std::slice::from_ref(message)).await
+    }
+
+    /// Send several messages in order. Frames bound for the same stream
+    /// are encoded into one buffer and written and flushed together, so a
+    /// backlog goes out in a single write instead of one per message.
+    pub async fn send_batch(&mut self, messages: &[Message]) -> ConnectionResult<()> {
         self.write_buf.clear();
-        self.encoder.encode(message, &mut self.write_buf)?;
-        
-        self.stream.write_all(&self.write_buf).await?;
-        self.stream.flush().await?;
-        
-        self.stats.messages_sent += 1;
-        self.stats.bytes_sent += self.write_buf.len() as u64;
+        self.input_buf.clear();
+
+        // Frames encoded before a failure have taken their sequence numbers,
+        // so they go out before the error is returned
+        let encoded = self.encode_batch(messages).await;
+
+        if !self.input_buf.is_empty() {
+            if let Some(lanes) = &mut self.lanes {
+                let input = match lanes.input.take() {
+                    Some(input) => input,
+                    None => lanes.mux.open().await?,
+                };
+                let input = lanes.input.insert(input);
+                input.write_all(&self.input_buf).await?;
+                input.flush().await?;
+            }
+        }
+        if !self.write_buf.is_empty() {
+            self.stream.write_all(&self.write_buf).await?;
+            self.stream.flush().await?;
+        }
+
         self.last_activity = Instant::now();
-        
+        encoded
+    }
+
+    /// Encode a batch into the write buffers, sending motion and bulk
+    /// frames on their own channels as it goes
+    async fn encode_batch(&mut self, messages: &[Message]) -> ConnectionResult<()> {
+        if let MotionSetup::Active(motion) = &mut self.motion {
+            if motion.take_ack() {
+                let len = append(&mut self.encoder, &Message::MotionAck, &mut self.write_buf)?;
+                self.stats.messages_sent += 1;
+                self.stats.bytes_sent += len as u64;
+            }
+            if motion.is_unacknowledged() {
+                self.drop_motion_channel(format_args!("unacknowledged for {:?}", motion::ACK_TIMEOUT));
+            }
+        }
+
+        for message in messages {
+            let motion = matches!(self.motion, MotionSetup::Active(_)) && message.is_pointer_motion();
+            let lane = self.lanes.as_ref().map_or(Lane::Control, |_| Lane::of(message));
+
+            let len = match (motion, lane) {
+                (true, _) => {
+                    let mut datagram = BytesMut::new();
+                    self.encoder.encode(message, &mut datagram)?;
+                    if let MotionSetup::Active(motion) = &mut self.motion {
+                        // A lost datagram isn't worth failing over; newer motion follows
+                        if let Err(e) = motion.send(&datagram).await {
+                            tracing::debug!("Failed to send motion to {}: {}", self.remote_addr, e);
+                        }
+                    }
+                    datagram.len()
+                }
+                (false, Lane::Bulk) => {
+                    let mut data = BytesMut::new();
+                    self.encoder.encode(message, &mut data)?;
+                    let len = data.len();
+                    if let Some(lanes) = &mut self.lanes {
+                        let mux = lanes.mux.clone();
+                        lanes.bulk.get_or_insert_with(|| BulkLane::open(mux)).send(data).await?;
+                    }
+                    len
+                }
+                (false, Lane::Input) => append(&mut self.encoder, message, &mut self.input_buf)?,
+                (false, Lane::Control) => append(&mut self.encoder, message, &mut self.write_buf)?,
+            };
+
+            self.stats.messages_sent += 1;
+            self.stats.bytes_sent += len as u64;
+        }
         Ok(())
     }
 
     /// Receive a message (returns None if no complete message available)
     pub async fn recv(&mut self) -> ConnectionResult<Option<Frame>> {
+        loop {
+            match self.recv_frame().await? {
+                Some(Frame { message: Message::MotionAck, .. }) => {
+                    if let MotionSetup::Active(motion) = &mut self.motion {
+                        motion.acknowledged();
+                    }
+                }
+                frame => return Ok(frame),
+            }
+        }
+    }
+
+    /// Receive the next frame on any channel, including those meant for
+    /// the connection itself
+    async fn recv_frame(&mut self) -> ConnectionResult<Option<Frame>> {
         loop {
             // Try to decode a message from the buffer
-            if let Some(frame) = self.decoder.decode(&mut self.read_buf)? {
-                self.stats.messages_received += 1;
-                self.last_activity = Instant::now();
-                return Ok(Some(frame));
+            let decoded = self.decoder.decode(&mut self.read_buf)?;
+            self.stats.messages_skipped = self.decoder.skipped();
+            if let Some(frame) = decoded {
+                return Ok(Some(self.received(frame)));
             }
 
-            // Read more data
-            let mut buf = [0u8; 4096];
-            let n = self.stream.read(&mut buf).await?;
+            // Read more data straight into the buffer, or take a frame that
+            // came on an extra stream or the motion channel
+            self.read_buf.reserve(READ_CHUNK);
+            let n = tokio::select! {
+                n = self.stream.read_buf(&mut self.read_buf) => n?,
+                Some(frame) = next_lane_frame(&mut self.lanes) => {
+                    return Ok(Some(self.received(frame?)));
+                }
+                result = next_motion_frame(&mut self.motion) => match result {
+                    Ok(frame) => return Ok(Some(self.received(frame))),
+                    Err(e) => {
+                        self.drop_motion_channel(format_args!("failed: {}", e));
+                        continue;
+                    }
+                },
+            };
             
             if n == 0 {
                 if self.read_buf.is_empty() {
@@ -286,11 +847,21 @@ impl Connection {
                 }
             }
             
-            self.read_buf.extend_from_slice(&buf[..n]);
             self.stats.bytes_received += n as u64;
         }
     }
 
+    /// Account for a frame received on any channel
+    fn received(&mut self, frame: Frame) -> Frame {
+        if let MotionSetup::Active(motion) = &self.motion {
+            self.stats.motion_dropped = motion.dropped();
+        }
+        self.stats.messages_received += 1;
+        self.last_activity = Instant::now();
+        self.last_received = self.last_activity;
+        frame
+    }
+
     /// Try to receive a message with a timeout
     pub async fn recv_timeout(&mut self, timeout: Duration) -> ConnectionResult<Option<Frame>> {
         match tokio::time::timeout(timeout, self.recv()).await {
@@ -301,23 +872,17 @@ impl Connection {
 
     /// Send a heartbeat and wait for response
     pub async fn ping(&mut self) -> ConnectionResult<Duration> {
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_micros() as u64;
-
-        let start = Instant::now();
+        let timestamp = self.epoch.elapsed().as_micros() as u64;
         
         self.send(&Message::Heartbeat { timestamp }).await?;
+        self.rtt_window.sent(timestamp, &mut self.stats);
         
         // Wait for HeartbeatAck
         let frame = self.recv_timeout(Duration::from_secs(5)).await?.ok_or(ConnectionError::Closed)?;
         
         match frame.message {
             Message::HeartbeatAck { timestamp: ts } if ts == timestamp => {
-                let rtt = start.elapsed();
-                self.stats.rtt_us = rtt.as_micros() as u64;
-                Ok(rtt)
+                self.heartbeat_acked(ts).ok_or(ConnectionError::Closed)
             }
             _ => Err(ConnectionError::Protocol(
                 crate::protocol::CodecError::InvalidMagic,
@@ -325,6 +890,33 @@ impl Connection {
         }
     }
 
+    /// Send a heartbeat, first checking that the peer has been heard from
+    /// within `missed` heartbeat intervals (0 disables the check). Both sides
+    /// heartbeat, so a silent peer is gone even if the socket looks open.
+    pub async fn heartbeat(&mut self, interval: Duration, missed: u32) -> ConnectionResult<()> {
+        let silence = self.last_received.elapsed();
+        if missed > 0 && silence > interval * missed {
+            return Err(ConnectionError::PeerTimeout { missed, silence });
+        }
+
+        let timestamp = self.epoch.elapsed().as_micros() as u64;
+        self.send(&Message::Heartbeat { timestamp }).await?;
+        self.rtt_window.sent(timestamp, &mut self.stats);
+        Ok(())
+    }
+
+    /// Record the peer's answer to one of our heartbeats, updating the RTT
+    /// statistics. Returns the round-trip time, or None for a stale or
+    /// unknown timestamp.
+    pub fn heartbeat_acked(&mut self, timestamp: u64) -> Option<Duration> {
+        let now = self.epoch.elapsed().as_micros() as u64;
+        let rtt_us = now.checked_sub(timestamp)?;
+        if !self.rtt_window.acked(timestamp, rtt_us, &mut self.stats) {
+            return None;
+        }
+        Some(Duration::from_micros(rtt_us))
+    }
+
     /// Close the connection gracefully
     pub async fn close(&mut self, reason: &str) -> ConnectionResult<()> {
         self.state = ConnectionState::Closing;
@@ -340,9 +932,9 @@ impl Connection {
         Ok(())
     }
 
-    /// Get the underlying stream for advanced operations
-    pub fn stream_ref(&self) -> &TcpStream {
-        &self.stream
+    /// Get the underlying transport for advanced operations
+    pub fn stream_ref(&self) -> &dyn Transport {
+        self.stream.as_ref()
     }
 
     /// Check if the connection is still active
@@ -356,33 +948,386 @@ impl Connection {
     }
 }
 
+/// Append a message's frame to `buf`, returning its size
+fn append(encoder: &mut Encoder, message: &Message, buf: &mut BytesMut) -> ConnectionResult<usize> {
+    let before = buf.len();
+    encoder.encode(message, buf)?;
+    Ok(buf.len() - before)
+}
+
+/// Outgoing stream for clipboard data, written by a task of its own so a
+/// large transfer can't hold up the streams beside it
+struct BulkLane {
+    tx: mpsc::Sender<Bytes>,
+    /// Writes queued frames in order; ends early only on a failed write
+    writer: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl BulkLane {
+    fn open(mux: Arc<dyn Multiplex>) -> Self {
+        let (tx, rx) = mpsc::channel(BULK_QUEUE_DEPTH);
+        Self {
+            tx,
+            writer: Some(tokio::spawn(write_bulk(mux, rx))),
+        }
+    }
+
+    /// Queue a frame, waiting while the queue is full. Fails with the
+    /// writer's error once a write has failed.
+    async fn send(&mut self, data: BytesMut) -> ConnectionResult<()> {
+        if self.tx.send(data.freeze()).await.is_ok() {
+            return Ok(());
+        }
+        match self.writer.take() {
+            Some(writer) => match writer.await {
+                Ok(Err(e)) => Err(e.into()),
+                _ => Err(ConnectionError::Closed),
+            },
+            None => Err(ConnectionError::Closed),
+        }
+    }
+}
+
+impl Drop for BulkLane {
+    fn drop(&mut self) {
+        if let Some(writer) = &self.writer {
+            writer.abort();
+        }
+    }
+}
+
+/// Write clipboard frames to one stream, opened with the first of them
+async fn write_bulk(mux: Arc<dyn Multiplex>, mut rx: mpsc::Receiver<Bytes>) -> io::Result<()> {
+    let mut lane = mux.open().await?;
+    while let Some(data) = rx.recv().await {
+        lane.write_all(&data).await?;
+        lane.flush().await?;
+    }
+    lane.shutdown().await
+}
+
+/// Which stream a message goes on when the transport has several
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lane {
+    /// The main stream: handshake, heartbeats and everything not below
+    Control,
+    /// One long-lived stream for input events
+    Input,
+    /// One long-lived stream for clipboard data
+    Bulk,
+}
+
+impl Lane {
+    fn of(message: &Message) -> Self {
+        if message.is_input_event() {
+            Lane::Input
+        } else if matches!(message, Message::ClipboardData { .. }) {
+            Lane::Bulk
+        } else {
+            Lane::Control
+        }
+    }
+}
+
+/// Where a connection stands on the UDP channel for pointer motion
+#[derive(Debug, Default)]
+enum MotionSetup {
+    /// Not offered, or not granted
+    #[default]
+    Off,
+    /// Client: offered in Hello from this socket
+    Offered(tokio::net::UdpSocket),
+    /// Server: granted to clients that offer it
+    Allowed,
+    /// Agreed in the handshake
+    Active(MotionChannel),
+}
+
+/// Next frame from the peer's extra streams; never ready without them
+async fn next_lane_frame(lanes: &mut Option<Lanes>) -> Option<ConnectionResult<Frame>> {
+    match lanes {
+        Some(lanes) => lanes.incoming.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Next motion frame from the peer; never ready without a motion channel
+async fn next_motion_frame(motion: &mut MotionSetup) -> io::Result<Frame> {
+    match motion {
+        MotionSetup::Active(motion) => motion.recv().await,
+        _ => std::future::pending().await,
+    }
+}
+
+/// The extra streams of a multiplexing transport, see `Lane`
+struct Lanes {
+    mux: Arc<dyn Multiplex>,
+    /// Outgoing input stream, opened with the first input event
+    input: Option<SendLane>,
+    /// Outgoing clipboard stream, opened with the first clipboard data
+    bulk: Option<BulkLane>,
+    /// Frames read from every stream the peer opened
+    incoming: mpsc::Receiver<ConnectionResult<Frame>>,
+    /// Accepts the peer's streams and reads them into `incoming`
+    reader: JoinHandle<()>,
+}
+
+impl Lanes {
+    fn open(mux: Arc<dyn Multiplex>, version: u32) -> Self {
+        let (tx, incoming) = mpsc::channel(256);
+        let reader = tokio::spawn(accept_lanes(mux.clone(), tx, version));
+        Self {
+            mux,
+            input: None,
+            bulk: None,
+            incoming,
+            reader,
+        }
+    }
+}
+
+impl Drop for Lanes {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Read every stream the peer opens until the transport closes. Readers
+/// stop when this task is aborted.
+async fn accept_lanes(
+    mux: Arc<dyn Multiplex>,
+    tx: mpsc::Sender<ConnectionResult<Frame>>,
+    version: u32,
+) {
+    let mut readers = JoinSet::new();
+    while let Ok(lane) = mux.accept().await {
+        while readers.try_join_next().is_some() {}
+        readers.spawn(read_lane(lane, tx.clone(), version));
+    }
+}
+
+/// Decode frames from one of the peer's streams. A stream that fails is
+/// dropped quietly: losing the transport shows up on the main stream.
+async fn read_lane(mut lane: RecvLane, tx: mpsc::Sender<ConnectionResult<Frame>>, version: u32) {
+    let mut decoder = Decoder::for_version(version);
+    let mut buf = BytesMut::with_capacity(4096);
+    loop {
+        match decoder.decode(&mut buf) {
+            Ok(Some(frame)) => {
+                if tx.send(Ok(frame)).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                let _ = tx.send(Err(e.into())).await;
+                return;
+            }
+        }
+
+        match lane.read_buf(&mut buf).await {
+            Ok(0) => {
+                if !buf.is_empty() {
+                    let _ = tx.send(Err(ConnectionError::Closed)).await;
+                }
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::debug!("Extra stream failed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Messages waiting for the connection task to write them. Motion queued
+/// right behind motion that hasn't gone out yet is merged into it, so a
+/// congested link sends one up-to-date move instead of replaying stale
+/// ones. Nothing else is merged or dropped, and order is kept.
+///
+/// A link that falls behind by `MAX_SEND_QUEUE_DEPTH` messages or
+/// `MAX_SEND_QUEUE_BYTES` of clipboard data has stalled: the queue stops
+/// taking messages and the connection task ends the session.
+#[derive(Debug, Default)]
+struct SendQueue {
+    queued: std::sync::Mutex<Queued>,
+    ready: Notify,
+    coalesced: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Queued {
+    messages: VecDeque<Message>,
+    /// Clipboard data held in `messages`
+    bytes: usize,
+    /// Went over the limits; nothing more is queued
+    full: bool,
+}
+
+impl Queued {
+    fn full_error(&self) -> ConnectionError {
+        ConnectionError::SendQueueFull {
+            depth: self.messages.len(),
+            bytes: self.bytes,
+        }
+    }
+}
+
+/// Clipboard data carried by a message, counted against `MAX_SEND_QUEUE_BYTES`
+fn queued_bytes(message: &Message) -> usize {
+    match message {
+        Message::ClipboardData { mime_type, data } => mime_type.len() + data.len(),
+        _ => 0,
+    }
+}
+
+impl SendQueue {
+    fn push(&self, message: Message) -> Result<(), ConnectionError> {
+        let mut queued = self.queued.lock().unwrap();
+        if queued.full {
+            return Err(queued.full_error());
+        }
+
+        let merged = match (queued.messages.back_mut(), &message) {
+            (
+                Some(Message::MouseMoveRelative { dx, dy }),
+                Message::MouseMoveRelative { dx: more_x, dy: more_y },
+            ) => {
+                *dx = dx.saturating_add(*more_x);
+                *dy = dy.saturating_add(*more_y);
+                true
+            }
+            (Some(last @ Message::MouseMoveAbsolute { .. }), Message::MouseMoveAbsolute { .. }) => {
+                *last = message.clone();
+                true
+            }
+            _ => false,
+        };
+
+        if merged {
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        let bytes = queued_bytes(&message);
+        if queued.messages.len() >= MAX_SEND_QUEUE_DEPTH || queued.bytes + bytes > MAX_SEND_QUEUE_BYTES {
+            queued.full = true;
+            let error = queued.full_error();
+            drop(queued);
+            tracing::warn!("Giving up on a stalled link: {}", error);
+            self.ready.notify_one();
+            return Err(error);
+        }
+
+        queued.bytes += bytes;
+        queued.messages.push_back(message);
+        drop(queued);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    /// The next message, or the overflow once the queue is full
+    fn pop(&self) -> Result<Option<Message>, ConnectionError> {
+        let mut queued = self.queued.lock().unwrap();
+        if queued.full {
+            return Err(queued.full_error());
+        }
+        let message = queued.messages.pop_front();
+        if let Some(message) = &message {
+            queued.bytes -= queued_bytes(message);
+        }
+        Ok(message)
+    }
+
+    fn drain_into(&self, batch: &mut Vec<Message>) {
+        let mut queued = self.queued.lock().unwrap();
+        queued.bytes = 0;
+        batch.extend(queued.messages.drain(..));
+    }
+
+    /// Why the queue stopped taking messages, if it has
+    fn overflow(&self) -> Option<ConnectionError> {
+        let queued = self.queued.lock().unwrap();
+        queued.full.then(|| queued.full_error())
+    }
+
+    fn depth(&self) -> usize {
+        self.queued.lock().unwrap().messages.len()
+    }
+}
+
+/// Receiving end of a `ConnectionHandle`, drained by the task that owns
+/// the connection
+#[derive(Debug)]
+pub struct Outbox {
+    queue: Arc<SendQueue>,
+}
+
+impl Outbox {
+    /// Wait for the next queued message. Fails with `SendQueueFull` once
+    /// the link has fallen too far behind. Cancel-safe: a message is only
+    /// taken off the queue when it is returned.
+    pub async fn recv(&mut self) -> Result<Message, ConnectionError> {
+        loop {
+            if let Some(message) = self.queue.pop()? {
+                return Ok(message);
+            }
+            self.queue.ready.notified().await;
+        }
+    }
+
+    /// Wait until something is queued, then take everything that is, so
+    /// it can go out in one `Connection::send_batch`. Cancel-safe like
+    /// `recv`.
+    pub async fn recv_batch(&mut self) -> Result<Vec<Message>, ConnectionError> {
+        let mut batch = vec![self.recv().await?];
+        self.queue.drain_into(&mut batch);
+        Ok(batch)
+    }
+
+    /// Wait until the queue fills up, for giving up on a write that
+    /// isn't making progress. Cancel-safe.
+    pub async fn full(&self) -> ConnectionError {
+        loop {
+            if let Some(e) = self.queue.overflow() {
+                return e;
+            }
+            self.queue.ready.notified().await;
+        }
+    }
+}
+
 /// A handle for sending messages to a connection
 #[derive(Clone, Debug)]
 pub struct ConnectionHandle {
-    sender: mpsc::Sender<Message>,
+    queue: Arc<SendQueue>,
     connected: Arc<AtomicBool>,
-    rtt_us: Arc<AtomicU64>,
+    stats: Arc<std::sync::Mutex<ConnectionStats>>,
 }
 
 impl ConnectionHandle {
-    pub fn new(sender: mpsc::Sender<Message>) -> Self {
-        Self {
-            sender,
+    /// Create a handle and the outbox its connection task drains
+    pub fn new() -> (Self, Outbox) {
+        let queue = Arc::new(SendQueue::default());
+        let handle = Self {
+            queue: queue.clone(),
             connected: Arc::new(AtomicBool::new(true)),
-            rtt_us: Arc::new(AtomicU64::new(0)),
-        }
+            stats: Arc::new(std::sync::Mutex::new(ConnectionStats::default())),
+        };
+        (handle, Outbox { queue })
     }
 
-    /// Send a message through this connection
-    pub async fn send(&self, message: Message) -> Result<(), ConnectionError> {
+    /// Queue a message for this connection. Never waits, so input capture
+    /// keeps running while the link is congested; see `SendQueue` for what
+    /// happens to motion meanwhile, and when a stalled link is given up on.
+    pub fn send(&self, message: Message) -> Result<(), ConnectionError> {
         if !self.connected.load(Ordering::SeqCst) {
             return Err(ConnectionError::Closed);
         }
 
-        self.sender
-            .send(message)
-            .await
-            .map_err(|_| ConnectionError::SendChannelClosed)
+        self.queue.push(message)
     }
 
     /// Check if the connection is still active
@@ -392,7 +1337,16 @@ impl ConnectionHandle {
 
     /// Get the current round-trip time in microseconds
     pub fn rtt_us(&self) -> u64 {
-        self.rtt_us.load(Ordering::SeqCst)
+        self.stats.lock().unwrap().rtt_us
+    }
+
+    /// Get the latest statistics published by the connection task, with
+    /// the send queue as it is now
+    pub fn stats(&self) -> ConnectionStats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.send_queue_depth = self.queue.depth() as u64;
+        stats.motion_coalesced = self.queue.coalesced.load(Ordering::Relaxed);
+        stats
     }
 
     /// Mark the connection as disconnected
@@ -400,8 +1354,427 @@ impl ConnectionHandle {
         self.connected.store(false, Ordering::SeqCst);
     }
 
-    /// Update the RTT value
-    pub fn update_rtt(&self, rtt_us: u64) {
-        self.rtt_us.store(rtt_us, Ordering::SeqCst);
+    /// Publish the connection's statistics
+    pub fn update_stats(&self, stats: &ConnectionStats) {
+        *self.stats.lock().unwrap() = stats.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt_window_statistics() {
+        let mut window = RttWindow::default();
+        let mut stats = ConnectionStats::default();
+
+        for (i, rtt) in [1000, 3000, 2000].into_iter().enumerate() {
+            let timestamp = i as u64 * 10_000;
+            window.sent(timestamp, &mut stats);
+            assert!(window.acked(timestamp, rtt, &mut stats));
+        }
+
+        assert_eq!(stats.rtt_us, 2000);
+        assert_eq!(stats.rtt_min_us, 1000);
+        assert_eq!(stats.rtt_avg_us, 2000);
+        assert_eq!(stats.rtt_p99_us, 3000);
+        // |3000-1000| then |2000-3000|, each folded in at 1/16
+        assert_eq!(stats.jitter_us, 125 + (1000 - 125) / 16);
+        assert_eq!(stats.loss(), 0.0);
+
+        // An ack for an unknown heartbeat is ignored
+        assert!(!window.acked(99, 500, &mut stats));
+        assert_eq!(stats.rtt_us, 2000);
+    }
+
+    #[test]
+    fn test_unanswered_heartbeats_count_as_lost() {
+        let mut window = RttWindow::default();
+        let mut stats = ConnectionStats::default();
+
+        window.sent(1, &mut stats);
+        window.sent(2, &mut stats);
+        window.sent(3, &mut stats);
+        assert!(window.acked(3, 800, &mut stats));
+
+        assert_eq!(stats.heartbeats_sent, 3);
+        assert_eq!(stats.heartbeats_acked, 1);
+        assert_eq!(stats.heartbeats_lost, 2);
+        assert!((stats.loss() - 2.0 / 3.0).abs() < 1e-9);
+
+        // Acks for the lost ones arriving late don't count
+        assert!(!window.acked(1, 5000, &mut stats));
+    }
+
+    #[tokio::test]
+    async fn test_queued_motion_is_merged() {
+        let (handle, mut outbox) = ConnectionHandle::new();
+        let click = |pressed| Message::MouseButton {
+            button: crate::protocol::MouseButton::Left,
+            pressed,
+        };
+
+        handle.send(Message::MouseMoveRelative { dx: 1, dy: 2 }).unwrap();
+        handle.send(Message::MouseMoveRelative { dx: 3, dy: -4 }).unwrap();
+        handle.send(click(true)).unwrap();
+        handle.send(Message::MouseMoveRelative { dx: 5, dy: 5 }).unwrap();
+        handle.send(Message::MouseMoveRelative { dx: i32::MAX, dy: 0 }).unwrap();
+        handle.send(click(false)).unwrap();
+
+        let stats = handle.stats();
+        assert_eq!(stats.send_queue_depth, 4);
+        assert_eq!(stats.motion_coalesced, 2);
+
+        // Motion is merged only up to the button, which keeps its place
+        assert!(matches!(outbox.recv().await.unwrap(), Message::MouseMoveRelative { dx: 4, dy: -2 }));
+        assert!(matches!(outbox.recv().await.unwrap(), Message::MouseButton { pressed: true, .. }));
+        assert!(matches!(outbox.recv().await.unwrap(), Message::MouseMoveRelative { dx: i32::MAX, dy: 5 }));
+        assert!(matches!(outbox.recv().await.unwrap(), Message::MouseButton { pressed: false, .. }));
+        assert_eq!(handle.stats().send_queue_depth, 0);
+
+        // Motion that has gone out isn't merged into
+        handle.send(Message::MouseMoveRelative { dx: 1, dy: 1 }).unwrap();
+        assert!(matches!(outbox.recv().await.unwrap(), Message::MouseMoveRelative { dx: 1, dy: 1 }));
+        handle.send(Message::MouseMoveRelative { dx: 2, dy: 2 }).unwrap();
+        assert!(matches!(outbox.recv().await.unwrap(), Message::MouseMoveRelative { dx: 2, dy: 2 }));
+
+        handle.mark_disconnected();
+        assert!(matches!(handle.send(click(true)), Err(ConnectionError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_queue_gives_up() {
+        let key = |keycode| Message::KeyDown {
+            keycode,
+            character: None,
+            modifiers: Default::default(),
+        };
+
+        // By depth
+        let (handle, mut outbox) = ConnectionHandle::new();
+        for keycode in 0..MAX_SEND_QUEUE_DEPTH as u32 {
+            handle.send(key(keycode)).unwrap();
+        }
+        let full = outbox.full();
+        assert!(matches!(
+            handle.send(key(0)),
+            Err(ConnectionError::SendQueueFull { depth: MAX_SEND_QUEUE_DEPTH, bytes: 0 })
+        ));
+        assert!(matches!(full.await, ConnectionError::SendQueueFull { .. }));
+        assert!(matches!(outbox.recv_batch().await, Err(ConnectionError::SendQueueFull { .. })));
+        assert!(handle.send(Message::MouseMoveRelative { dx: 1, dy: 1 }).is_err());
+        assert_eq!(handle.stats().send_queue_depth, MAX_SEND_QUEUE_DEPTH as u64);
+
+        // By clipboard bytes, which are given back as messages go out
+        let (handle, mut outbox) = ConnectionHandle::new();
+        let clipboard = || Message::ClipboardData {
+            mime_type: String::new(),
+            data: vec![0; MAX_SEND_QUEUE_BYTES / 2],
+        };
+        handle.send(clipboard()).unwrap();
+        handle.send(clipboard()).unwrap();
+        assert!(matches!(outbox.recv().await, Ok(Message::ClipboardData { .. })));
+        handle.send(clipboard()).unwrap();
+        handle.send(key(1)).unwrap();
+        assert!(matches!(
+            handle.send(clipboard()),
+            Err(ConnectionError::SendQueueFull { depth: 3, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_motion_channel_is_negotiated() {
+        use tokio::net::{TcpListener, TcpStream};
+
+        for allow in [true, false] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let server = tokio::spawn(async move {
+                let (stream, peer) = listener.accept().await.unwrap();
+                let mut conn = Connection::new(stream, peer);
+                if allow {
+                    conn.allow_motion_channel();
+                }
+                let info = ScreenInfo::new("server".into(), "Server".into(), 1920, 1080);
+                conn.handshake_server(&info).await.unwrap();
+                assert_eq!(conn.has_motion_channel(), allow);
+                assert_eq!(conn.features().contains(Features::UDP_MOTION), allow);
+
+                let mut received = Vec::new();
+                for _ in 0..2 {
+                    received.push(conn.recv().await.unwrap().unwrap().message.type_id());
+                }
+                received.sort();
+                received
+            });
+
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut conn = Connection::new(stream, addr);
+            conn.offer_motion_channel().await.unwrap();
+            let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+            conn.handshake_client(&info).await.unwrap();
+            assert_eq!(conn.has_motion_channel(), allow);
+            assert_eq!(conn.features().contains(Features::UDP_MOTION), allow);
+            assert!(conn.features().contains(Features::CLIPBOARD));
+            assert_eq!(conn.protocol_version(), PROTOCOL_VERSION);
+
+            let motion = Message::MouseMoveRelative { dx: 1, dy: -1 };
+            let key = Message::KeyDown {
+                keycode: 4,
+                character: Some('a'),
+                modifiers: Default::default(),
+            };
+            conn.send(&motion).await.unwrap();
+            conn.send(&key).await.unwrap();
+
+            assert_eq!(server.await.unwrap(), vec![motion.type_id(), key.type_id()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_without_common_version_is_rejected() {
+        let (mut client, mut server) = loopback_pair().await;
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let future = VersionRange { min: PROTOCOL_VERSION + 1, max: PROTOCOL_VERSION + 3 };
+        client
+            .send(&Message::Hello {
+                versions: future,
+                features: Features::SUPPORTED,
+                screen_info: info.clone(),
+                resume_token: None,
+                motion_port: None,
+            })
+            .await
+            .unwrap();
+
+        let result = server.handshake_server(&info).await;
+        assert!(matches!(
+            result,
+            Err(ConnectionError::VersionMismatch { remote, .. }) if remote == future
+        ));
+
+        match client.recv().await.unwrap().unwrap().message {
+            Message::HelloAck { accepted, protocol_version, .. } => {
+                assert!(!accepted);
+                assert_eq!(protocol_version, PROTOCOL_VERSION);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        assert!(matches!(
+            client.recv().await.unwrap().unwrap().message,
+            Message::Error { code: error_codes::PROTOCOL_MISMATCH, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_batch_is_received_in_order() {
+        let (mut sender, mut receiver) = loopback_pair().await;
+        let (handle, mut outbox) = ConnectionHandle::new();
+
+        for keycode in 0..100 {
+            handle.send(Message::KeyUp { keycode, modifiers: Default::default() }).unwrap();
+        }
+        let batch = outbox.recv_batch().await.unwrap();
+        assert_eq!(batch.len(), 100);
+        sender.send_batch(&batch).await.unwrap();
+        assert_eq!(sender.stats().messages_sent, 100);
+
+        for expected in 0..100 {
+            let frame = receiver.recv().await.unwrap().unwrap();
+            assert_eq!(frame.sequence, expected);
+            assert!(matches!(frame.message, Message::KeyUp { keycode, .. } if keycode == expected));
+        }
+        assert_eq!(receiver.stats().bytes_received, sender.stats().bytes_sent);
+    }
+
+    #[tokio::test]
+    async fn test_batch_sends_frames_before_encode_error() {
+        let (mut sender, mut receiver) = loopback_pair().await;
+        let oversized = Message::ClipboardData {
+            mime_type: "text/plain".into(),
+            data: vec![0; crate::protocol::MAX_MESSAGE_SIZE + 1],
+        };
+        let batch = [
+            Message::KeyUp { keycode: 0, modifiers: Default::default() },
+            oversized,
+            Message::KeyUp { keycode: 1, modifiers: Default::default() },
+        ];
+
+        assert!(sender.send_batch(&batch).await.is_err());
+        assert_eq!(sender.stats().messages_sent, 1);
+
+        // The frame encoded before the failure arrives, and the sequence
+        // carries on from it
+        let frame = receiver.recv().await.unwrap().unwrap();
+        assert_eq!(frame.sequence, 0);
+        assert!(matches!(frame.message, Message::KeyUp { keycode: 0, .. }));
+        sender.send(&batch[2]).await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap().unwrap().sequence, 1);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_write_error_reaches_sender() {
+        struct Reset;
+
+        #[async_trait::async_trait]
+        impl Multiplex for Reset {
+            async fn open(&self) -> io::Result<SendLane> {
+                Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+            }
+
+            async fn accept(&self) -> io::Result<RecvLane> {
+                std::future::pending().await
+            }
+        }
+
+        // Frames queued before the writer fails are accepted; the next
+        // send after it reports why
+        let mut bulk = BulkLane::open(Arc::new(Reset));
+        let error = loop {
+            if let Err(e) = bulk.send(BytesMut::from(&b"data"[..])).await {
+                break e;
+            }
+            tokio::task::yield_now().await;
+        };
+        assert!(matches!(error, ConnectionError::Io(e) if e.kind() == io::ErrorKind::ConnectionReset));
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_motion_falls_back_to_stream() {
+        let (mut client, mut server) = loopback_pair().await;
+        client.offer_motion_channel().await.unwrap();
+        server.allow_motion_channel();
+        let server_info = ScreenInfo::new("server".into(), "Server".into(), 1920, 1080);
+        let client_info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let (accepted, connected) = tokio::join!(
+            server.handshake_server(&server_info),
+            client.handshake_client(&client_info)
+        );
+        accepted.unwrap();
+        connected.unwrap();
+        assert!(client.has_motion_channel());
+
+        // Delivered motion is acked with the next frame the server sends
+        // and never surfaces as a message of its own
+        client.send(&Message::MouseMoveRelative { dx: 1, dy: 1 }).await.unwrap();
+        let frame = server.recv().await.unwrap().unwrap();
+        assert!(matches!(frame.message, Message::MouseMoveRelative { dx: 1, dy: 1 }));
+        server.send(&Message::Heartbeat { timestamp: 1 }).await.unwrap();
+        let frame = client.recv().await.unwrap().unwrap();
+        assert!(matches!(frame.message, Message::Heartbeat { timestamp: 1 }));
+
+        // Then datagrams stop arriving
+        server.motion = MotionSetup::Off;
+        client.send(&Message::MouseMoveRelative { dx: 2, dy: 2 }).await.unwrap();
+        tokio::time::pause();
+        tokio::time::advance(motion::ACK_TIMEOUT).await;
+        client.send(&Message::MouseMoveRelative { dx: 3, dy: 3 }).await.unwrap();
+        assert!(!client.has_motion_channel());
+        assert!(!client.features().contains(Features::UDP_MOTION));
+
+        let frame = server.recv().await.unwrap().unwrap();
+        assert!(matches!(frame.message, Message::MouseMoveRelative { dx: 3, dy: 3 }));
+    }
+
+    /// Connected pair of connections over loopback TCP, without a handshake
+    async fn loopback_pair() -> (Connection, Connection) {
+        use tokio::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (client, (server, peer)) = (client.unwrap(), accepted.unwrap());
+        client.set_nodelay(true).unwrap();
+        server.set_nodelay(true).unwrap();
+        (Connection::new(client, addr), Connection::new(server, peer))
+    }
+
+    // Benchmarks, run with
+    // `cargo test --release bench_ -- --ignored --nocapture --test-threads=1`
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_messages_per_second() {
+        const COUNT: u64 = 200_000;
+        let (mut sender, mut receiver) = loopback_pair().await;
+        let (handle, mut outbox) = ConnectionHandle::new();
+
+        let reader = tokio::spawn(async move {
+            for _ in 0..COUNT {
+                receiver.recv().await.unwrap().unwrap();
+            }
+        });
+
+        let start = Instant::now();
+        let writer = tokio::spawn(async move {
+            let mut sent = 0;
+            while sent < COUNT {
+                let batch = outbox.recv_batch().await.unwrap();
+                sender.send_batch(&batch).await.unwrap();
+                sent += batch.len() as u64;
+            }
+        });
+        for i in 0..COUNT {
+            let keycode = i as u32;
+            let modifiers = Default::default();
+            let key = if i % 2 == 0 {
+                Message::KeyDown { keycode, character: None, modifiers }
+            } else {
+                Message::KeyUp { keycode, modifiers }
+            };
+            handle.send(key).unwrap();
+            // Stay just under the queue limit, which a real capture loop
+            // never comes near
+            if i % 64 == 0 {
+                tokio::task::yield_now().await;
+                while handle.queue.depth() > MAX_SEND_QUEUE_DEPTH - 128 {
+                    tokio::task::yield_now().await;
+                }
+            }
+        }
+        writer.await.unwrap();
+        reader.await.unwrap();
+
+        let elapsed = start.elapsed();
+        println!(
+            "{} messages in {:?}: {:.0} messages/s",
+            COUNT,
+            elapsed,
+            COUNT as f64 / elapsed.as_secs_f64()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_event_latency() {
+        const COUNT: usize = 20_000;
+        let (mut sender, mut receiver) = loopback_pair().await;
+
+        tokio::spawn(async move {
+            while let Ok(Some(frame)) = receiver.recv().await {
+                if let Message::Heartbeat { timestamp } = frame.message {
+                    receiver.send(&Message::HeartbeatAck { timestamp }).await.unwrap();
+                }
+            }
+        });
+
+        let mut samples = Vec::with_capacity(COUNT);
+        for _ in 0..COUNT {
+            let start = Instant::now();
+            sender.ping().await.unwrap();
+            samples.push(start.elapsed());
+        }
+        samples.sort();
+
+        let mean = samples.iter().sum::<Duration>() / COUNT as u32;
+        println!(
+            "round trip over {} events: mean {:?}, p50 {:?}, p99 {:?}",
+            COUNT,
+            mean,
+            samples[COUNT / 2],
+            samples[COUNT * 99 / 100]
+        );
     }
 }
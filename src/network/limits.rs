@@ -0,0 +1,246 @@
+//! Limits on incoming connections
+//!
+//! Besides the handshake deadline and the cap on handshakes in progress,
+//! the server bounds how many clients it holds at once and how often a
+//! single address may connect.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::connection::Rejection;
+use crate::protocol::error_codes;
+
+/// Addresses tracked before fully refilled buckets are swept out
+const RATE_LIMIT_SWEEP: usize = 1024;
+
+/// Refused peers told why at once; past this, refused connections are
+/// dropped without a reply
+const MAX_PENDING_REFUSALS: usize = 64;
+
+/// Per-address connection rate limit, as a token bucket holding a minute's
+/// allowance
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Allow `per_minute` connections from each address (0 is unlimited)
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a connection from `ip`, returning false if it is over its allowance
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.per_minute == 0 {
+            return true;
+        }
+
+        let capacity = self.per_minute as f64;
+        let refill = |bucket: &mut Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * capacity / 60.0).min(capacity);
+            bucket.updated = now;
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= RATE_LIMIT_SWEEP {
+            buckets.retain(|_, bucket| {
+                refill(bucket);
+                bucket.tokens < capacity
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        refill(bucket);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Checks a new connection passes before its handshake starts: the
+/// per-address rate limit and the cap on handshakes in progress. Shared by
+/// the server's accept loops and by listeners that handshake in the
+/// background (QUIC).
+#[derive(Debug, Clone)]
+pub struct HandshakeLimits {
+    rate_limiter: Arc<RateLimiter>,
+    handshakes: Arc<Semaphore>,
+    refusals: Arc<Semaphore>,
+}
+
+impl HandshakeLimits {
+    /// Allow `per_minute` connections from each address and `max_pending`
+    /// handshakes at once (0 is unlimited for either)
+    pub fn new(per_minute: u32, max_pending: usize) -> Self {
+        Self {
+            rate_limiter: Arc::new(RateLimiter::new(per_minute)),
+            handshakes: Arc::new(Semaphore::new(match max_pending {
+                0 => Semaphore::MAX_PERMITS,
+                max => max,
+            })),
+            refusals: Arc::new(Semaphore::new(MAX_PENDING_REFUSALS)),
+        }
+    }
+
+    /// Admit a connection from `ip`, returning the permit it holds until
+    /// its handshake is done. Peers without an address are not rate limited.
+    pub fn admit(&self, ip: Option<IpAddr>) -> Result<OwnedSemaphorePermit, Rejection> {
+        if ip.is_some_and(|ip| !self.rate_limiter.allow(ip)) {
+            return Err(Rejection::new(
+                error_codes::RATE_LIMITED,
+                "Too many connections from this address",
+            ));
+        }
+        self.handshakes.clone().try_acquire_owned().map_err(|_| {
+            Rejection::new(error_codes::SERVER_BUSY, "Too many connections in progress")
+        })
+    }
+
+    /// A permit to tell a refused peer why, or None if enough refusals are
+    /// already under way and the connection should just be dropped
+    pub fn refusal(&self) -> Option<OwnedSemaphorePermit> {
+        self.refusals.clone().try_acquire_owned().ok()
+    }
+
+    /// Wait for a handshake permit, for connections the server opened itself
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.handshakes.clone().acquire_owned().await.ok()
+    }
+}
+
+/// Client slots in use, counting both connected clients and sessions held
+/// for resumption
+#[derive(Debug, Clone)]
+pub struct ClientSlots {
+    used: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl ClientSlots {
+    /// Allow up to `max` clients (0 is unlimited)
+    pub fn new(max: usize) -> Self {
+        Self {
+            used: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Claim a slot, returning false if the server is full
+    pub fn try_take(&self) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                (self.max == 0 || used < self.max).then_some(used + 1)
+            })
+            .is_ok()
+    }
+
+    /// Give back a slot claimed with `try_take`
+    pub fn release(&self) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| used.checked_sub(1));
+    }
+
+    /// Configured maximum (0 is unlimited)
+    pub fn max(&self) -> usize {
+        self.max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limiter_refills() {
+        let limiter = RateLimiter::new(2);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.allow_at(ip, start));
+        assert!(limiter.allow_at(ip, start));
+        assert!(!limiter.allow_at(ip, start));
+        assert!(limiter.allow_at(other, start));
+
+        // Two a minute refills one every 30 seconds
+        assert!(!limiter.allow_at(ip, start + Duration::from_secs(20)));
+        assert!(limiter.allow_at(ip, start + Duration::from_secs(31)));
+        assert!(!limiter.allow_at(ip, start + Duration::from_secs(31)));
+
+        let unlimited = RateLimiter::new(0);
+        assert!((0..100).all(|_| unlimited.allow_at(ip, start)));
+    }
+
+    #[test]
+    fn test_client_slots() {
+        let slots = ClientSlots::new(2);
+        assert!(slots.try_take());
+        assert!(slots.clone().try_take());
+        assert!(!slots.try_take());
+
+        slots.release();
+        assert!(slots.try_take());
+
+        // Releasing more than was taken doesn't free extra slots
+        slots.release();
+        slots.release();
+        slots.release();
+        assert!(slots.try_take());
+        assert!(slots.try_take());
+        assert!(!slots.try_take());
+
+        let unlimited = ClientSlots::new(0);
+        assert!((0..100).all(|_| unlimited.try_take()));
+    }
+
+    #[test]
+    fn test_handshake_limits() {
+        let limits = HandshakeLimits::new(2, 1);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let permit = limits.admit(Some(ip)).unwrap();
+        let busy = limits.admit(None).unwrap_err();
+        assert_eq!(busy.code, error_codes::SERVER_BUSY);
+        drop(permit);
+
+        assert!(limits.admit(Some(ip)).is_ok());
+        let limited = limits.admit(Some(ip)).unwrap_err();
+        assert_eq!(limited.code, error_codes::RATE_LIMITED);
+        assert!(limits.admit(None).is_ok());
+
+        let refusals: Vec<_> = (0..MAX_PENDING_REFUSALS).map_while(|_| limits.refusal()).collect();
+        assert_eq!(refusals.len(), MAX_PENDING_REFUSALS);
+        assert!(limits.refusal().is_none());
+        drop(refusals);
+        assert!(limits.refusal().is_some());
+    }
+}
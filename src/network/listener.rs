@@ -0,0 +1,162 @@
+//! Listening sockets for the server
+//!
+//! Bind addresses are written as strings in the `network` config section:
+//! - `192.168.1.5` or `192.168.1.5:24801` listens on one interface
+//! - `0.0.0.0` listens on every IPv4 interface, `[::]` on every IPv6 one
+//! - an empty list listens on `[::]` dual-stack, or `0.0.0.0` on hosts
+//!   without IPv6
+//!
+//! `[::]` also accepts IPv4 clients unless `0.0.0.0` is listed on the same
+//! port. QUIC listens on UDP sockets mirroring the TCP ones.
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::task::Poll;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Parse a bind address, using `default_port` when it has none
+pub fn parse_bind_address(addr: &str, default_port: u16) -> Result<SocketAddr, String> {
+    let addr = addr.trim();
+    if let Ok(sock) = addr.parse::<SocketAddr>() {
+        return Ok(sock);
+    }
+
+    let ip = addr
+        .strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(addr);
+    ip.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, default_port))
+        .map_err(|_| format!("invalid bind address '{}'", addr))
+}
+
+/// Open one listener per configured address (see the module docs)
+pub fn bind_listeners(addresses: &[String], port: u16) -> io::Result<Vec<TcpListener>> {
+    if addresses.is_empty() {
+        let any_v6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+        return match bind(any_v6, false) {
+            Ok(listener) => Ok(vec![listener]),
+            Err(e) => {
+                tracing::debug!("Dual-stack bind failed ({}), listening on IPv4 only", e);
+                Ok(vec![bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), false)?])
+            }
+        };
+    }
+
+    let addrs = addresses
+        .iter()
+        .map(|a| parse_bind_address(a, port))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let v4_any_ports: Vec<u16> = addrs
+        .iter()
+        .filter(|a| a.ip() == IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        .map(|a| a.port())
+        .collect();
+
+    addrs
+        .iter()
+        .map(|&addr| {
+            let only_v6 = !addr.ip().is_unspecified() || v4_any_ports.contains(&addr.port());
+            bind(addr, only_v6)
+        })
+        .collect()
+}
+
+/// Bind a listening socket, choosing whether an IPv6 socket also takes IPv4
+fn bind(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let with_addr = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", addr, e));
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        .map_err(with_addr)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6).map_err(with_addr)?;
+    }
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true).map_err(with_addr)?;
+    socket.set_nonblocking(true).map_err(with_addr)?;
+    socket.bind(&addr.into()).map_err(with_addr)?;
+    socket.listen(1024).map_err(with_addr)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Bind a UDP socket on the same address, port and IPv6 mode as a TCP listener
+pub fn bind_udp_like(listener: &TcpListener) -> io::Result<std::net::UdpSocket> {
+    let addr = listener.local_addr()?;
+    let with_addr = |e: io::Error| io::Error::new(e.kind(), format!("{} (UDP): {}", addr, e));
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))
+        .map_err(with_addr)?;
+    if addr.is_ipv6() {
+        let only_v6 = SockRef::from(listener).only_v6().map_err(with_addr)?;
+        socket.set_only_v6(only_v6).map_err(with_addr)?;
+    }
+    socket.set_nonblocking(true).map_err(with_addr)?;
+    socket.bind(&addr.into()).map_err(with_addr)?;
+
+    Ok(socket.into())
+}
+
+/// Accept the next connection on whichever listener gets one first
+pub async fn accept_any(listeners: &[TcpListener]) -> io::Result<(TcpStream, SocketAddr)> {
+    std::future::poll_fn(|cx| {
+        for listener in listeners {
+            if let Poll::Ready(result) = listener.poll_accept(cx) {
+                return Poll::Ready(result);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+    .map(|(stream, addr)| (stream, canonical_peer(addr)))
+}
+
+/// Report IPv4 clients of a dual-stack socket by their IPv4 address
+pub fn canonical_peer(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bind_address() {
+        assert_eq!(
+            parse_bind_address("192.168.1.5", 24800),
+            Ok("192.168.1.5:24800".parse().unwrap())
+        );
+        assert_eq!(
+            parse_bind_address("10.0.0.1:9000", 24800),
+            Ok("10.0.0.1:9000".parse().unwrap())
+        );
+        assert_eq!(parse_bind_address("[::]", 24800), Ok("[::]:24800".parse().unwrap()));
+        assert_eq!(parse_bind_address("::1", 24800), Ok("[::1]:24800".parse().unwrap()));
+        assert!(parse_bind_address("eth0", 24800).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_default_listener_accepts_ipv4() {
+        let listeners = bind_listeners(&[], 0).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+
+        let connect = TcpStream::connect(("127.0.0.1", addr.port()));
+        let (accepted, connected) = tokio::join!(accept_any(&listeners), connect);
+        let (_, peer) = accepted.unwrap();
+        assert_eq!(peer, connected.unwrap().local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_multiple_listeners() {
+        let addresses = vec!["127.0.0.1:0".to_string(), "127.0.0.1:0".to_string()];
+        let listeners = bind_listeners(&addresses, 0).unwrap();
+        assert_eq!(listeners.len(), 2);
+
+        let second = listeners[1].local_addr().unwrap();
+        let (accepted, _) = tokio::join!(accept_any(&listeners), TcpStream::connect(second));
+        assert!(accepted.unwrap().1.ip().is_loopback());
+    }
+}
@@ -1,4 +1,5 @@
-//! Network module - Handles TCP/TLS communication between hosts
+//! Network module - Handles communication between hosts over TCP, TLS or
+//! any other `Transport`
 //!
 //! Provides:
 //! - Server for accepting incoming connections
@@ -8,10 +9,27 @@
 mod server;
 mod client;
 mod connection;
+mod listener;
+mod limits;
+mod access;
+mod motion;
+mod pairing;
+mod quic;
+mod relay;
+mod tls;
+mod transport;
+mod trust;
+mod websocket;
+#[cfg(unix)]
+mod unix;
 
 pub use server::*;
 pub use client::*;
 pub use connection::*;
+pub use listener::*;
+pub use pairing::*;
+pub use relay::*;
+pub use trust::*;
 
 use std::net::SocketAddr;
 
@@ -20,16 +38,74 @@ use std::net::SocketAddr;
 pub struct NetworkConfig {
     /// Port to listen on or connect to
     pub port: u16,
+    /// Addresses the server listens on, with optional ports (for server).
+    /// Empty listens on all interfaces, IPv4 and IPv6.
+    pub bind_addresses: Vec<String>,
+    /// Unix domain socket path to listen on or connect to instead of TCP
+    pub socket_path: Option<String>,
+    /// Also accept QUIC on the TCP addresses and ports (server), or connect
+    /// over QUIC (client). Needs TLS.
+    pub quic: bool,
+    /// Send pointer motion over a UDP side channel when the peer agrees
+    pub udp_motion: bool,
+    /// Accept WebSocket upgrades on the native port (server), or connect
+    /// over WebSocket (client)
+    pub websocket: bool,
+    /// HTTP proxy that WebSocket connections tunnel through with `CONNECT`,
+    /// as `host:port` or `http://host:port`; unset uses `HTTPS_PROXY` from
+    /// the environment (for client)
+    pub proxy: Option<String>,
+    /// Clients the server connects out to, for clients that can't be
+    /// reached on the server's port and listen instead (for server)
+    pub reverse_clients: Vec<String>,
+    /// Relay to register with (server) or reach the server through
+    /// (client), as `host` or `host:port`
+    pub relay: Option<String>,
+    /// Secret the server and its clients present to the relay
+    pub relay_token: Option<String>,
     /// Whether to use TLS encryption
     pub use_tls: bool,
-    /// Path to TLS certificate (for server)
+    /// Refuse plaintext peers
+    pub require_tls: bool,
+    /// Path to this host's TLS certificate (server certificate, or client identity)
     pub cert_path: Option<String>,
-    /// Path to TLS private key (for server)
+    /// Path to this host's TLS private key
     pub key_path: Option<String>,
+    /// Path to the CA certificate used to verify the server (for client).
+    /// When unset, the server's fingerprint is pinned on first use instead.
+    pub ca_path: Option<String>,
+    /// Path to the known-hosts file holding pinned fingerprints (for client)
+    pub known_hosts_path: Option<String>,
+    /// Only accept clients that completed pairing (for server)
+    pub require_pairing: bool,
+    /// Path to the file holding paired client identities (for server)
+    pub paired_hosts_path: Option<String>,
+    /// Rules a client must match to be admitted; empty allows all (for server)
+    pub allowed_hosts: Vec<String>,
+    /// Rules that turn a client away regardless of `allowed_hosts` (for server)
+    pub denied_hosts: Vec<String>,
     /// Connection timeout in milliseconds
     pub connect_timeout_ms: u64,
     /// Heartbeat interval in milliseconds
     pub heartbeat_interval_ms: u64,
+    /// Drop a peer that has been silent for this many heartbeat intervals
+    /// (0 never drops)
+    pub missed_heartbeats: u32,
+    /// How long the server holds a dropped client's session so it can
+    /// resume it (0 disables resumption)
+    pub resume_grace_ms: u64,
+    /// Drop a client that has not finished its handshake, TLS included,
+    /// within this many milliseconds (for server)
+    pub handshake_timeout_ms: u64,
+    /// Most clients held at once, counting suspended sessions; 0 is
+    /// unlimited (for server)
+    pub max_clients: usize,
+    /// Most handshakes in progress at once; 0 is unlimited (for server)
+    pub max_pending_handshakes: usize,
+    /// Connections one address may open per minute; 0 is unlimited (for server)
+    pub max_connections_per_minute: u32,
+    /// What to do with a client whose host ID is already connected (for server)
+    pub duplicate_hosts: DuplicateHostPolicy,
     /// Maximum message size
     pub max_message_size: usize,
 }
@@ -38,11 +114,34 @@ impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
             port: crate::protocol::DEFAULT_PORT,
+            bind_addresses: Vec::new(),
+            socket_path: None,
+            quic: false,
+            udp_motion: false,
+            websocket: false,
+            proxy: None,
+            reverse_clients: Vec::new(),
+            relay: None,
+            relay_token: None,
             use_tls: true,
+            require_tls: false,
             cert_path: None,
             key_path: None,
+            ca_path: None,
+            known_hosts_path: None,
+            require_pairing: false,
+            paired_hosts_path: None,
+            allowed_hosts: Vec::new(),
+            denied_hosts: Vec::new(),
             connect_timeout_ms: 5000,
             heartbeat_interval_ms: 1000,
+            missed_heartbeats: 3,
+            resume_grace_ms: 10_000,
+            handshake_timeout_ms: 10_000,
+            max_clients: 16,
+            max_pending_handshakes: 32,
+            max_connections_per_minute: 60,
+            duplicate_hosts: DuplicateHostPolicy::Replace,
             max_message_size: 10 * 1024 * 1024, // 10 MB
         }
     }
@@ -63,10 +162,33 @@ impl NetworkConfig {
         self
     }
 
+    pub fn with_ca(mut self, ca_path: String) -> Self {
+        self.use_tls = true;
+        self.ca_path = Some(ca_path);
+        self
+    }
+
     pub fn without_tls(mut self) -> Self {
         self.use_tls = false;
         self
     }
+
+    pub fn require_tls(mut self, require: bool) -> Self {
+        self.require_tls = require;
+        self
+    }
+}
+
+/// Resolve a `host`, `host:port` or IP address (IPv6 in brackets when
+/// it has a port), using `default_port` when none is given
+pub async fn resolve_target(target: &str, default_port: u16) -> std::io::Result<SocketAddr> {
+    if let Ok(addr) = listener::parse_bind_address(target, default_port) {
+        return Ok(addr);
+    }
+    match target.rsplit_once(':').and_then(|(host, port)| Some((host, port.parse().ok()?))) {
+        Some((host, port)) => resolve_host(host, port).await,
+        None => resolve_host(target, default_port).await,
+    }
 }
 
 /// Resolve a hostname to a socket address
@@ -0,0 +1,272 @@
+//! UDP side channel for pointer motion
+//!
+//! Pointer motion is the most latency-sensitive traffic and the least
+//! harmful to lose: a newer position supersedes an older one. When both
+//! ends agree in Hello/HelloAck, `MouseMoveRelative` and `MouseMoveAbsolute`
+//! go out as single datagrams instead of queueing on the reliable stream.
+//!
+//! Each datagram is an ordinary encoded `Frame` followed by a truncated
+//! HMAC-SHA256 tag under the key from HelloAck. Datagrams with a bad tag
+//! are ignored, and so are stale ones, whose frame sequence number is not
+//! newer than the last motion received.
+//!
+//! The receiver confirms that datagrams arrive with a `MotionAck` on the
+//! reliable stream, for the first one and then at most every
+//! `ACK_INTERVAL`. A sender that hears nothing back for `ACK_TIMEOUT`,
+//! say behind a firewall that drops UDP, gives up on the channel and
+//! sends motion on the stream instead.
+
+use bytes::BytesMut;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use crate::protocol::{Decoder, Frame};
+
+/// Length of the key handed out in HelloAck
+pub const MOTION_KEY_LEN: usize = 32;
+
+/// Bytes of the HMAC tag kept on each datagram
+const TAG_LEN: usize = 16;
+
+/// Largest datagram accepted; motion frames are far smaller
+const MAX_DATAGRAM: usize = 512;
+
+/// Least time between acks while motion keeps arriving
+const ACK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long sent motion may go unacknowledged before the channel is given
+/// up on. Acks go out with the next frame the receiver sends, a heartbeat
+/// at the latest, so this spans several default heartbeat intervals.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One end of a pointer motion channel
+#[derive(Debug)]
+pub struct MotionChannel {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    key: Vec<u8>,
+    /// Protocol version the session uses
+    version: u32,
+    /// Sequence number of the newest motion received
+    last_sequence: Option<u32>,
+    /// Datagrams ignored as stale or forged
+    dropped: u64,
+    /// When the oldest motion sent since the peer's last ack went out
+    unacked_since: Option<Instant>,
+    /// Motion has arrived that the peer hasn't been told about
+    ack_due: bool,
+    /// When we last told the peer its motion arrived
+    last_ack: Option<Instant>,
+}
+
+impl MotionChannel {
+    /// Bind a socket for talking to `peer`
+    pub async fn bind_for(peer: SocketAddr) -> io::Result<UdpSocket> {
+        let local = match peer {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        UdpSocket::bind(local).await
+    }
+
+    /// A fresh random key for a new channel
+    pub fn generate_key() -> Vec<u8> {
+        let mut key = vec![0u8; MOTION_KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut key);
+        key
+    }
+
+    /// A channel to `peer` over `socket`, authenticated with `key`, for a
+    /// session speaking protocol `version`
+    pub fn new(socket: UdpSocket, peer: SocketAddr, key: Vec<u8>, version: u32) -> Self {
+        Self {
+            socket,
+            peer,
+            key,
+            version,
+            last_sequence: None,
+            dropped: 0,
+            unacked_since: None,
+            ack_due: false,
+            last_ack: None,
+        }
+    }
+
+    /// Datagrams ignored so far as stale or forged
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Send one encoded frame
+    pub async fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let mut datagram = Vec::with_capacity(frame.len() + TAG_LEN);
+        datagram.extend_from_slice(frame);
+        datagram.extend_from_slice(&self.tag(frame));
+        self.unacked_since.get_or_insert_with(Instant::now);
+        self.socket.send_to(&datagram, self.peer).await?;
+        Ok(())
+    }
+
+    /// The peer acknowledged our motion
+    pub fn acknowledged(&mut self) {
+        self.unacked_since = None;
+    }
+
+    /// Whether motion sent has gone unacknowledged for `ACK_TIMEOUT`
+    pub fn is_unacknowledged(&self) -> bool {
+        self.unacked_since.is_some_and(|since| since.elapsed() >= ACK_TIMEOUT)
+    }
+
+    /// Whether to send the peer a `MotionAck` now; counts it as sent
+    pub fn take_ack(&mut self) -> bool {
+        if !self.ack_due {
+            return false;
+        }
+        self.ack_due = false;
+        self.last_ack = Some(Instant::now());
+        true
+    }
+
+    /// Wait for the next fresh, authentic motion frame. Cancel safe.
+    pub async fn recv(&mut self) -> io::Result<Frame> {
+        let mut buf = [0u8; MAX_DATAGRAM];
+        loop {
+            let (n, _) = self.socket.recv_from(&mut buf).await?;
+            match self.accept(&buf[..n]) {
+                Some(frame) => return Ok(frame),
+                None => self.dropped += 1,
+            }
+        }
+    }
+
+    /// Check and decode one datagram
+    fn accept(&mut self, datagram: &[u8]) -> Option<Frame> {
+        let split = datagram.len().checked_sub(TAG_LEN)?;
+        let (frame, tag) = datagram.split_at(split);
+        self.mac(frame).verify_truncated_left(tag).ok()?;
+
+        let frame = Decoder::for_version(self.version)
+            .decode(&mut BytesMut::from(frame))
+            .ok()
+            .flatten()
+            .filter(|f| f.message.is_pointer_motion())?;
+
+        // Sequence numbers wrap, so newer means less than half the space ahead
+        if let Some(last) = self.last_sequence {
+            if (frame.sequence.wrapping_sub(last) as i32) <= 0 {
+                return None;
+            }
+        }
+        self.last_sequence = Some(frame.sequence);
+        if self.last_ack.is_none_or(|at| at.elapsed() >= ACK_INTERVAL) {
+            self.ack_due = true;
+        }
+        Some(frame)
+    }
+
+    fn mac(&self, frame: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(frame);
+        mac
+    }
+
+    fn tag(&self, frame: &[u8]) -> [u8; TAG_LEN] {
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&self.mac(frame).finalize().into_bytes()[..TAG_LEN]);
+        tag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Encoder, Message, PROTOCOL_VERSION};
+
+    fn encode(encoder: &mut Encoder, message: &Message) -> BytesMut {
+        let mut buf = BytesMut::new();
+        encoder.encode(message, &mut buf).unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_stale_and_forged_datagrams_are_dropped() {
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let a = MotionChannel::bind_for(localhost).await.unwrap();
+        let b = MotionChannel::bind_for(localhost).await.unwrap();
+        let a_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, a.local_addr().unwrap().port()));
+        let b_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, b.local_addr().unwrap().port()));
+
+        let key = MotionChannel::generate_key();
+        let mut sender = MotionChannel::new(a, b_addr, key.clone(), PROTOCOL_VERSION);
+        let mut receiver = MotionChannel::new(b, a_addr, key, PROTOCOL_VERSION);
+
+        let mut encoder = Encoder::new();
+        let old = encode(&mut encoder, &Message::MouseMoveRelative { dx: 1, dy: 1 });
+        let new = encode(&mut encoder, &Message::MouseMoveRelative { dx: 2, dy: 2 });
+        let key_down = encode(&mut encoder, &Message::KeyDown {
+            keycode: 4,
+            character: None,
+            modifiers: Default::default(),
+        });
+        let newest = encode(&mut encoder, &Message::MouseMoveAbsolute { x: 5, y: 6 });
+
+        sender.send(&new).await.unwrap();
+        sender.send(&old).await.unwrap();
+        sender.send(&key_down).await.unwrap();
+        let mut forger = MotionChannel::new(
+            MotionChannel::bind_for(localhost).await.unwrap(),
+            b_addr,
+            MotionChannel::generate_key(),
+            PROTOCOL_VERSION,
+        );
+        forger.send(&newest).await.unwrap();
+        sender.send(&newest).await.unwrap();
+
+        let first = receiver.recv().await.unwrap();
+        assert!(matches!(first.message, Message::MouseMoveRelative { dx: 2, dy: 2 }));
+        let second = receiver.recv().await.unwrap();
+        assert!(matches!(second.message, Message::MouseMoveAbsolute { x: 5, y: 6 }));
+        assert_eq!(receiver.dropped(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_acks_are_paced() {
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let a = MotionChannel::bind_for(localhost).await.unwrap();
+        let b = MotionChannel::bind_for(localhost).await.unwrap();
+        let a_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, a.local_addr().unwrap().port()));
+        let b_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, b.local_addr().unwrap().port()));
+
+        let key = MotionChannel::generate_key();
+        let mut sender = MotionChannel::new(a, b_addr, key.clone(), PROTOCOL_VERSION);
+        let mut receiver = MotionChannel::new(b, a_addr, key, PROTOCOL_VERSION);
+        let mut encoder = Encoder::new();
+
+        assert!(!receiver.take_ack());
+        for dx in 1..=3 {
+            sender.send(&encode(&mut encoder, &Message::MouseMoveRelative { dx, dy: 0 })).await.unwrap();
+            receiver.recv().await.unwrap();
+        }
+        // One ack for the burst, then none until the interval passes
+        assert!(receiver.take_ack());
+        assert!(!receiver.take_ack());
+        tokio::time::pause();
+        tokio::time::advance(ACK_INTERVAL).await;
+        sender.send(&encode(&mut encoder, &Message::MouseMoveRelative { dx: 4, dy: 0 })).await.unwrap();
+        receiver.recv().await.unwrap();
+        assert!(receiver.take_ack());
+
+        // Unanswered motion gives the channel up once the timeout passes
+        assert!(!sender.is_unacknowledged());
+        tokio::time::advance(ACK_TIMEOUT).await;
+        assert!(sender.is_unacknowledged());
+        sender.acknowledged();
+        assert!(!sender.is_unacknowledged());
+    }
+}
@@ -0,0 +1,440 @@
+//! Short-code pairing between a new client and the server
+//!
+//! The server shows a 6-digit code which the user types on the client.
+//! Both sides run SPAKE2 with the code as password, so an observer (or a
+//! man in the middle) learns nothing useful about the code and gets a
+//! single guess per attempt. The two TLS certificate fingerprints are the
+//! SPAKE2 identities, so the key is bound to both; once the key
+//! confirmations check out each side stores the other's fingerprint as a
+//! trusted identity.
+
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::Rng;
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+
+use super::connection::{Connection, ConnectionError, ConnectionResult};
+use super::tls;
+use super::trust::{self, HostTrust, KnownHosts};
+use super::NetworkConfig;
+use crate::protocol::{error_codes, Message, ScreenInfo};
+
+/// File name of the paired client identities inside the config directory
+pub const PAIRED_HOSTS_FILE: &str = "paired_hosts";
+
+/// Number of digits in a pairing code
+pub const PAIRING_CODE_DIGITS: usize = 6;
+
+/// How long each pairing step may take before giving up
+const PAIRING_STEP_TIMEOUT: Duration = Duration::from_secs(120);
+
+const DOMAIN: &[u8] = b"corenet-pair-v1";
+
+/// A host that completed pairing
+#[derive(Debug, Clone)]
+pub struct PairedPeer {
+    /// Host identifier (the client's host_id, or the server's known-hosts key)
+    pub host_id: String,
+    /// Human-readable name
+    pub host_name: String,
+    /// Fingerprint of the peer's certificate
+    pub fingerprint: String,
+}
+
+/// The paired-hosts file as the server checks clients against it: read
+/// once, then again only after it changes on disk
+#[derive(Debug)]
+pub struct PairedHosts {
+    path: PathBuf,
+    /// The file's modification time when read, and its entries
+    cached: Mutex<(Option<SystemTime>, KnownHosts)>,
+}
+
+impl PairedHosts {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let modified = modified(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            cached: Mutex::new((modified, KnownHosts::load(path)?)),
+        })
+    }
+
+    /// Check a client's fingerprint against the one it paired with
+    pub fn check(&self, host_id: &str, fingerprint: &str) -> io::Result<HostTrust> {
+        let modified = modified(&self.path)?;
+        let mut cached = self.cached.lock().unwrap();
+        if cached.0 != modified {
+            tracing::debug!("Reloading paired hosts from {}", self.path.display());
+            *cached = (modified, KnownHosts::load(&self.path)?);
+        }
+        Ok(cached.1.check(host_id, fingerprint))
+    }
+
+    /// Record a newly paired client, keeping entries others wrote meanwhile
+    pub fn pin(&self, host_id: &str, fingerprint: &str) -> io::Result<()> {
+        let mut cached = self.cached.lock().unwrap();
+        let mut hosts = KnownHosts::load(&self.path)?;
+        hosts.pin(host_id, fingerprint)?;
+        *cached = (modified(&self.path)?, hosts);
+        Ok(())
+    }
+}
+
+/// When the file at `path` last changed; None if there is none
+fn modified(path: &Path) -> io::Result<Option<SystemTime>> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => metadata.modified().map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Generate a random pairing code
+pub fn generate_code() -> String {
+    let code = OsRng.gen_range(0..10u32.pow(PAIRING_CODE_DIGITS as u32));
+    format!("{:0width$}", code, width = PAIRING_CODE_DIGITS)
+}
+
+/// Strip spaces and dashes from a code as typed by the user
+pub fn normalize_code(input: &str) -> Option<String> {
+    let code: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect();
+    if code.len() == PAIRING_CODE_DIGITS && code.chars().all(|c| c.is_ascii_digit()) {
+        Some(code)
+    } else {
+        None
+    }
+}
+
+/// Which side of the exchange we are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Client,
+    Server,
+}
+
+/// One side of a SPAKE2 exchange
+struct Exchange {
+    state: Spake2<Ed25519Group>,
+    outbound: Vec<u8>,
+}
+
+impl Exchange {
+    /// Start with the code as password and the certificate fingerprints as
+    /// identities, so the key is only shared if both sides see the same
+    /// pair of TLS certificates
+    fn start(role: Role, code: &str, client_fingerprint: &str, server_fingerprint: &str) -> Self {
+        let password = Password::new(code);
+        let client = Identity::new(client_fingerprint.as_bytes());
+        let server = Identity::new(server_fingerprint.as_bytes());
+        let (state, outbound) = match role {
+            Role::Client => Spake2::<Ed25519Group>::start_a(&password, &client, &server),
+            Role::Server => Spake2::<Ed25519Group>::start_b(&password, &client, &server),
+        };
+
+        Self { state, outbound }
+    }
+
+    fn message(&self) -> Vec<u8> {
+        self.outbound.clone()
+    }
+
+    /// Derive the shared key from the peer's message
+    fn finish(self, inbound: &[u8]) -> ConnectionResult<Vec<u8>> {
+        self.state
+            .finish(inbound)
+            .map_err(|e| ConnectionError::PairingFailed(format!("Invalid PAKE message: {}", e)))
+    }
+}
+
+fn confirmation_mac(key: &[u8], role: Role) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(DOMAIN);
+    mac.update(match role {
+        Role::Client => b"client confirm",
+        Role::Server => b"server confirm",
+    });
+    mac
+}
+
+fn confirmation(key: &[u8], role: Role) -> Vec<u8> {
+    confirmation_mac(key, role).finalize().into_bytes().to_vec()
+}
+
+fn verify_confirmation(key: &[u8], role: Role, received: &[u8]) -> bool {
+    confirmation_mac(key, role).verify_slice(received).is_ok()
+}
+
+async fn recv_step(conn: &mut Connection) -> ConnectionResult<Message> {
+    let frame = conn
+        .recv_timeout(PAIRING_STEP_TIMEOUT)
+        .await?
+        .ok_or_else(|| ConnectionError::PairingFailed("Connection closed during pairing".to_string()))?;
+
+    match frame.message {
+        Message::Error { message, .. } => Err(ConnectionError::PairingFailed(message)),
+        message => Ok(message),
+    }
+}
+
+pub(crate) fn local_fingerprint(config: &NetworkConfig) -> ConnectionResult<String> {
+    let cert_path = config.cert_path.as_ref().ok_or_else(|| {
+        ConnectionError::PairingFailed("Pairing requires a host identity".to_string())
+    })?;
+    Ok(trust::certificate_fingerprint(Path::new(cert_path))?)
+}
+
+/// Run the server side of pairing on an established TLS connection, once
+/// the client's PairRequest has been read
+pub async fn answer_pair_request(
+    conn: &mut Connection,
+    screen_info: ScreenInfo,
+    client_msg: &[u8],
+    code: &str,
+    local_fingerprint: &str,
+) -> ConnectionResult<PairedPeer> {
+    let client_fingerprint = conn.peer_fingerprint().ok_or_else(|| {
+        ConnectionError::PairingFailed("Client presented no certificate".to_string())
+    })?;
+
+    let exchange = Exchange::start(Role::Server, code, &client_fingerprint, local_fingerprint);
+    conn.send(&Message::PairResponse { pake: exchange.message() }).await?;
+    let key = exchange.finish(client_msg)?;
+
+    match recv_step(conn).await? {
+        Message::PairConfirm { mac } if verify_confirmation(&key, Role::Client, &mac) => {}
+        _ => {
+            let _ = conn
+                .send(&Message::Error {
+                    code: error_codes::AUTHENTICATION_FAILED,
+                    message: "Pairing code did not match".to_string(),
+                })
+                .await;
+            return Err(ConnectionError::PairingFailed(
+                "Pairing code did not match".to_string(),
+            ));
+        }
+    }
+
+    conn.send(&Message::PairConfirm {
+        mac: confirmation(&key, Role::Server),
+    })
+    .await?;
+
+    Ok(PairedPeer {
+        host_id: screen_info.host_id,
+        host_name: screen_info.host_name,
+        fingerprint: client_fingerprint,
+    })
+}
+
+/// Run the client side of pairing on an established TLS connection
+pub async fn pair_as_client(
+    conn: &mut Connection,
+    code: &str,
+    local_screen: &ScreenInfo,
+    local_fingerprint: &str,
+) -> ConnectionResult<String> {
+    let server_fingerprint = conn.peer_fingerprint().ok_or_else(|| {
+        ConnectionError::PairingFailed("Server presented no certificate".to_string())
+    })?;
+
+    let exchange = Exchange::start(Role::Client, code, local_fingerprint, &server_fingerprint);
+    conn.send(&Message::PairRequest {
+        screen_info: local_screen.clone(),
+        pake: exchange.message(),
+    })
+    .await?;
+
+    let server_msg = match recv_step(conn).await? {
+        Message::PairResponse { pake } => pake,
+        _ => {
+            return Err(ConnectionError::PairingFailed(
+                "Expected PairResponse message".to_string(),
+            ))
+        }
+    };
+    let key = exchange.finish(&server_msg)?;
+
+    conn.send(&Message::PairConfirm {
+        mac: confirmation(&key, Role::Client),
+    })
+    .await?;
+
+    match recv_step(conn).await? {
+        Message::PairConfirm { mac } if verify_confirmation(&key, Role::Server, &mac) => {
+            Ok(server_fingerprint)
+        }
+        _ => Err(ConnectionError::PairingFailed(
+            "Server could not prove it knows the pairing code".to_string(),
+        )),
+    }
+}
+
+/// Pair with the server at `server_addr` using the code shown on the server,
+/// pinning its certificate in the known-hosts file
+pub async fn request_pairing(
+    config: &NetworkConfig,
+    server_addr: SocketAddr,
+    server_name: &str,
+    local_screen: &ScreenInfo,
+    code: &str,
+) -> ConnectionResult<PairedPeer> {
+    let identity = match (&config.cert_path, &config.key_path) {
+        (Some(cert), Some(key)) => (Path::new(cert), Path::new(key)),
+        _ => {
+            return Err(ConnectionError::PairingFailed(
+                "Pairing requires a host identity".to_string(),
+            ))
+        }
+    };
+    let connector = trust::pinning_connector(Some(identity))?;
+    let fingerprint = local_fingerprint(config)?;
+
+    let stream = tokio::time::timeout(
+        Duration::from_millis(config.connect_timeout_ms),
+        TcpStream::connect(server_addr),
+    )
+    .await
+    .map_err(|_| ConnectionError::Timeout)??;
+
+    let stream = tls::connect_tls(&connector, tls::server_name(server_name)?, stream).await?;
+    let mut conn = Connection::new(stream, server_addr);
+    let server_fingerprint = pair_as_client(&mut conn, code, local_screen, &fingerprint).await?;
+
+    let host = trust::known_host_key(server_name, server_addr.port());
+    if let Some(path) = &config.known_hosts_path {
+        KnownHosts::load(Path::new(path))?.pin(&host, &server_fingerprint)?;
+    }
+
+    tracing::info!("Paired with server {} ({})", host, server_fingerprint);
+
+    Ok(PairedPeer {
+        host_name: server_name.to_string(),
+        host_id: host,
+        fingerprint: server_fingerprint,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Identity;
+
+    /// Run both sides of pairing over a local TLS connection
+    async fn pair_locally(
+        server_code: &str,
+        client_code: &str,
+    ) -> (ConnectionResult<PairedPeer>, ConnectionResult<String>, String, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let server_id = Identity::load_or_generate(&dir.path().join("server"), "server").unwrap();
+        let client_id = Identity::load_or_generate(&dir.path().join("client"), "client").unwrap();
+        let server_fp = trust::certificate_fingerprint(&server_id.cert_path).unwrap();
+        let client_fp = trust::certificate_fingerprint(&client_id.cert_path).unwrap();
+
+        let acceptor = tls::server_acceptor(&server_id.cert_path, &server_id.key_path).unwrap();
+        let connector =
+            trust::pinning_connector(Some((&client_id.cert_path, &client_id.key_path))).unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let code = server_code.to_string();
+        let fp = server_fp.clone();
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let stream = tls::accept_tls(&acceptor, stream).await.unwrap();
+            let mut conn = Connection::new(stream, peer);
+            match recv_step(&mut conn).await? {
+                Message::PairRequest { screen_info, pake } => {
+                    answer_pair_request(&mut conn, screen_info, &pake, &code, &fp).await
+                }
+                other => panic!("expected PairRequest, got {:?}", other),
+            }
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream = tls::connect_tls(&connector, tls::server_name("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let mut conn = Connection::new(stream, addr);
+        let info = ScreenInfo::new("client-id".into(), "Client".into(), 1280, 800);
+        let client = pair_as_client(&mut conn, client_code, &info, &client_fp).await;
+
+        (server.await.unwrap(), client, server_fp, client_fp)
+    }
+
+    #[tokio::test]
+    async fn test_pairing_exchanges_identities() {
+        let (server, client, server_fp, client_fp) = pair_locally("042137", "042137").await;
+
+        let peer = server.unwrap();
+        assert_eq!(peer.host_id, "client-id");
+        assert_eq!(peer.fingerprint, client_fp);
+        assert_eq!(client.unwrap(), server_fp);
+    }
+
+    #[tokio::test]
+    async fn test_pairing_rejects_wrong_code() {
+        let (server, client, _, _) = pair_locally("042137", "042138").await;
+
+        assert!(matches!(server, Err(ConnectionError::PairingFailed(_))));
+        assert!(matches!(client, Err(ConnectionError::PairingFailed(_))));
+    }
+
+    #[test]
+    fn test_spake_keys_agree_only_with_same_code_and_fingerprints() {
+        let keys = |server_code: &str, client_fp: &str| {
+            let client = Exchange::start(Role::Client, "123456", "fp-client", "fp-server");
+            let server = Exchange::start(Role::Server, server_code, client_fp, "fp-server");
+            let (c_msg, s_msg) = (client.message(), server.message());
+            (client.finish(&s_msg).unwrap(), server.finish(&c_msg).unwrap())
+        };
+
+        let (c_key, s_key) = keys("123456", "fp-client");
+        assert_eq!(c_key, s_key);
+        let (c_key, s_key) = keys("654321", "fp-client");
+        assert_ne!(c_key, s_key);
+        // A man in the middle terminates TLS with a certificate of its own
+        let (c_key, s_key) = keys("123456", "fp-intercepted");
+        assert_ne!(c_key, s_key);
+
+        // Both ends playing the same side is refused
+        let client = Exchange::start(Role::Client, "123456", "fp-client", "fp-server");
+        let other = Exchange::start(Role::Client, "123456", "fp-client", "fp-server");
+        assert!(client.finish(&other.message()).is_err());
+    }
+
+    #[test]
+    fn test_paired_hosts_reload_after_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("paired_hosts");
+        let paired = PairedHosts::load(&path).unwrap();
+        assert_eq!(paired.check("client", "aa").unwrap(), HostTrust::Unknown);
+
+        KnownHosts::load(&path).unwrap().pin("client", "aa").unwrap();
+        assert_eq!(paired.check("client", "aa").unwrap(), HostTrust::Trusted);
+
+        paired.pin("other", "bb").unwrap();
+        assert_eq!(paired.check("client", "aa").unwrap(), HostTrust::Trusted);
+        assert_eq!(paired.check("other", "bb").unwrap(), HostTrust::Trusted);
+    }
+
+    #[test]
+    fn test_normalize_code() {
+        assert_eq!(normalize_code("123 456"), Some("123456".to_string()));
+        assert_eq!(normalize_code("123-456"), Some("123456".to_string()));
+        assert_eq!(normalize_code("12345"), None);
+        assert_eq!(normalize_code("12345a"), None);
+        assert_eq!(generate_code().len(), PAIRING_CODE_DIGITS);
+    }
+}
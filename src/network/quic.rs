@@ -0,0 +1,398 @@
+//! QUIC transport
+//!
+//! Carries a connection over QUIC, secured with the same TLS identity and
+//! trust (CA or pinned fingerprint) as TCP. The client opens one
+//! bidirectional stream for the handshake and control messages, so a QUIC
+//! connection works like any other `Transport`. Once the handshake is done,
+//! input events get a stream of their own and every clipboard transfer gets
+//! another, so a large clipboard never holds up the mouse.
+//!
+//! Messages on different streams can overtake each other; only messages on
+//! the same stream keep their order.
+
+use async_trait::async_trait;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Endpoint, EndpointConfig, RecvStream, SendStream, TokioRuntime};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, Mutex, OwnedSemaphorePermit};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use super::limits::HandshakeLimits;
+use super::listener;
+use super::transport::{Connector, Multiplex, RecvLane, SendLane, Transport, TransportListener};
+
+/// The first stream of a QUIC connection, with the connection behind it
+pub struct QuicTransport {
+    send: SendStream,
+    recv: RecvStream,
+    connection: quinn::Connection,
+    peer: SocketAddr,
+    /// Handshake permit of an accepted peer, until the server takes it
+    permit: Option<OwnedSemaphorePermit>,
+    /// A client's own endpoint, kept for as long as its connection
+    _endpoint: Option<Endpoint>,
+}
+
+impl Transport for QuicTransport {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    fn is_encrypted(&self) -> bool {
+        true
+    }
+
+    fn peer_fingerprint(&self) -> Option<String> {
+        let certs = self
+            .connection
+            .peer_identity()?
+            .downcast::<Vec<CertificateDer<'static>>>()
+            .ok()?;
+        certs.first().map(|c| super::trust::fingerprint(c))
+    }
+
+    fn multiplex(&self) -> Option<Arc<dyn Multiplex>> {
+        Some(Arc::new(self.connection.clone()))
+    }
+
+    fn take_handshake_permit(&mut self) -> Option<OwnedSemaphorePermit> {
+        self.permit.take()
+    }
+}
+
+impl AsyncRead for QuicTransport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicTransport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.get_mut().send), cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.get_mut().send), cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.get_mut().send), cx)
+    }
+}
+
+#[async_trait]
+impl Multiplex for quinn::Connection {
+    async fn open(&self) -> io::Result<SendLane> {
+        Ok(Box::new(self.open_uni().await?))
+    }
+
+    async fn accept(&self) -> io::Result<RecvLane> {
+        Ok(Box::new(self.accept_uni().await?))
+    }
+}
+
+/// Accepts QUIC connections. QUIC handshakes run in the background so a
+/// slow peer can't hold up the others; `accept` returns connections whose
+/// first stream is open. The server's `HandshakeLimits` are applied before
+/// a handshake starts, and the permit taken travels with the connection.
+pub struct QuicListener {
+    endpoint: Endpoint,
+    ready: Mutex<mpsc::Receiver<QuicTransport>>,
+    driver: JoinHandle<()>,
+}
+
+impl QuicListener {
+    /// Listen on `socket` with the TLS identity of `acceptor`. Peers over
+    /// `limits` are refused, and those that haven't opened their first
+    /// stream and been accepted within `handshake_timeout` are dropped.
+    pub fn new(
+        socket: std::net::UdpSocket,
+        acceptor: &TlsAcceptor,
+        handshake_timeout: Duration,
+        limits: HandshakeLimits,
+    ) -> io::Result<Self> {
+        let crypto = QuicServerConfig::try_from(acceptor.config().clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(config),
+            socket,
+            Arc::new(TokioRuntime),
+        )?;
+
+        let (tx, rx) = mpsc::channel(16);
+        let driver = tokio::spawn({
+            let endpoint = endpoint.clone();
+            async move {
+                while let Some(incoming) = endpoint.accept().await {
+                    let peer = listener::canonical_peer(incoming.remote_address());
+                    let permit = match limits.admit(Some(peer.ip())) {
+                        Ok(permit) => permit,
+                        Err(rejection) => {
+                            tracing::warn!("Refusing QUIC connection from {}: {}", peer, rejection.reason);
+                            incoming.refuse();
+                            continue;
+                        }
+                    };
+
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        let accept = async {
+                            let connection = incoming.await?;
+                            let (send, recv) = connection.accept_bi().await?;
+                            let transport = QuicTransport {
+                                send,
+                                recv,
+                                connection,
+                                peer,
+                                permit: Some(permit),
+                                _endpoint: None,
+                            };
+                            let _ = tx.send(transport).await;
+                            Ok::<_, quinn::ConnectionError>(())
+                        };
+                        match tokio::time::timeout(handshake_timeout, accept).await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(e)) => tracing::debug!("QUIC handshake with {} failed: {}", peer, e),
+                            Err(_) => tracing::debug!("QUIC handshake with {} timed out", peer),
+                        }
+                    });
+                }
+            }
+        });
+
+        Ok(Self {
+            endpoint,
+            ready: Mutex::new(rx),
+            driver,
+        })
+    }
+}
+
+#[async_trait]
+impl TransportListener for QuicListener {
+    async fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        match self.ready.lock().await.recv().await {
+            Some(transport) => Ok(Box::new(transport)),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    fn describe(&self) -> String {
+        match self.endpoint.local_addr() {
+            Ok(addr) => format!("{} (QUIC)", addr),
+            Err(e) => e.to_string(),
+        }
+    }
+}
+
+impl Drop for QuicListener {
+    fn drop(&mut self) {
+        // Stop taking new peers; connections already accepted carry on
+        self.driver.abort();
+        self.endpoint.set_server_config(None);
+    }
+}
+
+/// Connects over QUIC, verifying the server like the TLS connector it was
+/// made from
+#[derive(Debug, Clone)]
+pub struct QuicConnector {
+    config: quinn::ClientConfig,
+    server_name: String,
+}
+
+impl QuicConnector {
+    /// Use the TLS configuration of `tls`, checking the server's
+    /// certificate against `server_name`
+    pub fn new(tls: &TlsConnector, server_name: impl Into<String>) -> io::Result<Self> {
+        let crypto = QuicClientConfig::try_from(tls.config().clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Self {
+            config: quinn::ClientConfig::new(Arc::new(crypto)),
+            server_name: server_name.into(),
+        })
+    }
+}
+
+#[async_trait]
+impl Connector for QuicConnector {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn Transport>> {
+        let local = match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let endpoint = Endpoint::client(local)?;
+        let connection = endpoint
+            .connect_with(self.config.clone(), addr, &self.server_name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .await?;
+        let (send, recv) = connection.open_bi().await?;
+
+        Ok(Box::new(QuicTransport {
+            send,
+            recv,
+            connection,
+            peer: addr,
+            permit: None,
+            _endpoint: Some(endpoint),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::transport::sniff;
+    use crate::network::{tls, Connection};
+    use crate::protocol::{Message, ScreenInfo};
+    use tokio::io::AsyncWriteExt;
+
+    /// A listener with a fresh self-signed identity, and the certificate
+    /// path clients trust it with
+    fn listen(dir: &std::path::Path, limits: HandshakeLimits) -> (QuicListener, std::path::PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+        let acceptor = tls::server_acceptor(&cert_path, &key_path).unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let listener = QuicListener::new(socket, &acceptor, Duration::from_secs(5), limits).unwrap();
+        (listener, cert_path)
+    }
+
+    #[tokio::test]
+    async fn test_clipboard_does_not_hold_up_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let (listener, cert_path) = listen(dir.path(), HandshakeLimits::new(0, 0));
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let stream = listener.accept().await.unwrap();
+            let peer = stream.peer_addr().unwrap();
+            let mut conn = Connection::new(stream, peer);
+            let info = ScreenInfo::new("server".into(), "Server".into(), 1920, 1080);
+            conn.handshake_server(&info).await.unwrap();
+
+            conn.send(&Message::ClipboardData {
+                mime_type: "text/plain".into(),
+                data: vec![b'x'; 8 * 1024 * 1024],
+            })
+            .await
+            .unwrap();
+            conn.send(&Message::MouseMoveRelative { dx: 3, dy: 4 }).await.unwrap();
+            conn.recv().await.unwrap();
+        });
+
+        let tls_connector = tls::client_connector(&cert_path, None).unwrap();
+        let connector = QuicConnector::new(&tls_connector, "localhost").unwrap();
+        let stream = connector.connect(addr).await.unwrap();
+        let mut conn = Connection::new(stream, addr);
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        conn.handshake_client(&info).await.unwrap();
+        assert!(conn.is_encrypted());
+        assert!(conn.peer_fingerprint().is_some());
+
+        let first = conn.recv().await.unwrap().unwrap();
+        assert!(matches!(first.message, Message::MouseMoveRelative { dx: 3, dy: 4 }));
+        let second = conn.recv().await.unwrap().unwrap();
+        match second.message {
+            Message::ClipboardData { data, .. } => assert_eq!(data.len(), 8 * 1024 * 1024),
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        conn.close("done").await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clipboard_data_keeps_its_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let (listener, cert_path) = listen(dir.path(), HandshakeLimits::new(0, 0));
+        let addr = listener.local_addr().unwrap();
+
+        // Largest first, so frames on separate streams would overtake it
+        let sizes = [4 * 1024 * 1024, 1, 2];
+        let server = tokio::spawn(async move {
+            let stream = listener.accept().await.unwrap();
+            let peer = stream.peer_addr().unwrap();
+            let mut conn = Connection::new(stream, peer);
+            let info = ScreenInfo::new("server".into(), "Server".into(), 1920, 1080);
+            conn.handshake_server(&info).await.unwrap();
+
+            let batch: Vec<_> = sizes
+                .iter()
+                .map(|&size| Message::ClipboardData {
+                    mime_type: "text/plain".into(),
+                    data: vec![b'x'; size],
+                })
+                .collect();
+            conn.send_batch(&batch).await.unwrap();
+            conn.recv().await.unwrap();
+        });
+
+        let tls_connector = tls::client_connector(&cert_path, None).unwrap();
+        let connector = QuicConnector::new(&tls_connector, "localhost").unwrap();
+        let stream = connector.connect(addr).await.unwrap();
+        let mut conn = Connection::new(stream, addr);
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        conn.handshake_client(&info).await.unwrap();
+
+        for size in sizes {
+            match conn.recv().await.unwrap().unwrap().message {
+                Message::ClipboardData { data, .. } => assert_eq!(data.len(), size),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+
+        conn.close("done").await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_limits_apply_before_handshake() {
+        let dir = tempfile::tempdir().unwrap();
+        let limits = HandshakeLimits::new(1, 0);
+        let (listener, cert_path) = listen(dir.path(), limits.clone());
+        let addr = listener.local_addr().unwrap();
+        let tls_connector = tls::client_connector(&cert_path, None).unwrap();
+        let connector = QuicConnector::new(&tls_connector, "localhost").unwrap();
+
+        // The permit taken for the first peer comes with it, also once
+        // the stream is sniffed. The server sees the first stream once
+        // something is written on it.
+        let mut client = connector.connect(addr).await.unwrap();
+        client.write_all(b"CNET").await.unwrap();
+        client.flush().await.unwrap();
+        let accepted = listener.accept().await.unwrap();
+        let (_, mut accepted) = sniff(accepted, 4).await.unwrap();
+        assert!(accepted.take_handshake_permit().is_some());
+
+        // A second connection from the same address is over its allowance
+        assert!(connector.connect(addr).await.is_err());
+    }
+}
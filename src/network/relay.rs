@@ -0,0 +1,576 @@
+//! Relay for hosts that cannot reach each other directly
+//!
+//! A relay is a small rendezvous process that the server and its clients
+//! all connect out to. The server keeps a connection registered under its
+//! host ID and a shared token; a client registering with the same host ID
+//! and token is joined to it. From then on the relay copies bytes both
+//! ways without looking at them, so TLS still runs end to end between
+//! server and client and the relay never sees their traffic in the clear.
+//!
+//! Registration is one `RelayRegister` frame from each side, answered with
+//! `RelayJoined` once a peer is found, or with `Error` if none is.
+//!
+//! The relay holds a bounded number of waiting server connections in all,
+//! and lets each one lapse if no client joins it in time. A server whose
+//! registration lapsed simply registers again.
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::client::ReconnectPolicy;
+use super::listener;
+use super::transport::{Connector, Transport, TransportListener};
+use crate::protocol::{error_codes, Decoder, Encoder, Message, RelayRole, MAGIC_BYTES};
+
+/// Port a relay listens on unless told otherwise
+pub const DEFAULT_RELAY_PORT: u16 = 24810;
+
+/// Registration frames are small; anything bigger is not a CoreNet peer
+const MAX_REGISTER_SIZE: usize = 4096;
+
+/// Frame header: magic(4) + type(1) + length(4) + sequence(4)
+const HEADER_SIZE: usize = 13;
+
+/// How long a peer has to register after connecting to the relay
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most connections a server may keep waiting under one host ID and token
+const MAX_WAITING_PER_SERVER: usize = 8;
+
+/// Most server connections waiting at the relay across all host IDs
+const MAX_WAITING: usize = 1024;
+
+/// How long a server connection may wait for a client before the relay
+/// drops it
+const WAITING_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Waiting servers are found by host ID and a digest of their token, so a
+/// peer without the token can neither join nor crowd out their connections
+type ServerKey = (String, [u8; 32]);
+
+fn server_key(host_id: &str, token: &str) -> ServerKey {
+    (host_id.to_string(), Sha256::digest(token.as_bytes()).into())
+}
+
+/// A server connection waiting for a client
+struct WaitingServer {
+    id: u64,
+    stream: TcpStream,
+    addr: SocketAddr,
+}
+
+/// Server connections waiting for clients, by key
+#[derive(Default)]
+struct Waiting {
+    servers: HashMap<ServerKey, VecDeque<WaitingServer>>,
+    count: usize,
+    next_id: u64,
+}
+
+impl Waiting {
+    /// Park a connection under `key`, returning its ID, or handing it back
+    /// if `max` connections are already waiting. Past the per-server cap the
+    /// oldest connection under the same key is dropped.
+    fn park(
+        &mut self,
+        key: ServerKey,
+        stream: TcpStream,
+        addr: SocketAddr,
+        max: usize,
+    ) -> Result<u64, TcpStream> {
+        if self.count >= max {
+            return Err(stream);
+        }
+        self.next_id += 1;
+        let id = self.next_id;
+        let queue = self.servers.entry(key).or_default();
+        queue.push_back(WaitingServer { id, stream, addr });
+        if queue.len() > MAX_WAITING_PER_SERVER {
+            queue.pop_front();
+        } else {
+            self.count += 1;
+        }
+        Ok(id)
+    }
+
+    /// Take the connection `id` under `key`, or the oldest one if `id` is None
+    fn take(&mut self, key: &ServerKey, id: Option<u64>) -> Option<WaitingServer> {
+        let queue = self.servers.get_mut(key)?;
+        let server = match id {
+            Some(id) => {
+                let index = queue.iter().position(|server| server.id == id)?;
+                queue.remove(index)
+            }
+            None => queue.pop_front(),
+        };
+        if queue.is_empty() {
+            self.servers.remove(key);
+        }
+        if server.is_some() {
+            self.count -= 1;
+        }
+        server
+    }
+}
+
+/// Joins clients to waiting servers and forwards their traffic
+#[derive(Clone)]
+pub struct Relay {
+    waiting: Arc<Mutex<Waiting>>,
+    max_waiting: usize,
+    waiting_timeout: Duration,
+}
+
+impl Default for Relay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        Self {
+            waiting: Arc::default(),
+            max_waiting: MAX_WAITING,
+            waiting_timeout: WAITING_TIMEOUT,
+        }
+    }
+
+    /// Serve peers on `listeners` until accepting fails
+    pub async fn serve(&self, listeners: Vec<TcpListener>) -> io::Result<()> {
+        for listener in &listeners {
+            tracing::info!("Relay listening on {}", listener.local_addr()?);
+        }
+
+        loop {
+            let (stream, addr) = listener::accept_any(&listeners).await?;
+            let relay = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = relay.handle_peer(stream, addr).await {
+                    tracing::debug!("Relay peer {}: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    /// Read a peer's registration and either park it (server) or join it
+    /// to a waiting server (client)
+    async fn handle_peer(&self, mut stream: TcpStream, addr: SocketAddr) -> io::Result<()> {
+        let _ = stream.set_nodelay(true);
+        let register = tokio::time::timeout(REGISTER_TIMEOUT, read_message(&mut stream))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        let (role, host_id, token) = match register {
+            Message::RelayRegister { role, host_id, token } => (role, host_id, token),
+            _ => {
+                return refuse(&mut stream, error_codes::PROTOCOL_MISMATCH, "Expected RelayRegister").await;
+            }
+        };
+        let key = server_key(&host_id, &token);
+
+        match role {
+            RelayRole::Server => {
+                let parked = self.waiting.lock().unwrap().park(key.clone(), stream, addr, self.max_waiting);
+                let id = match parked {
+                    Ok(id) => id,
+                    Err(mut stream) => {
+                        tracing::warn!("Relay is full, turning away server '{}' from {}", host_id, addr);
+                        return refuse(
+                            &mut stream,
+                            error_codes::SERVER_BUSY,
+                            "Too many servers waiting at the relay",
+                        )
+                        .await;
+                    }
+                };
+                tracing::info!("Server '{}' waiting at the relay from {}", host_id, addr);
+
+                // Unless a client took it meanwhile, let the registration lapse
+                tokio::time::sleep(self.waiting_timeout).await;
+                let expired = self.waiting.lock().unwrap().take(&key, Some(id));
+                match expired {
+                    Some(mut server) => {
+                        tracing::debug!("Server '{}' at {} waited too long", host_id, addr);
+                        refuse(
+                            &mut server.stream,
+                            error_codes::HANDSHAKE_TIMEOUT,
+                            "No client joined in time",
+                        )
+                        .await
+                    }
+                    None => Ok(()),
+                }
+            }
+            RelayRole::Client => {
+                // Servers that went away while waiting are only noticed now
+                while let Some(mut server) = self.take_waiting(&key) {
+                    if write_message(&mut server.stream, &Message::RelayJoined { peer_addr: addr })
+                        .await
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    write_message(&mut stream, &Message::RelayJoined { peer_addr: server.addr }).await?;
+
+                    tracing::info!("Joined client {} to server '{}' at {}", addr, host_id, server.addr);
+                    let (up, down) = tokio::io::copy_bidirectional(&mut stream, &mut server.stream).await?;
+                    tracing::info!(
+                        "Relayed session between {} and {} ended ({} bytes up, {} down)",
+                        addr,
+                        server.addr,
+                        up,
+                        down
+                    );
+                    return Ok(());
+                }
+
+                // Deliberately the same answer for an unknown host and a wrong token
+                refuse(
+                    &mut stream,
+                    error_codes::HOST_NOT_FOUND,
+                    &format!("No server '{}' is waiting at the relay", host_id),
+                )
+                .await
+            }
+        }
+    }
+
+    /// Take the oldest connection waiting under `key`
+    fn take_waiting(&self, key: &ServerKey) -> Option<WaitingServer> {
+        self.waiting.lock().unwrap().take(key, None)
+    }
+}
+
+/// Tell a peer why it is turned away, then hang up
+async fn refuse(stream: &mut TcpStream, code: u32, reason: &str) -> io::Result<()> {
+    write_message(stream, &Message::Error {
+        code,
+        message: reason.to_string(),
+    })
+    .await?;
+    stream.shutdown().await
+}
+
+/// Write one frame
+async fn write_message<S: AsyncWrite + Unpin>(stream: &mut S, message: &Message) -> io::Result<()> {
+    let mut buf = BytesMut::new();
+    Encoder::new()
+        .encode(message, &mut buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    stream.write_all(&buf).await?;
+    stream.flush().await
+}
+
+/// Read exactly one frame, leaving whatever follows it unread
+async fn read_message<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Message> {
+    let mut buf = BytesMut::zeroed(HEADER_SIZE);
+    stream.read_exact(&mut buf).await?;
+    if buf[0..4] != MAGIC_BYTES {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a CoreNet peer"));
+    }
+
+    let length = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) as usize;
+    if length > MAX_REGISTER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Relay frame too large"));
+    }
+    buf.resize(HEADER_SIZE + length, 0);
+    stream.read_exact(&mut buf[HEADER_SIZE..]).await?;
+
+    match Decoder::new().decode(&mut buf) {
+        Ok(Some(frame)) => Ok(frame.message),
+        Ok(None) => Err(io::ErrorKind::UnexpectedEof.into()),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+    }
+}
+
+/// Register with the relay at `relay` and wait until it joins us to a peer
+async fn register(
+    relay: &str,
+    role: RelayRole,
+    host_id: &str,
+    token: &str,
+) -> io::Result<RelayTransport> {
+    let addr = super::resolve_target(relay, DEFAULT_RELAY_PORT).await?;
+    let mut stream = TcpStream::connect(addr).await?;
+    write_message(&mut stream, &Message::RelayRegister {
+        role,
+        host_id: host_id.to_string(),
+        token: token.to_string(),
+    })
+    .await?;
+
+    match read_message(&mut stream).await? {
+        Message::RelayJoined { peer_addr } => Ok(RelayTransport {
+            stream,
+            peer: peer_addr,
+        }),
+        Message::Error {
+            code: error_codes::HANDSHAKE_TIMEOUT,
+            message,
+        } => Err(io::Error::new(io::ErrorKind::TimedOut, message)),
+        Message::Error { message, .. } => Err(io::Error::new(io::ErrorKind::ConnectionRefused, message)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected reply from relay")),
+    }
+}
+
+/// A connection joined to a peer through a relay. The peer address is the
+/// one the relay saw the peer connect from.
+pub struct RelayTransport {
+    stream: TcpStream,
+    peer: SocketAddr,
+}
+
+impl Transport for RelayTransport {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    fn set_low_latency(&self) -> io::Result<()> {
+        self.stream.set_nodelay(true)
+    }
+}
+
+impl AsyncRead for RelayTransport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RelayTransport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Server side: keeps a connection waiting at the relay and hands it over
+/// as an accepted client once the relay joins one to it
+pub struct RelayListener {
+    relay: String,
+    host_id: String,
+    token: String,
+}
+
+impl RelayListener {
+    /// Register at `relay` as the server `host_id`
+    pub fn new(relay: impl Into<String>, host_id: impl Into<String>, token: impl Into<String>) -> Self {
+        Self {
+            relay: relay.into(),
+            host_id: host_id.into(),
+            token: token.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl TransportListener for RelayListener {
+    /// Waits as long as it takes, re-registering with backoff while the
+    /// relay can't be reached, and at once when a registration lapses
+    async fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        let policy = ReconnectPolicy::default();
+        let mut attempt = 0;
+        loop {
+            match register(&self.relay, RelayRole::Server, &self.host_id, &self.token).await {
+                Ok(transport) => return Ok(Box::new(transport)),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    tracing::debug!("Registration at relay {} lapsed ({}), registering again", self.relay, e);
+                    attempt = 0;
+                }
+                Err(e) => {
+                    attempt += 1;
+                    let delay = policy.delay(attempt);
+                    tracing::warn!("Relay {} unavailable ({}), retrying in {:?}", self.relay, e, delay);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Relayed connections have no local address",
+        ))
+    }
+
+    fn describe(&self) -> String {
+        format!("relay {} as '{}'", self.relay, self.host_id)
+    }
+}
+
+/// Client side: reaches the server registered at the relay as `host_id`,
+/// whatever address it is asked for
+pub struct RelayConnector {
+    relay: String,
+    host_id: String,
+    token: String,
+}
+
+impl RelayConnector {
+    /// Reach the server `host_id` through `relay`
+    pub fn new(relay: impl Into<String>, host_id: impl Into<String>, token: impl Into<String>) -> Self {
+        Self {
+            relay: relay.into(),
+            host_id: host_id.into(),
+            token: token.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Connector for RelayConnector {
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<Box<dyn Transport>> {
+        let transport = register(&self.relay, RelayRole::Client, &self.host_id, &self.token).await?;
+        Ok(Box::new(transport))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{Client, NetworkConfig, Server, ServerEvent};
+    use crate::protocol::ScreenInfo;
+
+    async fn start_relay() -> String {
+        serve_relay(Relay::new()).await
+    }
+
+    async fn serve_relay(relay: Relay) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { relay.serve(vec![listener]).await });
+        addr.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_client_reaches_server_through_relay() {
+        let relay = start_relay().await;
+
+        let mut config = NetworkConfig::new(0).without_tls();
+        config.bind_addresses = vec!["127.0.0.1:0".to_string()];
+        config.relay = Some(relay.clone());
+        config.relay_token = Some("secret".to_string());
+        let info = ScreenInfo::new("server".into(), "Server".into(), 1920, 1080);
+        let mut server = Server::new(config.clone(), info);
+        let mut events = server.take_event_receiver().unwrap();
+        server.start().await.unwrap();
+        assert!(matches!(events.recv().await, Some(ServerEvent::Started { .. })));
+
+        // The server registers in the background; retry until it is waiting
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let client = Client::new(config, info);
+        let connected = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match client.connect_relay(&relay, "server").await {
+                    Ok(()) => break,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+                }
+            }
+        })
+        .await;
+        assert!(connected.is_ok());
+        assert_eq!(client.server_screen().await.unwrap().host_id, "server");
+
+        match events.recv().await {
+            Some(ServerEvent::ClientConnected { addr, screen_info }) => {
+                assert_eq!(screen_info.host_id, "client");
+                assert!(addr.ip().is_loopback());
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        client.send(Message::MouseMoveRelative { dx: 5, dy: 6 }).await.unwrap();
+        match events.recv().await {
+            Some(ServerEvent::MessageReceived { message, .. }) => {
+                assert!(matches!(message, Message::MouseMoveRelative { dx: 5, dy: 6 }));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrong_token_is_not_joined() {
+        let relay = start_relay().await;
+
+        let server = tokio::spawn({
+            let relay = relay.clone();
+            async move { register(&relay, RelayRole::Server, "server", "secret").await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let err = register(&relay, RelayRole::Client, "server", "guess").await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(!server.is_finished());
+
+        let joined = register(&relay, RelayRole::Client, "server", "secret").await.unwrap();
+        let server = server.await.unwrap().unwrap();
+        assert_eq!(server.peer_addr().unwrap().ip(), joined.stream.local_addr().unwrap().ip());
+    }
+
+    #[tokio::test]
+    async fn test_waiting_servers_are_bounded_and_lapse() {
+        let relay = Relay {
+            max_waiting: 2,
+            waiting_timeout: Duration::from_secs(1),
+            ..Relay::new()
+        };
+        let waiting = relay.waiting.clone();
+        let parked = move |count: usize| {
+            let waiting = waiting.clone();
+            async move {
+                while waiting.lock().unwrap().count != count {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+        };
+        let relay = serve_relay(relay).await;
+
+        let park = |host_id: &'static str| {
+            let relay = relay.clone();
+            tokio::spawn(async move { register(&relay, RelayRole::Server, host_id, "secret").await })
+        };
+        let first = park("first");
+        let second = park("second");
+        parked(2).await;
+
+        // A third server doesn't fit, whatever its host ID
+        let full = register(&relay, RelayRole::Server, "third", "secret").await.err().unwrap();
+        assert_eq!(full.kind(), io::ErrorKind::ConnectionRefused);
+
+        // Joining one frees its place, and the other lapses unjoined
+        register(&relay, RelayRole::Client, "first", "secret").await.unwrap();
+        assert!(first.await.unwrap().is_ok());
+        let lapsed = second.await.unwrap().err().unwrap();
+        assert_eq!(lapsed.kind(), io::ErrorKind::TimedOut);
+
+        let third = park("third");
+        parked(1).await;
+        register(&relay, RelayRole::Client, "third", "secret").await.unwrap();
+        assert!(third.await.unwrap().is_ok());
+    }
+}
@@ -5,14 +5,35 @@
 
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use thiserror::Error;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, RwLock};
+use std::path::Path;
+use std::str::FromStr;
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, RwLock};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
 
-use super::connection::{Connection, ConnectionError, ConnectionHandle};
+use super::access::{AccessList, PeerIdentity};
+use super::client::ReconnectPolicy;
+use super::limits::{ClientSlots, HandshakeLimits};
+use super::listener;
+use super::pairing::{self, PairedHosts, PairedPeer};
+use super::connection::{
+    Connection, ConnectionError, ConnectionHandle, ConnectionStats, Rejection, SessionGrant,
+};
+use super::quic::QuicListener;
+use super::relay::RelayListener;
+use super::tls;
+use super::transport::{Connector, TcpConnector, Transport, TransportListener};
+use super::trust::{self, HostTrust};
+use super::websocket;
 use super::NetworkConfig;
-use crate::protocol::{Message, ScreenInfo};
+use crate::protocol::{error_codes, Message, MouseButton, ScreenInfo};
+
+/// How long shutdown waits for client sessions to end before aborting them
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
 
 /// Server errors
 #[derive(Error, Debug)]
@@ -31,6 +52,15 @@ pub enum ServerError {
     
     #[error("Bind failed: {0}")]
     BindFailed(String),
+    
+    #[error("TLS configuration error: {0}")]
+    TlsConfig(String),
+    
+    #[error("Invalid host rule: {0}")]
+    InvalidHostRule(String),
+    
+    #[error("Relay configuration error: {0}")]
+    RelayConfig(String),
 }
 
 pub type ServerResult<T> = Result<T, ServerError>;
@@ -43,19 +73,54 @@ pub enum ServerEvent {
         addr: SocketAddr,
         screen_info: ScreenInfo,
     },
+    /// A client was turned away before or during the handshake
+    ClientRejected {
+        addr: SocketAddr,
+        /// Host ID from Hello, if the client got that far
+        host_id: Option<String>,
+        /// Error code from `protocol::error_codes`
+        code: u32,
+        reason: String,
+    },
+    /// A client paired using the code armed with `Server::allow_pairing`
+    ClientPaired {
+        addr: SocketAddr,
+        peer: PairedPeer,
+    },
+    /// The attempt to pair with the armed code failed; the code is spent
+    PairingFailed {
+        addr: SocketAddr,
+        reason: String,
+    },
+    /// A client's connection dropped; its session is held for the
+    /// resume grace period before `ClientDisconnected` is sent
+    ClientSuspended {
+        addr: SocketAddr,
+        host_id: String,
+        reason: String,
+    },
+    /// A client reconnected and picked up its suspended session
+    ClientResumed {
+        addr: SocketAddr,
+        /// Address of the connection that dropped
+        previous_addr: SocketAddr,
+        screen_info: ScreenInfo,
+    },
     /// A client has disconnected
     ClientDisconnected {
         addr: SocketAddr,
+        host_id: String,
         reason: String,
     },
     /// Received a message from a client
     MessageReceived {
         addr: SocketAddr,
+        host_id: String,
         message: Message,
     },
-    /// Server started
+    /// Server started, listening on these addresses
     Started {
-        bind_addr: SocketAddr,
+        bind_addrs: Vec<SocketAddr>,
     },
     /// Server stopped
     Stopped,
@@ -68,28 +133,358 @@ pub enum ServerEvent {
 /// Information about a connected client
 #[derive(Debug, Clone)]
 pub struct ClientInfo {
-    /// Client address
+    /// Client address; unspecified for peers without one, like Unix
+    /// socket peers
     pub addr: SocketAddr,
+    /// Number the server gave this connection, unique for its lifetime
+    pub session_id: u64,
     /// Client screen information
     pub screen_info: ScreenInfo,
     /// Handle for sending messages to this client
     pub handle: ConnectionHandle,
 }
 
+impl ClientInfo {
+    /// Latest link statistics (RTT, jitter, heartbeat loss) for this client
+    pub fn stats(&self) -> ConnectionStats {
+        self.handle.stats()
+    }
+}
+
+/// What to do when a client announces a host ID that is already connected
+/// or holding a suspended session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateHostPolicy {
+    /// Drop the existing session in favour of the new connection
+    #[default]
+    Replace,
+    /// Turn the new connection away
+    Reject,
+}
+
+impl FromStr for DuplicateHostPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "replace" => Ok(DuplicateHostPolicy::Replace),
+            "reject" => Ok(DuplicateHostPolicy::Reject),
+            _ => Err(format!("unknown duplicate host policy '{}' (expected replace or reject)", s)),
+        }
+    }
+}
+
+/// Checks applied to every incoming connection before it is admitted
+#[derive(Clone)]
+struct AcceptPolicy {
+    /// TLS acceptor, if TLS is enabled
+    tls_acceptor: Option<TlsAcceptor>,
+    /// Refuse plaintext peers
+    require_tls: bool,
+    /// Paired client identities, if TLS is on and a file is set
+    paired: Option<Arc<PairedHosts>>,
+    /// Refuse clients that have not paired
+    require_pairing: bool,
+    /// Allow/deny rules on address, host ID and fingerprint
+    access: AccessList,
+}
+
+impl AcceptPolicy {
+    /// Decide whether to admit a client once its Hello has been read
+    fn admit(&self, peer: &PeerIdentity<'_>) -> Result<(), Rejection> {
+        self.access.check(peer).map_err(Rejection::authentication)?;
+        self.check_paired(peer.host_id, peer.fingerprint)
+    }
+
+    /// Check that a client presenting `fingerprint` has paired as `host_id`
+    fn check_paired(&self, host_id: &str, fingerprint: Option<&str>) -> Result<(), Rejection> {
+        let Some(paired) = self.paired.as_ref().filter(|_| self.require_pairing) else {
+            return Ok(());
+        };
+
+        let fingerprint = fingerprint.ok_or_else(|| {
+            Rejection::authentication("Client certificate required; run `corenet pair`")
+        })?;
+
+        let trust = paired.check(host_id, fingerprint).map_err(|e| {
+            tracing::error!("Failed to read paired hosts: {}", e);
+            Rejection::new(error_codes::INTERNAL_ERROR, "Server could not read paired hosts")
+        })?;
+
+        match trust {
+            HostTrust::Trusted => Ok(()),
+            _ => Err(Rejection::authentication(
+                "Host is not paired with this server; run `corenet pair`",
+            )),
+        }
+    }
+}
+
+/// A client session that a new connection can pick up
+struct Session {
+    host_id: String,
+    /// Certificate fingerprint the session was established with
+    fingerprint: Option<String>,
+    /// Client info, held while no connection owns the session
+    suspended: Option<ClientInfo>,
+}
+
+/// Resumable client sessions, by resume token
+#[derive(Clone)]
+struct SessionTable {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    /// How long a dropped session is held
+    grace: Duration,
+}
+
+impl SessionTable {
+    fn new(grace: Duration) -> Self {
+        Self {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            grace,
+        }
+    }
+
+    fn new_token() -> String {
+        rand::random::<[u8; 32]>()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Start a session for a newly admitted client, returning its token
+    fn open(&self, host_id: &str, fingerprint: Option<&str>) -> Option<String> {
+        if self.grace.is_zero() {
+            return None;
+        }
+
+        let token = Self::new_token();
+        self.sessions.lock().unwrap().insert(token.clone(), Session {
+            host_id: host_id.to_string(),
+            fingerprint: fingerprint.map(str::to_string),
+            suspended: None,
+        });
+        Some(token)
+    }
+
+    /// Hand a session to the client presenting its token, provided it is the
+    /// same host with the same certificate. The token is replaced. Returns the
+    /// client info if the session was suspended; otherwise its old connection
+    /// is still live.
+    fn resume(
+        &self,
+        token: &str,
+        host_id: &str,
+        fingerprint: Option<&str>,
+    ) -> Option<(String, Option<ClientInfo>)> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(token)?;
+        if session.host_id != host_id || session.fingerprint.as_deref() != fingerprint {
+            return None;
+        }
+
+        let mut session = sessions.remove(token)?;
+        let info = session.suspended.take();
+        let new_token = Self::new_token();
+        sessions.insert(new_token.clone(), session);
+        Some((new_token, info))
+    }
+
+    /// Check whether `host_id` has a suspended session
+    fn is_held(&self, host_id: &str) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .any(|s| s.host_id == host_id && s.suspended.is_some())
+    }
+
+    /// Drop the suspended session of `host_id`, returning its client info
+    fn take_held(&self, host_id: &str) -> Option<ClientInfo> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let token = sessions
+            .iter()
+            .find(|(_, s)| s.host_id == host_id && s.suspended.is_some())
+            .map(|(token, _)| token.clone())?;
+        sessions.remove(&token)?.suspended
+    }
+
+    /// Hold a session whose connection dropped
+    fn suspend(&self, token: &str, info: ClientInfo) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(token) {
+            session.suspended = Some(info);
+        }
+    }
+
+    /// Drop a session if it is still suspended, returning its client info
+    fn expire(&self, token: &str) -> Option<ClientInfo> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.get(token)?.suspended.as_ref()?;
+        sessions.remove(token)?.suspended
+    }
+
+    /// Forget a session that ended cleanly
+    fn close(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+}
+
+/// Connected clients by host ID. Admission checks and registers a client in
+/// one step, so this is locked synchronously and never across an await.
+type ClientMap = Arc<std::sync::RwLock<HashMap<String, ClientInfo>>>;
+
+/// How an admitted client came by its slot
+enum Admission {
+    /// A new client, in a freshly claimed slot
+    New,
+    /// Resumed its session, taking over from this connection
+    Resumed(ClientInfo),
+    /// Took over from a connection or held session with the same host ID
+    Replaced(ClientInfo),
+}
+
+impl Admission {
+    /// The connection or held session this client took over from
+    fn previous(&self) -> Option<&ClientInfo> {
+        match self {
+            Admission::New => None,
+            Admission::Resumed(previous) | Admission::Replaced(previous) => Some(previous),
+        }
+    }
+}
+
+/// State shared by the accept loop and every client task
+#[derive(Clone)]
+struct ServerContext {
+    policy: AcceptPolicy,
+    sessions: SessionTable,
+    clients: ClientMap,
+    /// What to do with a second client claiming a host ID
+    duplicate_hosts: DuplicateHostPolicy,
+    /// Slots held by connected clients and suspended sessions
+    slots: ClientSlots,
+    /// Per-address connection rate limit and permits for handshakes in progress
+    limits: HandshakeLimits,
+    /// Source of connection session IDs
+    next_session_id: Arc<AtomicU64>,
+    event_tx: mpsc::Sender<ServerEvent>,
+    screen_info: ScreenInfo,
+    /// Deadline for a client's handshake, TLS included
+    handshake_timeout: Duration,
+    heartbeat_interval: Duration,
+    /// Heartbeat intervals a client may stay silent before it is dropped
+    missed_heartbeats: u32,
+    /// Code for the next pairing attempt, if one is armed
+    pairing_code: Arc<Mutex<Option<String>>>,
+    /// Fingerprint of our certificate, if TLS is on
+    fingerprint: Option<String>,
+    /// Grant clients a UDP channel for pointer motion
+    udp_motion: bool,
+    /// Accept WebSocket upgrades alongside native connections
+    websocket: bool,
+    /// Set when the server is stopping
+    shutdown: watch::Receiver<bool>,
+}
+
+impl ServerContext {
+    /// Register an admitted client under its host ID. A client resuming its
+    /// session or replacing one with the same host ID takes over that
+    /// session's slot; a new client needs a free one.
+    fn register(
+        &self,
+        info: ClientInfo,
+        resume_token: Option<&str>,
+        fingerprint: Option<&str>,
+    ) -> Result<(Admission, SessionGrant), Rejection> {
+        let host_id = info.screen_info.host_id.clone();
+        let mut clients = self.clients.write().unwrap();
+
+        // The old connection may not have noticed the drop yet
+        let resumed = resume_token.and_then(|token| self.sessions.resume(token, &host_id, fingerprint));
+        if let Some((token, held)) = resumed {
+            if let Some(previous) = held.or_else(|| clients.remove(&host_id)) {
+                clients.insert(host_id, info);
+                return Ok((
+                    Admission::Resumed(previous),
+                    SessionGrant {
+                        resume_token: Some(token),
+                        resumed: true,
+                    },
+                ));
+            }
+            self.sessions.close(&token);
+        }
+
+        let admission = if clients.contains_key(&host_id) || self.sessions.is_held(&host_id) {
+            if self.duplicate_hosts == DuplicateHostPolicy::Reject {
+                return Err(Rejection::new(
+                    error_codes::DUPLICATE_HOST,
+                    format!("Host '{}' is already connected", host_id),
+                ));
+            }
+            match clients.remove(&host_id).or_else(|| self.sessions.take_held(&host_id)) {
+                Some(previous) => Admission::Replaced(previous),
+                None => return Err(Rejection::new(error_codes::INTERNAL_ERROR, "Session vanished")),
+            }
+        } else if self.slots.try_take() {
+            Admission::New
+        } else {
+            return Err(Rejection::new(
+                error_codes::SERVER_FULL,
+                format!("Server is full ({} clients)", self.slots.max()),
+            ));
+        };
+
+        let grant = SessionGrant {
+            resume_token: self.sessions.open(&host_id, fingerprint),
+            resumed: false,
+        };
+        clients.insert(host_id, info);
+        Ok((admission, grant))
+    }
+
+    /// Remove a client's entry unless a newer connection has taken it over,
+    /// returning it if it was still session `session_id`'s. Given a token, the
+    /// session is held for the client to resume; that happens under the
+    /// clients lock, so a new connection from the same host finds either the
+    /// live client or its held session.
+    fn unregister(
+        &self,
+        host_id: &str,
+        session_id: u64,
+        suspend_token: Option<&str>,
+    ) -> Option<ClientInfo> {
+        let mut clients = self.clients.write().unwrap();
+        let info = match clients.get(host_id) {
+            Some(info) if info.session_id == session_id => clients.remove(host_id)?,
+            _ => return None,
+        };
+        if let Some(token) = suspend_token {
+            self.sessions.suspend(token, info.clone());
+        }
+        Some(info)
+    }
+}
+
 /// CoreNet Server
 pub struct Server {
     /// Server configuration
     config: NetworkConfig,
     /// Local screen information
     screen_info: ScreenInfo,
-    /// Connected clients
-    clients: Arc<RwLock<HashMap<SocketAddr, ClientInfo>>>,
+    /// Connected clients, by host ID
+    clients: ClientMap,
     /// Event sender
     event_tx: mpsc::Sender<ServerEvent>,
     /// Event receiver (for consumers)
     event_rx: Option<mpsc::Receiver<ServerEvent>>,
-    /// Shutdown signal
-    shutdown_tx: Option<mpsc::Sender<()>>,
+    /// Shutdown signal for the accept loops
+    shutdown_tx: Option<watch::Sender<bool>>,
+    /// Limits on new connections, shared with listeners that apply them
+    /// themselves
+    limits: HandshakeLimits,
+    /// Pairing code good for the next pairing attempt, if one is armed
+    pairing_code: Arc<Mutex<Option<String>>>,
     /// Whether the server is running
     running: Arc<RwLock<bool>>,
 }
@@ -98,18 +493,34 @@ impl Server {
     /// Create a new server
     pub fn new(config: NetworkConfig, screen_info: ScreenInfo) -> Self {
         let (event_tx, event_rx) = mpsc::channel(256);
+        let limits = HandshakeLimits::new(config.max_connections_per_minute, config.max_pending_handshakes);
         
         Self {
             config,
             screen_info,
-            clients: Arc::new(RwLock::new(HashMap::new())),
+            clients: Arc::new(std::sync::RwLock::new(HashMap::new())),
             event_tx,
             event_rx: Some(event_rx),
             shutdown_tx: None,
+            limits,
+            pairing_code: Arc::new(Mutex::new(None)),
             running: Arc::new(RwLock::new(false)),
         }
     }
 
+    /// Let one client pair using `code`. The next pairing request spends
+    /// the code whether or not it matches, so each code allows a single
+    /// guess; the outcome is reported as `ClientPaired` or `PairingFailed`.
+    pub fn allow_pairing(&self, code: String) -> ServerResult<()> {
+        if !self.config.use_tls || self.config.paired_hosts_path.is_none() {
+            return Err(ServerError::TlsConfig(
+                "Pairing needs TLS and a paired hosts file".to_string(),
+            ));
+        }
+        *self.pairing_code.lock().unwrap() = Some(code);
+        Ok(())
+    }
+
     /// Take the event receiver (can only be called once)
     pub fn take_event_receiver(&mut self) -> Option<mpsc::Receiver<ServerEvent>> {
         self.event_rx.take()
@@ -124,15 +535,81 @@ impl Server {
             }
         }
 
-        let bind_addr = format!("0.0.0.0:{}", self.config.port);
-        let listener = TcpListener::bind(&bind_addr).await.map_err(|e| {
-            ServerError::BindFailed(format!("Failed to bind to {}: {}", bind_addr, e))
-        })?;
+        let mut listeners = match &self.config.socket_path {
+            Some(path) => vec![bind_socket(path)?],
+            None => self.bind_network()?,
+        };
+        if let Some(relay) = &self.config.relay {
+            let token = self.config.relay_token.as_ref().ok_or_else(|| {
+                ServerError::RelayConfig("A relay is set but no relay token is".to_string())
+            })?;
+            listeners.push(Box::new(RelayListener::new(relay, &self.screen_info.host_id, token)));
+        }
+
+        self.start_on(listeners).await
+    }
+
+    /// Bind the TCP listeners, and QUIC ones on the same addresses if enabled
+    fn bind_network(&self) -> ServerResult<Vec<Box<dyn TransportListener>>> {
+        let tcp = listener::bind_listeners(&self.config.bind_addresses, self.config.port)
+            .map_err(|e| ServerError::BindFailed(format!("Failed to bind to {}", e)))?;
+
+        let mut quic = Vec::new();
+        if self.config.quic {
+            let acceptor = self.tls_acceptor()?.ok_or_else(|| {
+                ServerError::TlsConfig("QUIC is enabled but TLS is disabled".to_string())
+            })?;
+            let handshake_timeout = Duration::from_millis(self.config.handshake_timeout_ms);
+            for tcp in &tcp {
+                let listener = listener::bind_udp_like(tcp)
+                    .and_then(|socket| {
+                        QuicListener::new(socket, &acceptor, handshake_timeout, self.limits.clone())
+                    })
+                    .map_err(|e| ServerError::BindFailed(format!("Failed to bind to {}", e)))?;
+                quic.push(Box::new(listener) as Box<dyn TransportListener>);
+            }
+        }
 
-        let local_addr = listener.local_addr()?;
-        tracing::info!("Server listening on {}", local_addr);
+        Ok(tcp
+            .into_iter()
+            .map(|l| Box::new(l) as Box<dyn TransportListener>)
+            .chain(quic)
+            .collect())
+    }
 
-        let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
+    /// Start the server on listeners for any transport
+    pub async fn start_on(&mut self, listeners: Vec<Box<dyn TransportListener>>) -> ServerResult<()> {
+        {
+            let running = self.running.read().await;
+            if *running {
+                return Err(ServerError::AlreadyRunning);
+            }
+        }
+
+        let policy = self.accept_policy()?;
+        let fingerprint = match &self.config.cert_path {
+            Some(cert_path) if policy.tls_acceptor.is_some() => {
+                Some(trust::certificate_fingerprint(Path::new(cert_path))?)
+            }
+            _ => None,
+        };
+        let sessions = SessionTable::new(Duration::from_millis(self.config.resume_grace_ms));
+
+        // Listeners without a network address, like Unix sockets, are
+        // logged but not reported in `Started`
+        let bind_addrs = listeners
+            .iter()
+            .filter_map(|l| l.local_addr().ok())
+            .collect::<Vec<_>>();
+        for listener in &listeners {
+            tracing::info!(
+                "Server listening on {} ({})",
+                listener.describe(),
+                if policy.tls_acceptor.is_some() { "TLS" } else { "plaintext" }
+            );
+        }
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
         self.shutdown_tx = Some(shutdown_tx);
 
         {
@@ -140,49 +617,58 @@ impl Server {
             *running = true;
         }
 
-        let _ = self.event_tx.send(ServerEvent::Started { bind_addr: local_addr }).await;
+        let _ = self.event_tx.send(ServerEvent::Started { bind_addrs }).await;
 
-        let clients = self.clients.clone();
+        let ctx = ServerContext {
+            policy,
+            sessions,
+            clients: self.clients.clone(),
+            duplicate_hosts: self.config.duplicate_hosts,
+            slots: ClientSlots::new(self.config.max_clients),
+            limits: self.limits.clone(),
+            next_session_id: Arc::new(AtomicU64::new(1)),
+            event_tx: self.event_tx.clone(),
+            screen_info: self.screen_info.clone(),
+            handshake_timeout: Duration::from_millis(self.config.handshake_timeout_ms),
+            heartbeat_interval: Duration::from_millis(self.config.heartbeat_interval_ms),
+            missed_heartbeats: self.config.missed_heartbeats,
+            pairing_code: self.pairing_code.clone(),
+            fingerprint,
+            udp_motion: self.config.udp_motion,
+            websocket: self.config.websocket,
+            shutdown: shutdown_rx.clone(),
+        };
         let event_tx = self.event_tx.clone();
-        let screen_info = self.screen_info.clone();
         let running = self.running.clone();
 
-        // Spawn the accept loop
+        // Spawn one accept loop per listener, all feeding the same clients
+        let accept_loops: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| tokio::spawn(accept_loop(listener, ctx.clone(), shutdown_rx.clone())))
+            .collect();
+
+        // and one dial loop per client that waits for the server to connect in
+        let connect_timeout = Duration::from_millis(self.config.connect_timeout_ms);
+        let dial_loops: Vec<_> = self
+            .config
+            .reverse_clients
+            .iter()
+            .map(|target| {
+                let target = target.clone();
+                let port = self.config.port;
+                tokio::spawn(dial_loop(target, port, connect_timeout, ctx.clone(), shutdown_rx.clone()))
+            })
+            .collect();
+
         tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    result = listener.accept() => {
-                        match result {
-                            Ok((stream, addr)) => {
-                                tracing::info!("New connection from {}", addr);
-                                
-                                let clients = clients.clone();
-                                let event_tx = event_tx.clone();
-                                let screen_info = screen_info.clone();
-                                
-                                tokio::spawn(async move {
-                                    if let Err(e) = handle_client(
-                                        stream,
-                                        addr,
-                                        clients,
-                                        event_tx,
-                                        screen_info,
-                                    ).await {
-                                        tracing::error!("Client handler error: {}", e);
-                                    }
-                                });
-                            }
-                            Err(e) => {
-                                tracing::error!("Accept error: {}", e);
-                            }
-                        }
-                    }
-                    _ = shutdown_rx.recv() => {
-                        tracing::info!("Server shutdown requested");
-                        break;
-                    }
+            let mut sessions = Vec::new();
+            for task in accept_loops.into_iter().chain(dial_loops) {
+                if let Ok(remaining) = task.await {
+                    sessions.push(remaining);
                 }
             }
+            tracing::info!("Server shutdown requested");
+            drain_sessions(sessions, SHUTDOWN_TIMEOUT).await;
 
             let mut running = running.write().await;
             *running = false;
@@ -193,7 +679,67 @@ impl Server {
         Ok(())
     }
 
-    /// Stop the server
+    /// Build the checks applied to incoming connections from the configuration
+    fn accept_policy(&self) -> ServerResult<AcceptPolicy> {
+        if self.config.require_pairing {
+            if !self.config.use_tls {
+                return Err(ServerError::TlsConfig(
+                    "Pairing is required but TLS is disabled".to_string(),
+                ));
+            }
+            if self.config.paired_hosts_path.is_none() {
+                return Err(ServerError::TlsConfig(
+                    "Pairing is required but no paired hosts file is set".to_string(),
+                ));
+            }
+        }
+        let paired = match &self.config.paired_hosts_path {
+            Some(path) if self.config.use_tls => Some(Arc::new(PairedHosts::load(Path::new(path))?)),
+            _ => None,
+        };
+
+        let access = AccessList::parse(&self.config.allowed_hosts, &self.config.denied_hosts)
+            .map_err(ServerError::InvalidHostRule)?;
+
+        Ok(AcceptPolicy {
+            tls_acceptor: self.tls_acceptor()?,
+            require_tls: self.config.require_tls,
+            paired,
+            require_pairing: self.config.require_pairing,
+            access,
+        })
+    }
+
+    /// Build the TLS acceptor from the configured certificate, if TLS is enabled
+    fn tls_acceptor(&self) -> ServerResult<Option<TlsAcceptor>> {
+        if !self.config.use_tls {
+            if self.config.require_tls {
+                return Err(ServerError::TlsConfig(
+                    "TLS is required but disabled".to_string(),
+                ));
+            }
+            return Ok(None);
+        }
+
+        let (cert_path, key_path) = match (&self.config.cert_path, &self.config.key_path) {
+            (Some(cert), Some(key)) => (cert, key),
+            _ => {
+                return Err(ServerError::TlsConfig(
+                    "TLS is enabled but no certificate/key is configured".to_string(),
+                ));
+            }
+        };
+
+        let acceptor = tls::server_acceptor(Path::new(cert_path), Path::new(key_path))
+            .map_err(|e| ServerError::TlsConfig(format!("{}: {}", cert_path, e)))?;
+
+        Ok(Some(acceptor))
+    }
+
+    /// Stop the server. New connections stop being accepted and every
+    /// client is sent a release for any keys and buttons held on it, then
+    /// `Disconnect`. `ServerEvent::Stopped` follows once their sessions
+    /// have ended, or were aborted after `SHUTDOWN_TIMEOUT`.
     pub async fn stop(&mut self) -> ServerResult<()> {
         {
             let running = self.running.read().await;
@@ -203,15 +749,7 @@ impl Server {
         }
 
         if let Some(tx) = self.shutdown_tx.take() {
-            let _ = tx.send(()).await;
-        }
-
-        // Disconnect all clients
-        let clients = self.clients.read().await;
-        for (_, client) in clients.iter() {
-            let _ = client.handle.send(Message::Disconnect {
-                reason: "Server shutting down".to_string(),
-            }).await;
+            let _ = tx.send(true);
         }
 
         Ok(())
@@ -219,15 +757,15 @@ impl Server {
 
     /// Get a list of connected clients
     pub async fn clients(&self) -> Vec<ClientInfo> {
-        let clients = self.clients.read().await;
+        let clients = self.clients.read().unwrap();
         clients.values().cloned().collect()
     }
 
-    /// Send a message to a specific client
-    pub async fn send_to(&self, addr: &SocketAddr, message: Message) -> ServerResult<()> {
-        let clients = self.clients.read().await;
-        if let Some(client) = clients.get(addr) {
-            client.handle.send(message).await?;
+    /// Send a message to the client with this host ID
+    pub async fn send_to(&self, host_id: &str, message: Message) -> ServerResult<()> {
+        let handle = self.clients.read().unwrap().get(host_id).map(|c| c.handle.clone());
+        if let Some(handle) = handle {
+            handle.send(message)?;
             Ok(())
         } else {
             Err(ServerError::Connection(ConnectionError::Closed))
@@ -236,9 +774,8 @@ impl Server {
 
     /// Send a message to all connected clients
     pub async fn broadcast(&self, message: Message) {
-        let clients = self.clients.read().await;
-        for (_, client) in clients.iter() {
-            let _ = client.handle.send(message.clone()).await;
+        for client in self.clients().await {
+            let _ = client.handle.send(message.clone());
         }
     }
 
@@ -248,42 +785,550 @@ impl Server {
     }
 }
 
-/// Handle a client connection
-async fn handle_client(
-    stream: TcpStream,
+/// Listen on the Unix domain socket at `path`
+#[cfg(unix)]
+fn bind_socket(path: &str) -> ServerResult<Box<dyn TransportListener>> {
+    let listener = super::unix::UnixSocketListener::bind(path)
+        .map_err(|e| ServerError::BindFailed(format!("Failed to bind to {}: {}", path, e)))?;
+    Ok(Box::new(listener))
+}
+
+#[cfg(not(unix))]
+fn bind_socket(path: &str) -> ServerResult<Box<dyn TransportListener>> {
+    Err(ServerError::BindFailed(format!(
+        "Cannot listen on {}: Unix domain sockets are not supported on this platform",
+        path
+    )))
+}
+
+/// Pause after a failed accept before trying again
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Accept connections on one listener until shutdown is signalled,
+/// returning the sessions still running
+async fn accept_loop(
+    listener: Box<dyn TransportListener>,
+    ctx: ServerContext,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> JoinSet<()> {
+    let mut sessions = JoinSet::new();
+    loop {
+        tokio::select! {
+            result = listener.accept() => {
+                match result.and_then(|stream| Ok((stream.peer_addr()?, stream))) {
+                    Ok((addr, mut stream)) => {
+                        let ip = stream.peer_ip();
+                        match ip {
+                            Some(_) => tracing::info!("New connection from {}", addr),
+                            None => tracing::info!("New connection on {}", listener.describe()),
+                        }
+                        
+                        // Listeners that handshake in the background have
+                        // checked the limits already
+                        let ctx = ctx.clone();
+                        let admission = match stream.take_handshake_permit() {
+                            Some(permit) => Ok(permit),
+                            None => ctx.limits.admit(ip),
+                        };
+
+                        // Refusals are capped as well, so a flood past the
+                        // limits costs no more than closing its sockets
+                        let refusal = match &admission {
+                            Ok(_) => None,
+                            Err(rejection) => match ctx.limits.refusal() {
+                                Some(permit) => Some(permit),
+                                None => {
+                                    tracing::debug!("Dropping connection from {}: {}", addr, rejection.reason);
+                                    continue;
+                                }
+                            },
+                        };
+                        
+                        sessions.spawn(async move {
+                            let result = match admission {
+                                Ok(permit) => handle_client(stream, addr, permit, ctx).await,
+                                Err(rejection) => {
+                                    let result = refuse(stream, addr, rejection, ctx).await;
+                                    drop(refusal);
+                                    result
+                                }
+                            };
+                            if let Err(e) = result {
+                                tracing::error!("Client handler error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        // Errors like running out of file descriptors
+                        // persist; retrying at once would spin
+                        tracing::error!("Accept error: {}", e);
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    }
+                }
+            }
+            // Reap sessions that have ended
+            Some(_) = sessions.join_next() => {}
+            _ = shutdown_rx.changed() => {
+                break;
+            }
+        }
+    }
+    sessions
+}
+
+/// Keep a connection open to a client in reverse-connect mode, which
+/// listens for the server instead of dialling it. The server still runs
+/// the server side of TLS and the handshake. Redials with backoff whenever
+/// the client can't be reached or its session ends, until shutdown, then
+/// returns the session if it is still running.
+async fn dial_loop(
+    target: String,
+    default_port: u16,
+    connect_timeout: Duration,
+    ctx: ServerContext,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> JoinSet<()> {
+    let mut session = JoinSet::new();
+    let policy = ReconnectPolicy::default();
+    let mut attempt = 0;
+    loop {
+        let dialled = tokio::select! {
+            result = dial_client(&target, default_port, connect_timeout) => result,
+            _ = shutdown_rx.changed() => break,
+        };
+
+        match dialled {
+            Ok((stream, addr)) => {
+                tracing::info!("Connected out to client {} ({})", target, addr);
+                attempt = 0;
+
+                // Waiting here rather than refusing keeps our own dials from
+                // counting against inbound floods
+                let permit = tokio::select! {
+                    permit = ctx.limits.acquire() => match permit {
+                        Some(permit) => permit,
+                        None => break,
+                    },
+                    _ = shutdown_rx.changed() => break,
+                };
+                let ctx = ctx.clone();
+                session.spawn(async move {
+                    if let Err(e) = handle_client(stream, addr, permit, ctx).await {
+                        tracing::error!("Client handler error: {}", e);
+                    }
+                });
+
+                // The session sees shutdown itself and says goodbye, so it
+                // is handed back still running
+                tokio::select! {
+                    result = session.join_next() => {
+                        if let Some(Err(e)) = result {
+                            tracing::error!("Client handler panicked: {}", e);
+                        }
+                    }
+                    _ = shutdown_rx.changed() => break,
+                }
+            }
+            Err(e) => tracing::debug!("Could not reach client {}: {}", target, e),
+        }
+
+        attempt += 1;
+        let delay = policy.delay(attempt);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown_rx.changed() => break,
+        }
+    }
+    session
+}
+
+/// Keys and buttons a session has pressed on its client and not released
+#[derive(Debug, Default)]
+struct HeldInput {
+    keys: Vec<u32>,
+    buttons: Vec<MouseButton>,
+}
+
+impl HeldInput {
+    /// Follow presses and releases in messages sent to the client
+    fn track(&mut self, sent: &[Message]) {
+        for message in sent {
+            match message {
+                Message::KeyDown { keycode, .. } if !self.keys.contains(keycode) => {
+                    self.keys.push(*keycode);
+                }
+                Message::KeyUp { keycode, .. } => self.keys.retain(|k| k != keycode),
+                Message::MouseButton { button, pressed: true } if !self.buttons.contains(button) => {
+                    self.buttons.push(*button);
+                }
+                Message::MouseButton { button, pressed: false } => {
+                    self.buttons.retain(|b| b != button);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Messages releasing everything still held, most recent first
+    fn release_all(&mut self) -> Vec<Message> {
+        let keys = self.keys.drain(..).rev().map(|keycode| Message::KeyUp {
+            keycode,
+            modifiers: Default::default(),
+        });
+        let buttons = self.buttons.drain(..).rev().map(|button| Message::MouseButton {
+            button,
+            pressed: false,
+        });
+        keys.chain(buttons).collect()
+    }
+}
+
+/// Wait for the sessions left running at shutdown to say goodbye to their
+/// clients, aborting any still going after `timeout`
+async fn drain_sessions(mut sessions: Vec<JoinSet<()>>, timeout: Duration) {
+    let drained = tokio::time::timeout(timeout, async {
+        for set in &mut sessions {
+            while set.join_next().await.is_some() {}
+        }
+    })
+    .await;
+
+    if drained.is_err() {
+        let remaining: usize = sessions.iter().map(JoinSet::len).sum();
+        tracing::warn!("Aborting {} client sessions still running after {:?}", remaining, timeout);
+        for set in &mut sessions {
+            set.shutdown().await;
+        }
+    }
+}
+
+/// Open a TCP connection to a reverse-connect client at `target`, which
+/// names a host or address with an optional port
+async fn dial_client(
+    target: &str,
+    default_port: u16,
+    connect_timeout: Duration,
+) -> std::io::Result<(Box<dyn Transport>, SocketAddr)> {
+    let addr = super::resolve_target(target, default_port).await?;
+    let stream = tokio::time::timeout(connect_timeout, TcpConnector.connect(addr))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    let addr = stream.peer_addr()?;
+    Ok((stream, addr))
+}
+
+/// Turn away a connection before its handshake. Plaintext peers are told
+/// why; TLS peers are just closed, since answering them would cost the TLS
+/// handshake that the limits are there to avoid.
+async fn refuse(
+    stream: Box<dyn Transport>,
     addr: SocketAddr,
-    clients: Arc<RwLock<HashMap<SocketAddr, ClientInfo>>>,
-    event_tx: mpsc::Sender<ServerEvent>,
+    rejection: Rejection,
+    ctx: ServerContext,
+) -> Result<(), ConnectionError> {
+    tracing::warn!("Refusing connection from {}: {}", addr, rejection.reason);
+
+    let mut host_id = None;
+    let refused = tokio::time::timeout(REFUSE_TIMEOUT.min(ctx.handshake_timeout), async {
+        let stream = match &ctx.policy.tls_acceptor {
+            Some(_) => match tls::peer_starts_tls(stream).await? {
+                (true, _) => return Ok(()),
+                (false, stream) => stream,
+            },
+            None => stream,
+        };
+        let mut conn = Connection::new(stream, addr);
+        let result = conn.reject_handshake(&ctx.screen_info, &rejection).await;
+        host_id = conn.remote_screen_info().map(|s| s.host_id.clone());
+        result
+    })
+    .await;
+
+    let _ = ctx.event_tx.send(ServerEvent::ClientRejected {
+        addr,
+        host_id,
+        code: rejection.code,
+        reason: rejection.reason,
+    }).await;
+
+    refused.unwrap_or(Ok(()))
+}
+
+/// How long a refused plaintext peer has to send the Hello it is refused in
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Report a client that did not finish its handshake in time
+async fn report_handshake_timeout(
+    event_tx: &mpsc::Sender<ServerEvent>,
+    addr: SocketAddr,
+    host_id: Option<String>,
+) {
+    tracing::warn!("Handshake with {} timed out", addr);
+    let _ = event_tx.send(ServerEvent::ClientRejected {
+        addr,
+        host_id,
+        code: error_codes::HANDSHAKE_TIMEOUT,
+        reason: "Handshake timed out".to_string(),
+    }).await;
+}
+
+/// What a client opened its connection for
+enum Opening {
+    /// A session, admitted by the handshake
+    Session,
+    /// Pairing, with the PairRequest it opened with
+    Pairing { screen_info: ScreenInfo, pake: Vec<u8> },
+}
+
+/// Answer a pairing request from a client that has not paired yet, using
+/// the code armed with `Server::allow_pairing`. The code is spent on the
+/// first attempt, which ends in `ClientPaired` or `PairingFailed`.
+async fn pair_client(
+    conn: &mut Connection,
+    addr: SocketAddr,
+    ctx: &ServerContext,
     screen_info: ScreenInfo,
+    pake: &[u8],
+    deadline: tokio::time::Instant,
+) {
+    let code = ctx.pairing_code.lock().unwrap().take();
+    let (Some(code), Some(fingerprint), Some(paired)) = (code, &ctx.fingerprint, &ctx.policy.paired) else {
+        let rejection = Rejection::authentication("Server is not accepting pairing requests");
+        let _ = conn.send(&Message::Error {
+            code: rejection.code,
+            message: rejection.reason.clone(),
+        }).await;
+        let _ = ctx.event_tx.send(ServerEvent::ClientRejected {
+            addr,
+            host_id: Some(screen_info.host_id),
+            code: rejection.code,
+            reason: rejection.reason,
+        }).await;
+        return;
+    };
+    tracing::info!("Pairing request from {} ({})", screen_info.host_id, addr);
+
+    let pairing = async {
+        let peer = pairing::answer_pair_request(conn, screen_info, pake, &code, fingerprint).await?;
+        let (paired, host_id, peer_fingerprint) = (paired.clone(), peer.host_id.clone(), peer.fingerprint.clone());
+        tokio::task::spawn_blocking(move || paired.pin(&host_id, &peer_fingerprint))
+            .await
+            .map_err(std::io::Error::other)??;
+        Ok::<_, ConnectionError>(peer)
+    };
+    let event = match tokio::time::timeout_at(deadline, pairing).await {
+        Ok(Ok(peer)) => {
+            tracing::info!("Paired with {} ({})", peer.host_name, peer.fingerprint);
+            let _ = conn.close("Pairing complete").await;
+            ServerEvent::ClientPaired { addr, peer }
+        }
+        result => {
+            let reason = match result {
+                Ok(Err(ConnectionError::PairingFailed(reason))) => reason,
+                Ok(Err(e)) => e.to_string(),
+                _ => "Pairing timed out".to_string(),
+            };
+            tracing::warn!("Pairing with {} failed: {}", addr, reason);
+            ServerEvent::PairingFailed { addr, reason }
+        }
+    };
+    let _ = ctx.event_tx.send(event).await;
+}
+
+/// Handle a client connection. `permit` counts it against the cap on
+/// handshakes in progress until its handshake is done.
+async fn handle_client(
+    stream: Box<dyn Transport>,
+    addr: SocketAddr,
+    permit: OwnedSemaphorePermit,
+    ctx: ServerContext,
 ) -> Result<(), ConnectionError> {
+    // The whole handshake, TLS included, has to finish by this deadline
+    let deadline = tokio::time::Instant::now() + ctx.handshake_timeout;
+    let ip = stream.peer_ip();
+    let session_id = ctx.next_session_id.fetch_add(1, Ordering::Relaxed);
+
+    // TLS peers open with a handshake record; anything else is plaintext.
+    // Transports with their own encryption, like QUIC, skip this.
+    // WebSocket peers then open with an HTTP upgrade, native ones with a frame.
+    let stream = tokio::time::timeout_at(deadline, async {
+        let stream = match &ctx.policy.tls_acceptor {
+            Some(acceptor) if !stream.is_encrypted() => match tls::peer_starts_tls(stream).await? {
+                (true, stream) => Box::new(tls::accept_tls(acceptor, stream).await?) as Box<dyn Transport>,
+                (false, stream) => stream,
+            },
+            _ => stream,
+        };
+        if !ctx.websocket {
+            return Ok::<_, ConnectionError>(stream);
+        }
+        match websocket::peer_starts_websocket(stream).await? {
+            (true, stream) => Ok(Box::new(websocket::accept_websocket(stream).await?) as Box<dyn Transport>),
+            (false, stream) => Ok(stream),
+        }
+    })
+    .await;
+    let stream = match stream {
+        Ok(stream) => stream?,
+        Err(_) => {
+            report_handshake_timeout(&ctx.event_tx, addr, None).await;
+            return Ok(());
+        }
+    };
+
     let mut conn = Connection::new(stream, addr);
+    if ctx.udp_motion {
+        conn.allow_motion_channel();
+    }
+
+    if ctx.policy.require_tls && !conn.is_encrypted() {
+        tracing::warn!("Refusing plaintext connection from {}", addr);
+        let rejection = Rejection::new(error_codes::CONNECTION_REFUSED, "TLS required");
+        tokio::time::timeout_at(deadline, conn.reject_handshake(&ctx.screen_info, &rejection))
+            .await
+            .unwrap_or(Ok(()))?;
+        let _ = ctx.event_tx.send(ServerEvent::ClientRejected {
+            addr,
+            host_id: conn.remote_screen_info().map(|s| s.host_id.clone()),
+            code: rejection.code,
+            reason: rejection.reason,
+        }).await;
+        return Ok(());
+    }
     
-    // Perform handshake
-    conn.handshake_server(&screen_info).await?;
-    
-    let remote_screen = conn.remote_screen_info().cloned().unwrap();
-    
-    // Create message channel for this client
-    let (msg_tx, mut msg_rx) = mpsc::channel::<Message>(256);
-    let handle = ConnectionHandle::new(msg_tx);
+    // Create the send queue for this client; it is registered as soon as
+    // it is admitted
+    let (handle, mut outbox) = ConnectionHandle::new();
     
-    // Store client info
-    {
-        let mut clients = clients.write().await;
-        clients.insert(addr, ClientInfo {
-            addr,
-            screen_info: remote_screen.clone(),
-            handle: handle.clone(),
+    // Perform handshake, turning away clients that fail the access policy,
+    // duplicate a host ID or find the server full
+    let fingerprint = conn.peer_fingerprint();
+    let mut admitted: Option<(Admission, Option<String>)> = None;
+    let handshake = async {
+        let first = conn.recv().await?.ok_or_else(|| {
+            ConnectionError::HandshakeFailed("Connection closed during handshake".to_string())
+        })?;
+
+        // A client that has not paired yet opens with a pairing request
+        // instead of Hello
+        if let Message::PairRequest { screen_info, pake } = first.message {
+            return Ok(Opening::Pairing { screen_info, pake });
+        }
+
+        conn.handshake_server_from(first.message, &ctx.screen_info, |hello, resume_token| {
+            ctx.policy.admit(&PeerIdentity {
+                ip,
+                host_id: &hello.host_id,
+                fingerprint: fingerprint.as_deref(),
+            })?;
+
+            let info = ClientInfo {
+                addr,
+                session_id,
+                screen_info: hello.clone(),
+                handle: handle.clone(),
+            };
+            let (admission, grant) = ctx.register(info, resume_token, fingerprint.as_deref())?;
+            admitted = Some((admission, grant.resume_token.clone()));
+            Ok(grant)
+        })
+        .await
+        .map(|()| Opening::Session)
+    };
+    let handshake = tokio::time::timeout_at(deadline, handshake)
+        .await
+        .unwrap_or(Err(ConnectionError::Timeout));
+    if let Ok(Opening::Pairing { screen_info, pake }) = handshake {
+        pair_client(&mut conn, addr, &ctx, screen_info, &pake, deadline).await;
+        return Ok(());
+    }
+    drop(permit);
+
+    // Whatever happened, the connection taken over from is done
+    if let Some(previous) = admitted.as_ref().and_then(|(admission, _)| admission.previous()) {
+        let _ = previous.handle.send(Message::Disconnect {
+            reason: format!("Replaced by a new connection from {}", addr),
         });
     }
+
+    if let Err(ConnectionError::Rejected { code, reason }) = handshake {
+        let _ = ctx.event_tx.send(ServerEvent::ClientRejected {
+            addr,
+            host_id: conn.remote_screen_info().map(|s| s.host_id.clone()),
+            code,
+            reason,
+        }).await;
+        return Ok(());
+    }
+    if let Err(e) = handshake {
+        // Give back what admitting the client claimed
+        let host_id = conn.remote_screen_info().map(|s| s.host_id.clone());
+        if let (Some((admission, token)), Some(host_id)) = (admitted, &host_id) {
+            if let Some(token) = token {
+                ctx.sessions.close(&token);
+            }
+            if ctx.unregister(host_id, session_id, None).is_some() {
+                ctx.slots.release();
+                if let Some(previous) = admission.previous() {
+                    let _ = ctx.event_tx.send(ServerEvent::ClientDisconnected {
+                        addr: previous.addr,
+                        host_id: host_id.clone(),
+                        reason: format!("Handshake of its new connection failed: {}", e),
+                    }).await;
+                }
+            }
+        }
+
+        if let ConnectionError::Timeout = e {
+            report_handshake_timeout(&ctx.event_tx, addr, host_id).await;
+            let _ = conn.send(&Message::Error {
+                code: error_codes::HANDSHAKE_TIMEOUT,
+                message: "Handshake timed out".to_string(),
+            }).await;
+            return Ok(());
+        }
+        return Err(e);
+    }
     
-    // Notify about new connection
-    let _ = event_tx.send(ServerEvent::ClientConnected {
-        addr,
-        screen_info: remote_screen,
-    }).await;
+    let remote_screen = conn.remote_screen_info().cloned().unwrap();
+    let host_id = remote_screen.host_id.clone();
+    let resume_token = conn.session().resume_token.clone();
+    if conn.has_motion_channel() {
+        tracing::debug!("Pointer motion for {} goes over UDP", host_id);
+    }
+    let admission = admitted.map(|(admission, _)| admission).unwrap_or(Admission::New);
+    
+    // Notify about new, resumed or replacing connection
+    let event = match admission {
+        Admission::Resumed(previous) => ServerEvent::ClientResumed {
+            addr,
+            previous_addr: previous.addr,
+            screen_info: remote_screen,
+        },
+        Admission::Replaced(previous) => {
+            tracing::info!("{} ({}) replaces its connection from {}", host_id, addr, previous.addr);
+            let _ = ctx.event_tx.send(ServerEvent::ClientDisconnected {
+                addr: previous.addr,
+                host_id: host_id.clone(),
+                reason: format!("Replaced by a new connection from {}", addr),
+            }).await;
+            ServerEvent::ClientConnected {
+                addr,
+                screen_info: remote_screen,
+            }
+        }
+        Admission::New => ServerEvent::ClientConnected {
+            addr,
+            screen_info: remote_screen,
+        },
+    };
+    let _ = ctx.event_tx.send(event).await;
     
-    // Main message loop
+    // Main message loop; `clean` is set when either side says goodbye
+    let mut clean = false;
+    let mut heartbeat_timer = tokio::time::interval(ctx.heartbeat_interval);
+    let mut held = HeldInput::default();
+    let mut shutdown = ctx.shutdown.clone();
     let disconnect_reason = loop {
         tokio::select! {
             // Receive messages from the client
@@ -292,6 +1337,7 @@ async fn handle_client(
                     Ok(Some(frame)) => {
                         match &frame.message {
                             Message::Disconnect { reason } => {
+                                clean = true;
                                 break reason.clone();
                             }
                             Message::Heartbeat { timestamp } => {
@@ -300,10 +1346,17 @@ async fn handle_client(
                                     timestamp: *timestamp,
                                 }).await;
                             }
+                            Message::HeartbeatAck { timestamp } => {
+                                if let Some(rtt) = conn.heartbeat_acked(*timestamp) {
+                                    tracing::trace!("RTT to {}: {:?}", addr, rtt);
+                                }
+                                handle.update_stats(conn.stats());
+                            }
                             _ => {
                                 // Forward message to event handler
-                                let _ = event_tx.send(ServerEvent::MessageReceived {
+                                let _ = ctx.event_tx.send(ServerEvent::MessageReceived {
                                     addr,
+                                    host_id: host_id.clone(),
                                     message: frame.message,
                                 }).await;
                             }
@@ -319,28 +1372,115 @@ async fn handle_client(
             }
             
             // Send messages to the client
-            Some(message) = msg_rx.recv() => {
-                if let Err(e) = conn.send(&message).await {
+            batch = outbox.recv_batch() => {
+                let mut batch = match batch {
+                    Ok(batch) => batch,
+                    Err(e) => break format!("Link stalled: {}", e),
+                };
+                // Nothing queued after a disconnect goes out, and the client
+                // is left with nothing held down
+                let disconnect = batch
+                    .iter()
+                    .position(|m| matches!(m, Message::Disconnect { .. }))
+                    .map(|at| batch.drain(at..).next().unwrap());
+                held.track(&batch);
+                if let Some(disconnect) = &disconnect {
+                    batch.extend(held.release_all());
+                    batch.push(disconnect.clone());
+                }
+                // A write that stays stuck until the queue fills is abandoned
+                let sent = tokio::select! {
+                    sent = conn.send_batch(&batch) => sent,
+                    e = outbox.full() => break format!("Link stalled: {}", e),
+                };
+                if let Err(e) = sent {
                     break format!("Send error: {}", e);
                 }
+                if let Some(Message::Disconnect { reason }) = disconnect {
+                    clean = true;
+                    break reason;
+                }
+            }
+
+            // Say goodbye when the server stops
+            _ = shutdown.changed() => {
+                let reason = "Server shutting down".to_string();
+                let mut goodbye = held.release_all();
+                goodbye.push(Message::Disconnect { reason: reason.clone() });
+                let _ = conn.send_batch(&goodbye).await;
+                clean = true;
+                break reason;
+            }
+            
+            // Send heartbeats, dropping a client that went quiet
+            _ = heartbeat_timer.tick() => {
+                match conn.heartbeat(ctx.heartbeat_interval, ctx.missed_heartbeats).await {
+                    Ok(()) => {}
+                    Err(e @ ConnectionError::PeerTimeout { .. }) => {
+                        tracing::warn!("Client {} stopped responding: {}", addr, e);
+                        break format!("Client stopped responding: {}", e);
+                    }
+                    Err(e) => break format!("Heartbeat error: {}", e),
+                }
             }
         }
     };
     
-    // Clean up
+    // Clean up; a stalled link gets no longer than a heartbeat to say goodbye
     handle.mark_disconnected();
+    let _ = tokio::time::timeout(ctx.heartbeat_interval, conn.close("Session ended")).await;
     
-    {
-        let mut clients = clients.write().await;
-        clients.remove(&addr);
+    // Hold the session of a client that dropped without saying goodbye
+    let suspend_token = resume_token.as_deref().filter(|_| !clean);
+    if ctx.unregister(&host_id, session_id, suspend_token).is_none() {
+        // A newer connection from the same host took over
+        if let Some(token) = resume_token {
+            ctx.sessions.close(&token);
+        }
+        tracing::info!("Connection from {} ({}) was replaced: {}", host_id, addr, disconnect_reason);
+        return Ok(());
     }
     
-    let _ = event_tx.send(ServerEvent::ClientDisconnected {
-        addr,
-        reason: disconnect_reason,
-    }).await;
-    
-    let _ = conn.close("Session ended").await;
+    let ServerContext { sessions, slots, event_tx, .. } = ctx;
+    match resume_token {
+        Some(token) if !clean => {
+            tracing::info!(
+                "Holding session of {} ({}) for {:?}: {}",
+                host_id,
+                addr,
+                sessions.grace,
+                disconnect_reason
+            );
+            let _ = event_tx.send(ServerEvent::ClientSuspended {
+                addr,
+                host_id: host_id.clone(),
+                reason: disconnect_reason.clone(),
+            }).await;
+            
+            tokio::spawn(async move {
+                tokio::time::sleep(sessions.grace).await;
+                if sessions.expire(&token).is_some() {
+                    slots.release();
+                    let _ = event_tx.send(ServerEvent::ClientDisconnected {
+                        addr,
+                        host_id,
+                        reason: disconnect_reason,
+                    }).await;
+                }
+            });
+        }
+        token => {
+            if let Some(token) = token {
+                sessions.close(&token);
+            }
+            slots.release();
+            let _ = event_tx.send(ServerEvent::ClientDisconnected {
+                addr,
+                host_id,
+                reason: disconnect_reason,
+            }).await;
+        }
+    }
     
     Ok(())
 }
@@ -348,6 +1488,75 @@ async fn handle_client(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tokio::net::TcpStream;
+
+    /// Start a server on `config` and wait until it listens, returning the
+    /// addresses it bound
+    async fn start_bound(config: NetworkConfig) -> (Server, mpsc::Receiver<ServerEvent>, Vec<SocketAddr>) {
+        let info = ScreenInfo::new("server".into(), "Server".into(), 1920, 1080);
+        let mut server = Server::new(config, info);
+        let mut events = server.take_event_receiver().unwrap();
+        server.start().await.unwrap();
+        let bind_addrs = started(&mut events).await;
+        (server, events, bind_addrs)
+    }
+
+    /// Wait for the server to start, returning the addresses it bound
+    async fn started(events: &mut mpsc::Receiver<ServerEvent>) -> Vec<SocketAddr> {
+        match events.recv().await {
+            Some(ServerEvent::Started { bind_addrs }) => bind_addrs,
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    async fn start_with(config: NetworkConfig) -> (Server, mpsc::Receiver<ServerEvent>, SocketAddr) {
+        let (server, events, bind_addrs) = start_bound(config).await;
+        (server, events, SocketAddr::from(([127, 0, 0, 1], bind_addrs[0].port())))
+    }
+
+    async fn start_plain(resume_grace_ms: u64) -> (Server, mpsc::Receiver<ServerEvent>, SocketAddr) {
+        let mut config = NetworkConfig::new(0).without_tls();
+        config.resume_grace_ms = resume_grace_ms;
+        start_with(config).await
+    }
+
+    /// Next message from the server other than a heartbeat
+    async fn next_message(conn: &mut Connection) -> Message {
+        loop {
+            match conn.recv().await.unwrap().unwrap().message {
+                Message::Heartbeat { .. } => continue,
+                message => return message,
+            }
+        }
+    }
+
+    async fn dial(addr: SocketAddr, resume_token: Option<&str>) -> Connection {
+        dial_as(addr, "client", resume_token).await
+    }
+
+    async fn dial_as(addr: SocketAddr, host_id: &str, resume_token: Option<&str>) -> Connection {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut conn = Connection::new(stream, addr);
+        let info = ScreenInfo::new(host_id.into(), "Client".into(), 1280, 800);
+        conn.handshake_client_resume(&info, resume_token).await.unwrap();
+        conn
+    }
+
+    /// Handshake as `host_id`, expecting the server to turn us away
+    async fn dial_refused(addr: SocketAddr, host_id: &str) {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut conn = Connection::new(stream, addr);
+        let info = ScreenInfo::new(host_id.into(), "Client".into(), 1280, 800);
+        assert!(conn.handshake_client(&info).await.is_err());
+    }
+
+    async fn expect_rejection(events: &mut mpsc::Receiver<ServerEvent>) -> u32 {
+        let event = tokio::time::timeout(std::time::Duration::from_secs(5), events.recv()).await;
+        match event.unwrap() {
+            Some(ServerEvent::ClientRejected { code, .. }) => code,
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
 
     #[tokio::test]
     async fn test_server_creation() {
@@ -362,4 +1571,592 @@ mod tests {
         let server = Server::new(config, screen_info);
         assert!(!server.is_running().await);
     }
+
+    #[tokio::test]
+    async fn test_denied_host_is_rejected() {
+        use crate::network::{Client, ClientError};
+
+        let mut config = NetworkConfig::new(0).without_tls();
+        config.denied_hosts = vec!["127.0.0.0/8".to_string()];
+        let (_server, mut events, addr) = start_with(config).await;
+
+        let client_config = NetworkConfig::new(addr.port()).without_tls();
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let result = Client::new(client_config, info).connect(addr).await;
+        assert!(matches!(
+            result,
+            Err(ClientError::Connection(ConnectionError::Rejected {
+                code: error_codes::AUTHENTICATION_FAILED,
+                ..
+            }))
+        ));
+
+        match events.recv().await {
+            Some(ServerEvent::ClientRejected { host_id, .. }) => {
+                assert_eq!(host_id.as_deref(), Some("client"));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unpaired_client_is_rejected() {
+        use crate::network::{certificate_fingerprint, Client, ClientError, Identity, KnownHosts};
+
+        let dir = tempfile::tempdir().unwrap();
+        let paired_hosts = dir.path().join("paired_hosts");
+        let server_id = Identity::load_or_generate(&dir.path().join("server"), "server").unwrap();
+        let client_id = Identity::load_or_generate(&dir.path().join("client"), "client").unwrap();
+
+        let mut config = NetworkConfig::new(0).with_tls(
+            server_id.cert_path.to_string_lossy().to_string(),
+            server_id.key_path.to_string_lossy().to_string(),
+        );
+        config.require_pairing = true;
+        config.paired_hosts_path = Some(paired_hosts.to_string_lossy().to_string());
+        let (_server, _events, addr) = start_with(config).await;
+
+        let new_client = || {
+            let config = NetworkConfig::new(addr.port()).with_tls(
+                client_id.cert_path.to_string_lossy().to_string(),
+                client_id.key_path.to_string_lossy().to_string(),
+            );
+            let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+            Client::new(config, info).with_trust_prompt(Arc::new(|_, _| true))
+        };
+
+        let result = new_client().connect(addr).await;
+        assert!(matches!(
+            result,
+            Err(ClientError::Connection(ConnectionError::Rejected {
+                code: error_codes::AUTHENTICATION_FAILED,
+                ..
+            }))
+        ));
+
+        let fingerprint = certificate_fingerprint(&client_id.cert_path).unwrap();
+        KnownHosts::load(&paired_hosts)
+            .unwrap()
+            .pin("client", &fingerprint)
+            .unwrap();
+
+        let client = new_client();
+        client.connect(addr).await.unwrap();
+        assert!(client.is_connected().await);
+    }
+
+    #[tokio::test]
+    async fn test_client_pairs_through_running_server() {
+        use crate::network::{certificate_fingerprint, request_pairing, Client, Identity, KnownHosts};
+
+        let dir = tempfile::tempdir().unwrap();
+        let paired_hosts = dir.path().join("paired_hosts");
+        let server_id = Identity::load_or_generate(&dir.path().join("server"), "server").unwrap();
+        let client_id = Identity::load_or_generate(&dir.path().join("client"), "client").unwrap();
+
+        let mut config = NetworkConfig::new(0).with_tls(
+            server_id.cert_path.to_string_lossy().to_string(),
+            server_id.key_path.to_string_lossy().to_string(),
+        );
+        config.require_pairing = true;
+        config.paired_hosts_path = Some(paired_hosts.to_string_lossy().to_string());
+        let (server, mut events, addr) = start_with(config).await;
+
+        let mut client_config = NetworkConfig::new(addr.port()).with_tls(
+            client_id.cert_path.to_string_lossy().to_string(),
+            client_id.key_path.to_string_lossy().to_string(),
+        );
+        client_config.known_hosts_path = Some(dir.path().join("known_hosts").to_string_lossy().to_string());
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let pair = |code: &'static str| request_pairing(&client_config, addr, "localhost", &info, code);
+
+        // Nothing is armed yet, and a wrong guess spends the code
+        assert!(matches!(pair("123456").await, Err(ConnectionError::PairingFailed(_))));
+        server.allow_pairing("123456".to_string()).unwrap();
+        assert!(matches!(pair("654321").await, Err(ConnectionError::PairingFailed(_))));
+        assert!(matches!(pair("123456").await, Err(ConnectionError::PairingFailed(_))));
+        let client_fingerprint = certificate_fingerprint(&client_id.cert_path).unwrap();
+        assert_eq!(
+            KnownHosts::load(&paired_hosts).unwrap().check("client", &client_fingerprint),
+            HostTrust::Unknown
+        );
+
+        server.allow_pairing("123456".to_string()).unwrap();
+        let peer = pair("123456").await.unwrap();
+        assert_eq!(peer.fingerprint, certificate_fingerprint(&server_id.cert_path).unwrap());
+
+        let mut failures = 0;
+        let paired = loop {
+            match tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap() {
+                ServerEvent::ClientPaired { peer, .. } => break peer,
+                ServerEvent::PairingFailed { .. } => failures += 1,
+                _ => continue,
+            }
+        };
+        assert_eq!(failures, 1);
+        assert_eq!(paired.host_id, "client");
+        assert_eq!(paired.fingerprint, client_fingerprint);
+
+        let client = Client::new(client_config.clone(), info.clone())
+            .with_trust_prompt(Arc::new(|_, _| true));
+        client.connect(addr).await.unwrap();
+        assert!(client.is_connected().await);
+    }
+
+    #[tokio::test]
+    async fn test_client_connects_over_quic() {
+        use crate::network::{Client, Identity};
+
+        let dir = tempfile::tempdir().unwrap();
+        let server_id = Identity::load_or_generate(&dir.path().join("server"), "server").unwrap();
+        let mut config = NetworkConfig::new(0).with_tls(
+            server_id.cert_path.to_string_lossy().to_string(),
+            server_id.key_path.to_string_lossy().to_string(),
+        );
+        config.bind_addresses = vec!["127.0.0.1:0".to_string()];
+        config.quic = true;
+        let (_server, mut events, addr) = start_with(config).await;
+
+        let mut config = NetworkConfig::new(addr.port());
+        config.quic = true;
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let client = Client::new(config, info).with_trust_prompt(Arc::new(|_, _| true));
+        client.connect(addr).await.unwrap();
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+
+        client.send(Message::MouseMoveRelative { dx: 1, dy: 2 }).await.unwrap();
+        match events.recv().await {
+            Some(ServerEvent::MessageReceived { message, .. }) => {
+                assert!(matches!(message, Message::MouseMoveRelative { dx: 1, dy: 2 }));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_and_native_clients_share_port() {
+        use crate::network::Client;
+
+        let mut config = NetworkConfig::new(0).without_tls();
+        config.bind_addresses = vec!["127.0.0.1:0".to_string()];
+        config.websocket = true;
+        let (_server, mut events, addr) = start_with(config).await;
+
+        let mut config = NetworkConfig::new(addr.port()).without_tls();
+        config.websocket = true;
+        let info = ScreenInfo::new("web".into(), "Web".into(), 1280, 800);
+        let client = Client::new(config, info);
+        client.connect(addr).await.unwrap();
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+
+        let _native = dial(addr, None).await;
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+
+        client.send(Message::MouseMoveRelative { dx: 1, dy: 2 }).await.unwrap();
+        match events.recv().await {
+            Some(ServerEvent::MessageReceived { message, .. }) => {
+                assert!(matches!(message, Message::MouseMoveRelative { dx: 1, dy: 2 }));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dropped_client_resumes_session() {
+        let (server, mut events, addr) = start_plain(10_000).await;
+
+        let conn = dial(addr, None).await;
+        let token = conn.session().resume_token.clone().unwrap();
+        assert!(!conn.session().resumed);
+        let first_addr = match events.recv().await {
+            Some(ServerEvent::ClientConnected { addr, .. }) => addr,
+            other => panic!("unexpected event: {:?}", other),
+        };
+        drop(conn);
+
+        match events.recv().await {
+            Some(ServerEvent::ClientSuspended { addr, host_id, .. }) => {
+                assert_eq!(addr, first_addr);
+                assert_eq!(host_id, "client");
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(server.clients().await.is_empty());
+
+        let conn = dial(addr, Some(&token)).await;
+        assert!(conn.session().resumed);
+        assert_ne!(conn.session().resume_token.as_deref(), Some(token.as_str()));
+        match events.recv().await {
+            Some(ServerEvent::ClientResumed { previous_addr, .. }) => {
+                assert_eq!(previous_addr, first_addr);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert_eq!(server.clients().await.len(), 1);
+
+        // A token is only good once
+        let other = dial(addr, Some(&token)).await;
+        assert!(!other.session().resumed);
+    }
+
+    #[tokio::test]
+    async fn test_suspended_session_expires() {
+        let (_server, mut events, addr) = start_plain(50).await;
+
+        let conn = dial(addr, None).await;
+        let token = conn.session().resume_token.clone().unwrap();
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+        drop(conn);
+
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientSuspended { .. })));
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientDisconnected { .. })));
+
+        let conn = dial(addr, Some(&token)).await;
+        assert!(!conn.session().resumed);
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_is_dropped() {
+        let mut config = NetworkConfig::new(0).without_tls();
+        config.resume_grace_ms = 0;
+        config.heartbeat_interval_ms = 20;
+        config.missed_heartbeats = 2;
+        let (server, mut events, addr) = start_with(config).await;
+
+        // Completes the handshake, then never answers again
+        let _conn = dial(addr, None).await;
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+
+        let event = tokio::time::timeout(std::time::Duration::from_secs(5), events.recv()).await;
+        match event.unwrap() {
+            Some(ServerEvent::ClientDisconnected { reason, .. }) => {
+                assert!(reason.contains("stopped responding"), "{}", reason);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(server.clients().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_full_server_rejects_client() {
+        let mut config = NetworkConfig::new(0).without_tls();
+        config.max_clients = 1;
+        config.resume_grace_ms = 10_000;
+        let (_server, mut events, addr) = start_with(config).await;
+
+        let first = dial(addr, None).await;
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+
+        dial_refused(addr, "other").await;
+        assert_eq!(expect_rejection(&mut events).await, error_codes::SERVER_FULL);
+
+        // A suspended session keeps its slot and can still come back
+        let token = first.session().resume_token.clone().unwrap();
+        drop(first);
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientSuspended { .. })));
+        let first = dial(addr, Some(&token)).await;
+        assert!(first.session().resumed);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_handshake_times_out() {
+        let mut config = NetworkConfig::new(0).without_tls();
+        config.handshake_timeout_ms = 50;
+        let (server, mut events, addr) = start_with(config).await;
+
+        // Connects but never sends Hello
+        let _stalled = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(expect_rejection(&mut events).await, error_codes::HANDSHAKE_TIMEOUT);
+
+        let _conn = dial(addr, None).await;
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+        assert_eq!(server.clients().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connection_rate_is_limited() {
+        let mut config = NetworkConfig::new(0).without_tls();
+        config.max_connections_per_minute = 2;
+        let (_server, mut events, addr) = start_with(config).await;
+
+        let mut connected = Vec::new();
+        for host_id in ["first", "second"] {
+            connected.push(dial_as(addr, host_id, None).await);
+            assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+        }
+
+        dial_refused(addr, "client").await;
+        match events.recv().await {
+            Some(ServerEvent::ClientRejected { code, host_id, .. }) => {
+                assert_eq!(code, error_codes::RATE_LIMITED);
+                assert_eq!(host_id.as_deref(), Some("client"));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_takes_over_live_connection() {
+        let (server, mut events, addr) = start_plain(10_000).await;
+
+        let mut stale = dial(addr, None).await;
+        let token = stale.session().resume_token.clone().unwrap();
+        let first_addr = match events.recv().await {
+            Some(ServerEvent::ClientConnected { addr, .. }) => addr,
+            other => panic!("unexpected event: {:?}", other),
+        };
+
+        // The server hasn't noticed the old connection is gone yet
+        let conn = dial(addr, Some(&token)).await;
+        assert!(conn.session().resumed);
+        match events.recv().await {
+            Some(ServerEvent::ClientResumed { previous_addr, .. }) => {
+                assert_eq!(previous_addr, first_addr);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        let clients = server.clients().await;
+        assert_eq!(clients.len(), 1);
+        assert_ne!(clients[0].addr, first_addr);
+        assert!(matches!(next_message(&mut stale).await, Message::Disconnect { .. }));
+        server.send_to("client", Message::Heartbeat { timestamp: 1 }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_host_replaces_or_is_rejected() {
+        let (server, mut events, addr) = start_plain(0).await;
+
+        let mut first = dial(addr, None).await;
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+        let _second = dial(addr, None).await;
+        match events.recv().await {
+            Some(ServerEvent::ClientDisconnected { host_id, reason, .. }) => {
+                assert_eq!(host_id, "client");
+                assert!(reason.contains("Replaced"), "{}", reason);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+        assert_eq!(server.clients().await.len(), 1);
+        assert!(matches!(next_message(&mut first).await, Message::Disconnect { .. }));
+
+        let mut config = NetworkConfig::new(0).without_tls();
+        config.duplicate_hosts = DuplicateHostPolicy::Reject;
+        let (server, mut events, addr) = start_with(config).await;
+
+        let _first = dial(addr, None).await;
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+        dial_refused(addr, "client").await;
+        assert_eq!(expect_rejection(&mut events).await, error_codes::DUPLICATE_HOST);
+        assert_eq!(server.clients().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_accept_errors_back_off() {
+        use crate::network::transport::{Transport, TransportListener};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct FailingListener(Arc<AtomicUsize>);
+
+        #[async_trait::async_trait]
+        impl TransportListener for FailingListener {
+            async fn accept(&self) -> std::io::Result<Box<dyn Transport>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Err(std::io::Error::other("too many open files"))
+            }
+
+            fn local_addr(&self) -> std::io::Result<SocketAddr> {
+                Ok(SocketAddr::from(([127, 0, 0, 1], 24800)))
+            }
+        }
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let info = ScreenInfo::new("server".into(), "Server".into(), 1920, 1080);
+        let mut server = Server::new(NetworkConfig::new(0).without_tls(), info);
+        server.start_on(vec![Box::new(FailingListener(attempts.clone()))]).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        server.stop().await.unwrap();
+        assert!(attempts.load(Ordering::SeqCst) <= 5);
+    }
+
+        #[tokio::test]
+    async fn test_server_over_memory_transport() {
+        use crate::network::transport::memory_transport;
+        use crate::network::Client;
+
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 24800));
+        let (connector, listener) = memory_transport(server_addr);
+
+        let info = ScreenInfo::new("server".into(), "Server".into(), 1920, 1080);
+        let mut server = Server::new(NetworkConfig::new(0).without_tls(), info);
+        let mut events = server.take_event_receiver().unwrap();
+        server.start_on(vec![Box::new(listener)]).await.unwrap();
+        assert_eq!(started(&mut events).await, vec![server_addr]);
+
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let client = Client::new(NetworkConfig::new(24800).without_tls(), info)
+            .with_connector(Arc::new(connector));
+        client.connect(server_addr).await.unwrap();
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+
+        client
+            .send(Message::LeaveScreen {
+                edge: crate::protocol::ScreenEdge::Left,
+                position: 0.5,
+            })
+            .await
+            .unwrap();
+        match events.recv().await {
+            Some(ServerEvent::MessageReceived { host_id, message, .. }) => {
+                assert_eq!(host_id, "client");
+                assert!(matches!(message, Message::LeaveScreen { .. }));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_server_over_unix_socket() {
+        use crate::network::Client;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = NetworkConfig::new(0).without_tls();
+        config.socket_path = Some(dir.path().join("corenet.sock").to_string_lossy().to_string());
+
+        // Socket peers have no address, so neither address rules nor the
+        // per-address rate limit apply to them
+        let mut server_config = config.clone();
+        server_config.denied_hosts = vec!["127.0.0.0/8".to_string(), "0.0.0.0/0".to_string()];
+        server_config.max_connections_per_minute = 1;
+        let (server, mut events, bind_addrs) = start_bound(server_config).await;
+        assert!(bind_addrs.is_empty());
+
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 24800));
+        let mut clients = Vec::new();
+        for host_id in ["first", "second"] {
+            let info = ScreenInfo::new(host_id.into(), "Client".into(), 1280, 800);
+            let client = Client::new(config.clone(), info);
+            client.connect(server_addr).await.unwrap();
+            match events.recv().await {
+                Some(ServerEvent::ClientConnected { addr, screen_info }) => {
+                    assert_eq!(screen_info.host_id, host_id);
+                    assert!(addr.ip().is_unspecified());
+                }
+                other => panic!("unexpected event: {:?}", other),
+            }
+            assert_eq!(client.server_screen().await.unwrap().host_id, "server");
+            clients.push(client);
+        }
+
+        // Sessions are told apart by ID, not address
+        let connected = server.clients().await;
+        assert_eq!(connected.len(), 2);
+        assert_ne!(connected[0].session_id, connected[1].session_id);
+    }
+
+    #[tokio::test]
+    async fn test_server_dials_listening_client() {
+        use crate::network::transport::InboundConnector;
+        use crate::network::{Client, ClientEvent};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = listener.local_addr().unwrap();
+
+        let mut config = NetworkConfig::new(0).without_tls();
+        config.bind_addresses = vec!["127.0.0.1:0".to_string()];
+        config.reverse_clients = vec![client_addr.to_string()];
+        let (_server, mut events, _) = start_with(config).await;
+
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        let mut client = Client::new(NetworkConfig::new(client_addr.port()).without_tls(), info);
+        let mut client_events = client.take_event_receiver().unwrap();
+        client.listen_on(InboundConnector::new(vec![listener])).await.unwrap();
+        match client_events.recv().await {
+            Some(ClientEvent::Connected { server_screen, .. }) => {
+                assert_eq!(server_screen.host_id, "server");
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        // The dialling side still owns the keyboard
+        match events.recv().await {
+            Some(ServerEvent::ClientConnected { screen_info, .. }) => {
+                assert_eq!(screen_info.host_id, "client");
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        client.send(Message::MouseMoveRelative { dx: 3, dy: 4 }).await.unwrap();
+        match events.recv().await {
+            Some(ServerEvent::MessageReceived { message, .. }) => {
+                assert!(matches!(message, Message::MouseMoveRelative { dx: 3, dy: 4 }));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stop_releases_input_and_drains_clients() {
+        let (mut server, mut events, addr) = start_plain(10_000).await;
+        let mut conn = dial(addr, None).await;
+        assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+
+        let modifiers = Default::default();
+        let sent = [
+            Message::KeyDown { keycode: 4, character: None, modifiers },
+            Message::MouseButton { button: MouseButton::Left, pressed: true },
+            Message::KeyDown { keycode: 5, character: None, modifiers },
+            Message::KeyUp { keycode: 4, modifiers },
+        ];
+        for message in &sent {
+            server.send_to("client", message.clone()).await.unwrap();
+        }
+        for _ in &sent {
+            next_message(&mut conn).await;
+        }
+
+        server.stop().await.unwrap();
+        assert!(matches!(next_message(&mut conn).await, Message::KeyUp { keycode: 5, .. }));
+        assert!(matches!(
+            next_message(&mut conn).await,
+            Message::MouseButton { button: MouseButton::Left, pressed: false }
+        ));
+        assert!(matches!(next_message(&mut conn).await, Message::Disconnect { .. }));
+
+        // The session has ended by the time the server reports it stopped
+        assert!(matches!(
+            events.recv().await,
+            Some(ServerEvent::ClientDisconnected { reason, .. }) if reason == "Server shutting down"
+        ));
+        assert!(matches!(events.recv().await, Some(ServerEvent::Stopped)));
+        assert!(!server.is_running().await);
+    }
+
+    #[tokio::test]
+    async fn test_listeners_share_clients() {
+        let mut config = NetworkConfig::new(0).without_tls();
+        config.bind_addresses = vec!["127.0.0.1:0".to_string(), "127.0.0.1:0".to_string()];
+        let (mut server, mut events, bind_addrs) = start_bound(config).await;
+        assert_eq!(bind_addrs.len(), 2);
+
+        let _first = dial_as(bind_addrs[0], "first", None).await;
+        let _second = dial_as(bind_addrs[1], "second", None).await;
+        for _ in 0..2 {
+            assert!(matches!(events.recv().await, Some(ServerEvent::ClientConnected { .. })));
+        }
+        assert_eq!(server.clients().await.len(), 2);
+
+        server.stop().await.unwrap();
+        loop {
+            match events.recv().await {
+                Some(ServerEvent::Stopped) => break,
+                Some(_) => continue,
+                None => panic!("event stream ended before Stopped"),
+            }
+        }
+        assert!(!server.is_running().await);
+    }
 }
@@ -0,0 +1,254 @@
+//! TLS support for CoreNet connections
+//!
+//! Builds rustls client/server configurations from PEM files and layers
+//! TLS over any `Transport`.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::client::WantsClientCert;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, ConfigBuilder, RootCertStore, ServerConfig};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+use super::connection::{ConnectionError, ConnectionResult};
+use super::transport::{self, Transport};
+use super::trust::OptionalClientCert;
+
+/// First byte of a TLS handshake record, used to tell TLS peers from plaintext ones
+pub const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+/// Load all certificates from a PEM file
+pub fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificates found in {}", path.display()),
+        ));
+    }
+
+    Ok(certs)
+}
+
+/// Load the first private key from a PEM file
+pub fn load_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No private key found in {}", path.display()),
+        )
+    })
+}
+
+/// Build a TLS acceptor from a certificate chain and private key on disk.
+/// Clients may present their own certificate so they can be matched
+/// against paired hosts.
+pub fn server_acceptor(cert_path: &Path, key_path: &Path) -> ConnectionResult<TlsAcceptor> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(Arc::new(OptionalClientCert::new()))
+        .with_single_cert(certs, key)
+        .map_err(|e| ConnectionError::Tls(e.to_string()))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Finish a client configuration, presenting `identity` (certificate, key) if given
+pub fn with_client_identity(
+    builder: ConfigBuilder<ClientConfig, WantsClientCert>,
+    identity: Option<(&Path, &Path)>,
+) -> ConnectionResult<ClientConfig> {
+    match identity {
+        Some((cert_path, key_path)) => builder
+            .with_client_auth_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+            .map_err(|e| ConnectionError::Tls(e.to_string())),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Build a TLS connector that trusts the certificates in `ca_path`,
+/// presenting `identity` (certificate, key) to the server if given
+pub fn client_connector(
+    ca_path: &Path,
+    identity: Option<(&Path, &Path)>,
+) -> ConnectionResult<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots
+            .add(cert)
+            .map_err(|e| ConnectionError::Tls(e.to_string()))?;
+    }
+
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let config = with_client_identity(builder, identity)?;
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Convert a host name or address into a TLS server name
+pub fn server_name(host: &str) -> ConnectionResult<ServerName<'static>> {
+    ServerName::try_from(host.to_string())
+        .map_err(|e| ConnectionError::Tls(format!("Invalid server name '{}': {}", host, e)))
+}
+
+/// Check whether the peer opened with a TLS handshake. The byte read to
+/// tell is put back.
+pub async fn peer_starts_tls(
+    stream: Box<dyn Transport>,
+) -> io::Result<(bool, Box<dyn Transport>)> {
+    let (first, stream) = transport::sniff(stream, 1).await?;
+    Ok((first.first() == Some(&TLS_HANDSHAKE_RECORD), stream))
+}
+
+/// Perform the server side of a TLS handshake over any transport
+pub async fn accept_tls<S: Transport>(
+    acceptor: &TlsAcceptor,
+    stream: S,
+) -> ConnectionResult<server::TlsStream<S>> {
+    acceptor
+        .accept(stream)
+        .await
+        .map_err(handshake_error)
+}
+
+/// Perform the client side of a TLS handshake over any transport
+pub async fn connect_tls<S: Transport>(
+    connector: &TlsConnector,
+    name: ServerName<'static>,
+    stream: S,
+) -> ConnectionResult<client::TlsStream<S>> {
+    connector
+        .connect(name, stream)
+        .await
+        .map_err(handshake_error)
+}
+
+/// Tell a certificate that failed verification, on either side, from a
+/// handshake the link cut short
+fn handshake_error(e: io::Error) -> ConnectionError {
+    use tokio_rustls::rustls::{AlertDescription, Error};
+
+    let rejected = match e.get_ref().and_then(|inner| inner.downcast_ref::<Error>()) {
+        Some(Error::InvalidCertificate(_) | Error::NoCertificatesPresented) => true,
+        Some(Error::AlertReceived(alert)) => matches!(
+            alert,
+            AlertDescription::BadCertificate
+                | AlertDescription::UnsupportedCertificate
+                | AlertDescription::CertificateRevoked
+                | AlertDescription::CertificateExpired
+                | AlertDescription::CertificateUnknown
+                | AlertDescription::CertificateRequired
+                | AlertDescription::UnknownCA
+                | AlertDescription::AccessDenied
+        ),
+        _ => false,
+    };
+    if rejected {
+        ConnectionError::CertificateRejected(e.to_string())
+    } else {
+        ConnectionError::Tls(e.to_string())
+    }
+}
+
+/// Fingerprint of the first certificate in a peer's chain
+fn chain_fingerprint(certs: Option<&[CertificateDer<'_>]>) -> Option<String> {
+    certs.and_then(|c| c.first()).map(|c| super::trust::fingerprint(c))
+}
+
+impl<S: Transport> Transport for server::TlsStream<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.get_ref().0.peer_ip()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        true
+    }
+
+    fn peer_fingerprint(&self) -> Option<String> {
+        chain_fingerprint(self.get_ref().1.peer_certificates())
+    }
+
+    fn set_low_latency(&self) -> io::Result<()> {
+        self.get_ref().0.set_low_latency()
+    }
+}
+
+impl<S: Transport> Transport for client::TlsStream<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.get_ref().0.peer_ip()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        true
+    }
+
+    fn peer_fingerprint(&self) -> Option<String> {
+        chain_fingerprint(self.get_ref().1.peer_certificates())
+    }
+
+    fn set_low_latency(&self) -> io::Result<()> {
+        self.get_ref().0.set_low_latency()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Connection;
+    use crate::protocol::ScreenInfo;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_tls_handshake_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+        let acceptor = server_acceptor(&cert_path, &key_path).unwrap();
+        let connector = client_connector(&cert_path, None).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let (starts_tls, stream) = peer_starts_tls(Box::new(stream)).await.unwrap();
+            assert!(starts_tls);
+            let stream = accept_tls(&acceptor, stream).await.unwrap();
+            let mut conn = Connection::new(stream, peer);
+            let info = ScreenInfo::new("server".into(), "Server".into(), 1920, 1080);
+            conn.handshake_server(&info).await.unwrap();
+            conn.is_encrypted()
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream = connect_tls(&connector, server_name("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let mut conn = Connection::new(stream, addr);
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        conn.handshake_client(&info).await.unwrap();
+
+        assert!(conn.is_encrypted());
+        assert!(server.await.unwrap());
+    }
+}
@@ -0,0 +1,446 @@
+//! Transports a `Connection` can run over
+//!
+//! A transport is any ordered, reliable byte stream (TCP, a Unix domain
+//! socket, TLS layered on another transport, or an in-memory pipe in
+//! tests) together with what is known about the peer. Listeners accept
+//! transports and connectors open them, so the server and client never
+//! depend on where the bytes go.
+//!
+//! Some transports (QUIC) can also open extra streams to the same peer;
+//! they expose them through `Multiplex` so that input and bulk data don't
+//! queue behind each other.
+
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::OwnedSemaphorePermit;
+
+use super::listener;
+
+/// A byte stream to a peer, with metadata about that peer
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {
+    /// Address of the peer
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// IP address of the peer, or None if the transport has no network
+    /// address (a Unix socket). Address rules and per-address limits only
+    /// apply to peers that have one.
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|addr| addr.ip())
+    }
+
+    /// Whether traffic on this transport is encrypted
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+
+    /// Fingerprint of the certificate presented by the peer, if any
+    fn peer_fingerprint(&self) -> Option<String> {
+        None
+    }
+
+    /// Favour latency over throughput, e.g. by disabling Nagle's algorithm
+    fn set_low_latency(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Extra streams to the same peer, if the transport has them
+    fn multiplex(&self) -> Option<Arc<dyn Multiplex>> {
+        None
+    }
+
+    /// Handshake permit taken by a listener that applied the server's
+    /// `HandshakeLimits` itself (QUIC), for the server to hold on to
+    fn take_handshake_permit(&mut self) -> Option<OwnedSemaphorePermit> {
+        None
+    }
+}
+
+/// One-way stream opened alongside a transport's main stream
+pub type SendLane = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// One-way stream accepted alongside a transport's main stream
+pub type RecvLane = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Opens and accepts one-way streams to the peer of a transport, each
+/// independent of the others and of the main stream
+#[async_trait]
+pub trait Multiplex: Send + Sync {
+    /// Open a new stream to the peer
+    async fn open(&self) -> io::Result<SendLane>;
+
+    /// Wait for the next stream opened by the peer
+    async fn accept(&self) -> io::Result<RecvLane>;
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self).map(listener::canonical_peer)
+    }
+
+    fn set_low_latency(&self) -> io::Result<()> {
+        self.set_nodelay(true)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        (**self).peer_ip()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        (**self).is_encrypted()
+    }
+
+    fn peer_fingerprint(&self) -> Option<String> {
+        (**self).peer_fingerprint()
+    }
+
+    fn set_low_latency(&self) -> io::Result<()> {
+        (**self).set_low_latency()
+    }
+
+    fn multiplex(&self) -> Option<Arc<dyn Multiplex>> {
+        (**self).multiplex()
+    }
+
+    fn take_handshake_permit(&mut self) -> Option<OwnedSemaphorePermit> {
+        (**self).take_handshake_permit()
+    }
+}
+
+/// Accepts incoming transports
+#[async_trait]
+pub trait TransportListener: Send + Sync {
+    /// Wait for the next peer
+    async fn accept(&self) -> io::Result<Box<dyn Transport>>;
+
+    /// Address this listener is bound to
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Where this listener accepts peers, for logs
+    fn describe(&self) -> String {
+        match self.local_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl TransportListener for TcpListener {
+    async fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        let (stream, _) = TcpListener::accept(self).await?;
+        Ok(Box::new(stream))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+/// Opens outgoing transports
+#[async_trait]
+pub trait Connector: Send + Sync {
+    /// Open a transport to the peer at `addr`
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn Transport>>;
+}
+
+/// Connects over plain TCP
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpConnector;
+
+#[async_trait]
+impl Connector for TcpConnector {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::connect(addr).await?))
+    }
+}
+
+/// Waits for the peer to connect in instead of dialling it, for hosts
+/// that can accept connections but not make them (reverse-connect mode)
+pub struct InboundConnector {
+    listeners: Vec<TcpListener>,
+}
+
+impl InboundConnector {
+    pub fn new(listeners: Vec<TcpListener>) -> Self {
+        Self { listeners }
+    }
+
+    /// Addresses the peer can connect to
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().filter_map(|l| l.local_addr().ok()).collect()
+    }
+}
+
+#[async_trait]
+impl Connector for InboundConnector {
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<Box<dyn Transport>> {
+        let (stream, _) = listener::accept_any(&self.listeners).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// Read up to `len` bytes from the start of a transport to tell which
+/// protocol the peer speaks. The bytes are returned and also put back, so
+/// the returned transport reads from the very beginning.
+pub async fn sniff(
+    mut stream: Box<dyn Transport>,
+    len: usize,
+) -> io::Result<(Bytes, Box<dyn Transport>)> {
+    let mut prefix = vec![0u8; len];
+    let mut filled = 0;
+    while filled < len {
+        let n = stream.read(&mut prefix[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    prefix.truncate(filled);
+
+    let prefix = Bytes::from(prefix);
+    Ok((prefix.clone(), Box::new(Rewind { prefix, inner: stream })))
+}
+
+/// A transport with bytes already read from it put back in front
+struct Rewind<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S: Transport> Transport for Rewind<S> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.inner.peer_ip()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.inner.is_encrypted()
+    }
+
+    fn peer_fingerprint(&self) -> Option<String> {
+        self.inner.peer_fingerprint()
+    }
+
+    fn set_low_latency(&self) -> io::Result<()> {
+        self.inner.set_low_latency()
+    }
+
+    fn multiplex(&self) -> Option<Arc<dyn Multiplex>> {
+        self.inner.multiplex()
+    }
+
+    fn take_handshake_permit(&mut self) -> Option<OwnedSemaphorePermit> {
+        self.inner.take_handshake_permit()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.prefix.has_remaining() {
+            let n = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix[..n]);
+            this.prefix.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+pub use memory::*;
+
+/// In-memory transports over `tokio::io::duplex`, for tests
+#[cfg(test)]
+mod memory {
+    use super::*;
+    use std::sync::atomic::{AtomicU16, Ordering};
+    use tokio::io::DuplexStream;
+    use tokio::sync::{mpsc, Mutex};
+
+    /// One end of an in-memory pipe
+    pub struct MemoryStream {
+        pipe: DuplexStream,
+        peer: SocketAddr,
+    }
+
+    impl MemoryStream {
+        /// A connected pair, each end reporting the other's address
+        pub fn pair(a: SocketAddr, b: SocketAddr) -> (Self, Self) {
+            let (pa, pb) = tokio::io::duplex(64 * 1024);
+            (Self { pipe: pa, peer: b }, Self { pipe: pb, peer: a })
+        }
+    }
+
+    impl Transport for MemoryStream {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.peer)
+        }
+    }
+
+    impl AsyncRead for MemoryStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().pipe).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for MemoryStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().pipe).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().pipe).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().pipe).poll_shutdown(cx)
+        }
+    }
+
+    /// Listener end of an in-memory transport
+    pub struct MemoryListener {
+        addr: SocketAddr,
+        incoming: Mutex<mpsc::Receiver<MemoryStream>>,
+    }
+
+    #[async_trait]
+    impl TransportListener for MemoryListener {
+        async fn accept(&self) -> io::Result<Box<dyn Transport>> {
+            match self.incoming.lock().await.recv().await {
+                Some(stream) => Ok(Box::new(stream)),
+                None => Err(io::ErrorKind::NotConnected.into()),
+            }
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.addr)
+        }
+    }
+
+    /// Connector end of an in-memory transport; each connection gets its
+    /// own loopback port
+    pub struct MemoryConnector {
+        listener: mpsc::Sender<MemoryStream>,
+        next_port: AtomicU16,
+    }
+
+    #[async_trait]
+    impl Connector for MemoryConnector {
+        async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn Transport>> {
+            let port = self.next_port.fetch_add(1, Ordering::SeqCst);
+            let (local, remote) = MemoryStream::pair(SocketAddr::from(([127, 0, 0, 1], port)), addr);
+            self.listener
+                .send(remote)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+            Ok(Box::new(local))
+        }
+    }
+
+    /// A listener at `addr` and a connector that reaches it
+    pub fn memory_transport(addr: SocketAddr) -> (MemoryConnector, MemoryListener) {
+        let (tx, rx) = mpsc::channel(16);
+        let connector = MemoryConnector {
+            listener: tx,
+            next_port: AtomicU16::new(40000),
+        };
+        let listener = MemoryListener {
+            addr,
+            incoming: Mutex::new(rx),
+        };
+        (connector, listener)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Connection;
+    use crate::protocol::ScreenInfo;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_sniffed_bytes_are_replayed() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let (mut a, b) = MemoryStream::pair(addr, addr);
+        a.write_all(b"CNET and the rest").await.unwrap();
+
+        let (prefix, mut stream) = sniff(Box::new(b), 4).await.unwrap();
+        assert_eq!(&prefix[..], b"CNET");
+
+        let mut all = [0u8; 17];
+        stream.read_exact(&mut all).await.unwrap();
+        assert_eq!(&all, b"CNET and the rest");
+    }
+
+    #[tokio::test]
+    async fn test_handshake_over_memory_transport() {
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 24800));
+        let (connector, listener) = memory_transport(server_addr);
+
+        let server = tokio::spawn(async move {
+            let stream = listener.accept().await.unwrap();
+            let peer = stream.peer_addr().unwrap();
+            let mut conn = Connection::new(stream, peer);
+            let info = ScreenInfo::new("server".into(), "Server".into(), 1920, 1080);
+            conn.handshake_server(&info).await.unwrap();
+            conn.remote_screen_info().unwrap().host_id.clone()
+        });
+
+        let stream = connector.connect(server_addr).await.unwrap();
+        let mut conn = Connection::new(stream, server_addr);
+        let info = ScreenInfo::new("client".into(), "Client".into(), 1280, 800);
+        conn.handshake_client(&info).await.unwrap();
+
+        assert!(!conn.is_encrypted());
+        assert_eq!(conn.remote_screen_info().unwrap().host_id, "server");
+        assert_eq!(server.await.unwrap(), "client");
+    }
+}
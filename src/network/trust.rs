@@ -0,0 +1,387 @@
+//! Host identities and trust-on-first-use certificate pinning
+//!
+//! Every host owns a self-signed TLS identity generated on first start.
+//! Clients pin the fingerprint of each server they accept in a
+//! known-hosts file, SSH style, and refuse to talk to a server whose
+//! certificate later changes.
+
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use tokio_rustls::rustls::{
+    self, ClientConfig, DigitallySignedStruct, DistinguishedName, SignatureScheme,
+};
+use tokio_rustls::TlsConnector;
+
+use super::connection::ConnectionResult;
+
+/// File name of the identity certificate inside the config directory
+pub const IDENTITY_CERT_FILE: &str = "identity.pem";
+
+/// File name of the identity private key inside the config directory
+pub const IDENTITY_KEY_FILE: &str = "identity.key";
+
+/// File name of the pinned server fingerprints inside the config directory
+pub const KNOWN_HOSTS_FILE: &str = "known_hosts";
+
+/// Callback asked whether to trust a server seen for the first time.
+/// Receives the known-hosts key and the certificate fingerprint.
+pub type TrustPrompt = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// Compute the SHA-256 fingerprint of a DER-encoded certificate
+pub fn fingerprint(cert: &[u8]) -> String {
+    let digest = Sha256::digest(cert);
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("SHA256:{}", hex)
+}
+
+/// Compute the fingerprint of the first certificate in a PEM file
+pub fn certificate_fingerprint(path: &Path) -> io::Result<String> {
+    let certs = super::tls::load_certs(path)?;
+    Ok(fingerprint(&certs[0]))
+}
+
+/// Key under which a server is recorded in the known-hosts file
+pub fn known_host_key(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// A host's self-signed TLS identity on disk
+#[derive(Debug, Clone)]
+pub struct Identity {
+    /// PEM certificate
+    pub cert_path: PathBuf,
+    /// PEM private key
+    pub key_path: PathBuf,
+}
+
+impl Identity {
+    /// Load the identity from `dir`, generating a new one if none exists
+    pub fn load_or_generate(dir: &Path, host_name: &str) -> io::Result<Self> {
+        let cert_path = dir.join(IDENTITY_CERT_FILE);
+        let key_path = dir.join(IDENTITY_KEY_FILE);
+
+        if !cert_path.exists() || !key_path.exists() {
+            Self::generate(&cert_path, &key_path, host_name)?;
+            tracing::info!("Generated new host identity in {}", dir.display());
+        }
+
+        Ok(Self {
+            cert_path,
+            key_path,
+        })
+    }
+
+    fn generate(cert_path: &Path, key_path: &Path, host_name: &str) -> io::Result<()> {
+        let cert = rcgen::generate_simple_self_signed(vec![
+            host_name.to_string(),
+            "localhost".to_string(),
+        ])
+        .map_err(io::Error::other)?;
+
+        if let Some(parent) = cert_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::fs::write(cert_path, cert.cert.pem())?;
+        write_private(key_path, cert.key_pair.serialize_pem().as_bytes())?;
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    std::fs::write(path, contents)
+}
+
+/// Result of checking a fingerprint against the known-hosts file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostTrust {
+    /// Fingerprint matches the pinned one
+    Trusted,
+    /// Host has never been seen
+    Unknown,
+    /// Host is pinned to a different fingerprint
+    Mismatch { expected: String },
+}
+
+/// Pinned fingerprints, one `<host> <fingerprint>` per line. Clients use it
+/// for known servers, servers for paired clients (keyed by host ID).
+#[derive(Debug, Clone, Default)]
+pub struct KnownHosts {
+    path: Option<PathBuf>,
+    entries: BTreeMap<String, String>,
+}
+
+impl KnownHosts {
+    /// A store that is never written to disk
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Load the store from `path`; a missing file is treated as empty
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut entries = BTreeMap::new();
+
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    let mut parts = line.split_whitespace();
+                    if let (Some(host), Some(fp)) = (parts.next(), parts.next()) {
+                        entries.insert(host.to_string(), fp.to_string());
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            entries,
+        })
+    }
+
+    /// Check a host's fingerprint against the pinned one
+    pub fn check(&self, host: &str, fingerprint: &str) -> HostTrust {
+        match self.entries.get(host) {
+            Some(expected) if expected == fingerprint => HostTrust::Trusted,
+            Some(expected) => HostTrust::Mismatch {
+                expected: expected.clone(),
+            },
+            None => HostTrust::Unknown,
+        }
+    }
+
+    /// Pin a fingerprint for a host and persist the store
+    pub fn pin(&mut self, host: &str, fingerprint: &str) -> io::Result<()> {
+        self.entries.insert(host.to_string(), fingerprint.to_string());
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut contents = String::new();
+        for (host, fp) in &self.entries {
+            contents.push_str(host);
+            contents.push(' ');
+            contents.push_str(fp);
+            contents.push('\n');
+        }
+
+        std::fs::write(path, contents)
+    }
+}
+
+/// Accepts any server certificate while still checking handshake signatures,
+/// so the peer must hold the private key. The certificate itself is checked
+/// against the known-hosts file once the handshake completes.
+#[derive(Debug)]
+struct PinningVerifier {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Build a TLS connector that defers server trust to fingerprint pinning,
+/// presenting `identity` (certificate, key) to the server if given
+pub fn pinning_connector(identity: Option<(&Path, &Path)>) -> ConnectionResult<TlsConnector> {
+    let verifier = PinningVerifier {
+        provider: Arc::new(crypto::ring::default_provider()),
+    };
+
+    let builder = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let config = super::tls::with_client_identity(builder, identity)?;
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Lets clients present any certificate (or none). Signatures are still
+/// checked; whether the certificate belongs to a paired host is decided by
+/// the server after the handshake.
+#[derive(Debug)]
+pub(super) struct OptionalClientCert {
+    provider: Arc<CryptoProvider>,
+}
+
+impl OptionalClientCert {
+    pub(super) fn new() -> Self {
+        Self {
+            provider: Arc::new(crypto::ring::default_provider()),
+        }
+    }
+}
+
+impl ClientCertVerifier for OptionalClientCert {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_hosts_pin_and_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KNOWN_HOSTS_FILE);
+
+        let mut hosts = KnownHosts::load(&path).unwrap();
+        assert_eq!(hosts.check("10.0.0.2:24800", "SHA256:aa"), HostTrust::Unknown);
+
+        hosts.pin("10.0.0.2:24800", "SHA256:aa").unwrap();
+
+        let hosts = KnownHosts::load(&path).unwrap();
+        assert_eq!(hosts.check("10.0.0.2:24800", "SHA256:aa"), HostTrust::Trusted);
+        assert_eq!(
+            hosts.check("10.0.0.2:24800", "SHA256:bb"),
+            HostTrust::Mismatch {
+                expected: "SHA256:aa".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_identity_is_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = Identity::load_or_generate(dir.path(), "test-host").unwrap();
+        let first = certificate_fingerprint(&first.cert_path).unwrap();
+        let second = Identity::load_or_generate(dir.path(), "test-host").unwrap();
+        let second = certificate_fingerprint(&second.cert_path).unwrap();
+        assert_eq!(first, second);
+        assert!(first.starts_with("SHA256:"));
+    }
+}
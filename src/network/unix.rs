@@ -0,0 +1,177 @@
+//! Unix domain socket transport
+//!
+//! For peers on the same machine, or VMs that share a socket with their
+//! host. Socket peers have no network address: accepted peers report the
+//! unspecified address and no `peer_ip`, so address rules and per-address
+//! limits pass them by.
+
+use async_trait::async_trait;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{UnixListener, UnixStream};
+
+use super::transport::{Connector, Transport, TransportListener};
+
+/// A connected Unix domain socket
+pub struct UnixTransport {
+    stream: UnixStream,
+    /// Address the connector was asked for, or unspecified on the
+    /// accepting side
+    peer: SocketAddr,
+}
+
+impl Transport for UnixTransport {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+impl AsyncRead for UnixTransport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixTransport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Listens on a Unix domain socket path, removing the socket file when dropped
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocketListener {
+    /// Bind to `path`. A socket file left behind by a server that is no
+    /// longer running is replaced; one that still accepts connections is not.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use", path.display()),
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+#[async_trait]
+impl TransportListener for UnixSocketListener {
+    async fn accept(&self) -> io::Result<Box<dyn Transport>> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(Box::new(UnixTransport {
+            stream,
+            peer: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        }))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix domain sockets have no network address",
+        ))
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Connects to a Unix domain socket path, whatever address it is asked for
+#[derive(Debug, Clone)]
+pub struct UnixConnector {
+    path: PathBuf,
+}
+
+impl UnixConnector {
+    /// Connect to the socket at `path`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl Connector for UnixConnector {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(UnixTransport {
+            stream: UnixStream::connect(&self.path).await?,
+            peer: addr,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_peers_have_no_address() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("corenet.sock");
+        let listener = UnixSocketListener::bind(&path).unwrap();
+        let connector = UnixConnector::new(&path);
+        let server_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 24800));
+
+        let client = connector.connect(server_addr).await.unwrap();
+        assert_eq!(client.peer_addr().unwrap(), server_addr);
+        assert_eq!(client.peer_ip(), None);
+
+        let peer = listener.accept().await.unwrap();
+        assert!(peer.peer_addr().unwrap().ip().is_unspecified());
+        assert_eq!(peer.peer_ip(), None);
+
+        // A live socket is not taken over, a stale one is
+        assert!(UnixSocketListener::bind(&path).is_err());
+        drop(listener);
+        assert!(!path.exists());
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+        assert!(UnixSocketListener::bind(&path).is_ok());
+    }
+}
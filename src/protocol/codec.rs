@@ -1,15 +1,23 @@
 //! Protocol codec for encoding/decoding messages
 //!
-//! Handles serialization and framing of protocol messages.
+//! Handles serialization and framing of protocol messages. Input messages
+//! use the layout in `compact` when the session's protocol version has
+//! it; everything else is bincode.
 
+use bincode::Options;
 use bytes::{Buf, BufMut, BytesMut};
 use std::io;
 use thiserror::Error;
 
-use super::{Message, MAGIC_BYTES};
+use super::compact::{self, COMPACT_INPUT_VERSION};
+use super::{Message, MessageType, MAGIC_BYTES, PROTOCOL_VERSION};
 
 /// Maximum message size (10 MB)
-const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+pub const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
+/// Maximum message size until the handshake is done. Nothing exchanged
+/// before then comes close, and the peer hasn't been checked yet.
+pub const MAX_HANDSHAKE_MESSAGE_SIZE: usize = 16 * 1024;
 
 /// Header size: magic(4) + type(1) + length(4) + sequence(4) = 13 bytes
 const HEADER_SIZE: usize = 13;
@@ -31,6 +39,12 @@ pub enum CodecError {
     
     #[error("Incomplete message")]
     Incomplete,
+    
+    #[error("Malformed message: {0}")]
+    Malformed(&'static str),
+    
+    #[error("Header says message type {header:#04x} but the payload is type {payload:#04x}")]
+    TypeMismatch { header: u8, payload: u8 },
 }
 
 /// Message frame with metadata
@@ -51,30 +65,47 @@ impl Frame {
 /// Encodes messages into the wire format
 pub struct Encoder {
     sequence: u32,
+    /// Write input messages in the compact layout
+    compact_input: bool,
 }
 
 impl Encoder {
+    /// An encoder for the newest protocol version
     pub fn new() -> Self {
-        Self { sequence: 0 }
+        Self {
+            sequence: 0,
+            compact_input: true,
+        }
+    }
+
+    /// Encode for the protocol version agreed with the peer
+    pub fn set_protocol_version(&mut self, version: u32) {
+        self.compact_input = version >= COMPACT_INPUT_VERSION;
     }
 
     /// Encode a message into a buffer
     pub fn encode(&mut self, message: &Message, buf: &mut BytesMut) -> Result<(), CodecError> {
-        // Serialize the message payload
-        let payload = bincode::serialize(message)?;
-        
-        if payload.len() > MAX_MESSAGE_SIZE {
-            return Err(CodecError::MessageTooLarge(payload.len(), MAX_MESSAGE_SIZE));
-        }
-
-        // Write header
+        // Write the header, then the payload straight after it, filling in
+        // the length once it is known
+        let start = buf.len();
         buf.put_slice(&MAGIC_BYTES);
         buf.put_u8(message.type_id());
-        buf.put_u32(payload.len() as u32);
+        buf.put_u32(0);
         buf.put_u32(self.sequence);
-        
-        // Write payload
-        buf.put_slice(&payload);
+
+        if !(self.compact_input && compact::encode(message, buf)) {
+            if let Err(e) = bincode::serialize_into((&mut *buf).writer(), message) {
+                buf.truncate(start);
+                return Err(e.into());
+            }
+        }
+
+        let length = buf.len() - start - HEADER_SIZE;
+        if length > MAX_MESSAGE_SIZE {
+            buf.truncate(start);
+            return Err(CodecError::MessageTooLarge(length, MAX_MESSAGE_SIZE));
+        }
+        buf[start + 5..start + 9].copy_from_slice(&(length as u32).to_be_bytes());
         
         self.sequence = self.sequence.wrapping_add(1);
         Ok(())
@@ -94,9 +125,18 @@ impl Default for Encoder {
     }
 }
 
-/// Decodes messages from the wire format
+/// Decodes messages from the wire format. The header's type byte decides
+/// what happens to a payload: types this build doesn't know, sent by a
+/// newer peer, are skipped whole and counted, so the connection survives
+/// them.
 pub struct Decoder {
     state: DecodeState,
+    /// Largest payload accepted, whatever the type
+    max_size: usize,
+    /// Read input messages in the compact layout
+    compact_input: bool,
+    /// Frames of unknown type skipped so far
+    skipped: u64,
 }
 
 #[derive(Default)]
@@ -111,10 +151,36 @@ enum DecodeState {
 }
 
 impl Decoder {
+    /// A decoder for the newest protocol version
     pub fn new() -> Self {
-        Self {
+        Self::for_version(PROTOCOL_VERSION)
+    }
+
+    /// A decoder for the protocol version agreed with the peer
+    pub fn for_version(version: u32) -> Self {
+        let mut decoder = Self {
             state: DecodeState::Header,
-        }
+            max_size: MAX_MESSAGE_SIZE,
+            compact_input: true,
+            skipped: 0,
+        };
+        decoder.set_protocol_version(version);
+        decoder
+    }
+
+    /// Decode for the protocol version agreed with the peer
+    pub fn set_protocol_version(&mut self, version: u32) {
+        self.compact_input = version >= COMPACT_INPUT_VERSION;
+    }
+
+    /// Refuse payloads over `max_size` bytes, below the per-type limits
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size.min(MAX_MESSAGE_SIZE);
+    }
+
+    /// Number of frames of unknown type skipped
+    pub fn skipped(&self) -> u64 {
+        self.skipped
     }
 
     /// Attempt to decode a frame from the buffer
@@ -136,8 +202,10 @@ impl Decoder {
                     let length = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) as usize;
                     let sequence = u32::from_be_bytes([buf[9], buf[10], buf[11], buf[12]]);
 
-                    if length > MAX_MESSAGE_SIZE {
-                        return Err(CodecError::MessageTooLarge(length, MAX_MESSAGE_SIZE));
+                    // Checked before any of the payload is buffered
+                    let limit = max_payload_size(message_type).min(self.max_size);
+                    if length > limit {
+                        return Err(CodecError::MessageTooLarge(length, limit));
                     }
 
                     buf.advance(HEADER_SIZE);
@@ -148,16 +216,43 @@ impl Decoder {
                         sequence,
                     };
                 }
-                DecodeState::Payload { message_type: _, length, sequence } => {
+                DecodeState::Payload { message_type, length, sequence } => {
                     if buf.len() < *length {
                         return Ok(None);
                     }
 
+                    let (message_type, seq) = (*message_type, *sequence);
                     let payload = buf.split_to(*length);
-                    let message: Message = bincode::deserialize(&payload)?;
-                    let seq = *sequence;
-                    
                     self.state = DecodeState::Header;
+
+                    if !Message::is_known_type(message_type) {
+                        self.skipped += 1;
+                        tracing::warn!(
+                            "Skipped message of unknown type {:#04x} ({} bytes, {} skipped so far)",
+                            message_type,
+                            payload.len(),
+                            self.skipped
+                        );
+                        continue;
+                    }
+
+                    if self.compact_input && compact::is_compact_type(message_type) {
+                        let message = compact::decode(message_type, &payload)?;
+                        return Ok(Some(Frame::new(seq, message)));
+                    }
+
+                    // Lengths inside the payload can't claim more than it holds
+                    let message: Message = bincode::options()
+                        .with_fixint_encoding()
+                        .allow_trailing_bytes()
+                        .with_limit(payload.len() as u64)
+                        .deserialize(&payload)?;
+                    if message.type_id() != message_type {
+                        return Err(CodecError::TypeMismatch {
+                            header: message_type,
+                            payload: message.type_id(),
+                        });
+                    }
                     
                     return Ok(Some(Frame::new(seq, message)));
                 }
@@ -166,6 +261,12 @@ impl Decoder {
     }
 }
 
+/// Largest payload accepted for messages of `type_id`. Types this build
+/// doesn't know may be as large as any frame; they are skipped unread.
+fn max_payload_size(type_id: u8) -> usize {
+    MessageType::get(type_id).map_or(MAX_MESSAGE_SIZE, |ty| ty.max_payload)
+}
+
 impl Default for Decoder {
     fn default() -> Self {
         Self::new()
@@ -217,4 +318,136 @@ mod tests {
             assert_eq!(frame.sequence, i as u32);
         }
     }
+
+    #[test]
+    fn test_input_layout_follows_version() {
+        let motion = Message::MouseMoveRelative { dx: 3, dy: -2 };
+        for version in [COMPACT_INPUT_VERSION - 1, COMPACT_INPUT_VERSION] {
+            let mut encoder = Encoder::new();
+            encoder.set_protocol_version(version);
+            let mut decoder = Decoder::for_version(version);
+            let mut buf = BytesMut::new();
+            encoder.encode(&motion, &mut buf).unwrap();
+            encoder.encode(&Message::Heartbeat { timestamp: 1 }, &mut buf).unwrap();
+
+            let compact = version >= COMPACT_INPUT_VERSION;
+            let length = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]);
+            assert_eq!(length, if compact { 2 } else { 12 });
+
+            let frame = decoder.decode(&mut buf).unwrap().unwrap();
+            assert!(matches!(frame.message, Message::MouseMoveRelative { dx: 3, dy: -2 }));
+            let frame = decoder.decode(&mut buf).unwrap().unwrap();
+            assert!(matches!(frame.message, Message::Heartbeat { timestamp: 1 }));
+        }
+    }
+
+    #[test]
+    fn test_oversized_frames_are_refused_from_the_header() {
+        let header = |message_type: u8, length: u32| {
+            let mut buf = BytesMut::new();
+            buf.put_slice(&MAGIC_BYTES);
+            buf.put_u8(message_type);
+            buf.put_u32(length);
+            buf.put_u32(0);
+            buf
+        };
+
+        // A heartbeat never needs a megabyte
+        let mut decoder = Decoder::new();
+        let result = decoder.decode(&mut header(0xF0, 1 << 20));
+        assert!(matches!(result, Err(CodecError::MessageTooLarge(_, 32))));
+
+        // Clipboard data may be large, but not before the handshake
+        let mut decoder = Decoder::new();
+        assert!(decoder.decode(&mut header(0x40, 1 << 20)).unwrap().is_none());
+        decoder = Decoder::new();
+        decoder.set_max_size(MAX_HANDSHAKE_MESSAGE_SIZE);
+        let result = decoder.decode(&mut header(0x40, 1 << 20));
+        assert!(matches!(result, Err(CodecError::MessageTooLarge(_, MAX_HANDSHAKE_MESSAGE_SIZE))));
+    }
+
+    #[test]
+    fn test_payload_lengths_are_bounded_by_the_frame() {
+        let mut buf = BytesMut::new();
+        Encoder::new().encode(&Message::Disconnect { reason: "bye".into() }, &mut buf).unwrap();
+        // The string's length follows the 4-byte variant tag
+        let at = HEADER_SIZE + 4;
+        buf[at..at + 8].copy_from_slice(&(1u64 << 40).to_le_bytes());
+
+        let result = Decoder::new().decode(&mut buf);
+        assert!(matches!(result, Err(CodecError::Serialization(_))));
+    }
+
+    #[test]
+    fn test_decoder_survives_corrupted_input() {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x434e4554);
+        let messages = [
+            Message::MouseMoveRelative { dx: -300, dy: 7 },
+            Message::KeyDown { keycode: 0xE0, character: Some('x'), modifiers: Default::default() },
+            Message::Disconnect { reason: "done".into() },
+            Message::ClipboardData { mime_type: "text/plain".into(), data: vec![1; 40] },
+        ];
+        let mut valid = BytesMut::new();
+        let mut encoder = Encoder::new();
+        for message in &messages {
+            encoder.encode(message, &mut valid).unwrap();
+        }
+
+        for _ in 0..20_000 {
+            let mut buf = valid.clone();
+            for _ in 0..rng.gen_range(1..8) {
+                let at = rng.gen_range(0..buf.len());
+                buf[at] = rng.gen();
+            }
+            buf.truncate(rng.gen_range(0..=buf.len()));
+
+            // Any outcome but a panic or an unbounded allocation will do
+            let mut decoder = Decoder::new();
+            while let Ok(Some(_)) = decoder.decode(&mut buf) {}
+        }
+    }
+
+    /// A frame with the given header type around a real message's payload
+    fn frame_with_type(message_type: u8, message: &Message) -> BytesMut {
+        let mut buf = BytesMut::new();
+        Encoder::new().encode(message, &mut buf).unwrap();
+        buf[4] = message_type;
+        buf
+    }
+
+    #[test]
+    fn test_unknown_types_are_skipped() {
+        let mut decoder = Decoder::new();
+        let heartbeat = Message::Heartbeat { timestamp: 7 };
+        assert!(!Message::is_known_type(0xA0));
+
+        // Split across reads, an unknown frame waits for its whole payload
+        let mut buf = frame_with_type(0xA0, &Message::Disconnect { reason: "x".repeat(64) });
+        let rest = buf.split_off(HEADER_SIZE + 10);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.unsplit(rest);
+        Encoder::new().encode(&heartbeat, &mut buf).unwrap();
+
+        let frame = decoder.decode(&mut buf).unwrap().unwrap();
+        assert!(matches!(frame.message, Message::Heartbeat { timestamp: 7 }));
+        assert_eq!(decoder.skipped(), 1);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_header_type_must_match_payload() {
+        let mut decoder = Decoder::new();
+        let goodbye = Message::Disconnect { reason: "bye".into() };
+        let mut buf = frame_with_type(Message::Heartbeat { timestamp: 0 }.type_id(), &goodbye);
+
+        match decoder.decode(&mut buf) {
+            Err(CodecError::TypeMismatch { header, payload }) => {
+                assert_eq!(header, 0xF0);
+                assert_eq!(payload, goodbye.type_id());
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
 }
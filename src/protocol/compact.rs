@@ -0,0 +1,197 @@
+//! Compact wire layout for input messages
+//!
+//! From protocol version 5 (`COMPACT_INPUT_VERSION`), the high-frequency
+//! input messages are written in the fixed layout below instead of as
+//! bincode. The header's type byte says which layout follows, so the
+//! payload carries no enum tag and doesn't depend on the order of
+//! `Message` variants. Everything else stays bincode.
+//!
+//! Integers are LEB128 varints: unsigned as is, signed zigzag-encoded
+//! first, so small deltas of either sign take one byte.
+//!
+//! | Type | Message             | Payload                                      |
+//! |------|---------------------|----------------------------------------------|
+//! | 0x10 | `MouseMoveRelative` | dx: sint, dy: sint                           |
+//! | 0x11 | `MouseMoveAbsolute` | x: sint, y: sint                             |
+//! | 0x12 | `MouseButton`       | u8: button in bits 0-6, bit 7 set if pressed |
+//! | 0x13 | `MouseScroll`       | dx: sint, dy: sint                           |
+//! | 0x20 | `KeyDown`           | keycode: uint, modifiers: u8, char: uint     |
+//! | 0x21 | `KeyUp`             | keycode: uint, modifiers: u8                 |
+//!
+//! Buttons are numbered as in `MouseButton`, and modifiers are packed
+//! with `Modifiers::to_bits`. `char` is 0 for none, otherwise the
+//! character's code point plus one. Bytes after the last field are
+//! ignored, so later versions can append fields.
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use super::{CodecError, Message, MessageType, Modifiers, MouseButton};
+
+/// First protocol version that uses the compact layout
+pub const COMPACT_INPUT_VERSION: u32 = 5;
+
+/// Longest varint for a u32
+const MAX_VARINT_LEN: usize = 5;
+
+/// Whether messages of `type_id` use the compact layout
+pub fn is_compact_type(type_id: u8) -> bool {
+    MessageType::get(type_id).is_some_and(|ty| ty.compact)
+}
+
+/// Append the compact payload for `message`. Returns false, writing
+/// nothing, if it isn't an input message.
+pub fn encode(message: &Message, buf: &mut BytesMut) -> bool {
+    match message {
+        Message::MouseMoveRelative { dx: a, dy: b }
+        | Message::MouseMoveAbsolute { x: a, y: b }
+        | Message::MouseScroll { dx: a, dy: b } => {
+            put_sint(buf, *a);
+            put_sint(buf, *b);
+        }
+        Message::MouseButton { button, pressed } => {
+            buf.put_u8(*button as u8 | if *pressed { 0x80 } else { 0 });
+        }
+        Message::KeyDown { keycode, character, modifiers } => {
+            put_uint(buf, *keycode);
+            buf.put_u8(modifiers.to_bits());
+            put_uint(buf, character.map_or(0, |c| c as u32 + 1));
+        }
+        Message::KeyUp { keycode, modifiers } => {
+            put_uint(buf, *keycode);
+            buf.put_u8(modifiers.to_bits());
+        }
+        _ => return false,
+    }
+    true
+}
+
+/// Decode the compact payload of a message of `type_id`
+pub fn decode(type_id: u8, mut payload: &[u8]) -> Result<Message, CodecError> {
+    use super::message_types::*;
+
+    let buf = &mut payload;
+    let message = match type_id {
+        MOUSE_MOVE_RELATIVE => Message::MouseMoveRelative { dx: get_sint(buf)?, dy: get_sint(buf)? },
+        MOUSE_MOVE_ABSOLUTE => Message::MouseMoveAbsolute { x: get_sint(buf)?, y: get_sint(buf)? },
+        MOUSE_BUTTON => {
+            let bits = get_u8(buf)?;
+            Message::MouseButton {
+                button: button_from_u8(bits & 0x7F)?,
+                pressed: bits & 0x80 != 0,
+            }
+        }
+        MOUSE_SCROLL => Message::MouseScroll { dx: get_sint(buf)?, dy: get_sint(buf)? },
+        KEY_DOWN => {
+            let keycode = get_uint(buf)?;
+            let modifiers = Modifiers::from_bits(get_u8(buf)?);
+            let character = match get_uint(buf)? {
+                0 => None,
+                c => Some(char::from_u32(c - 1).ok_or(CodecError::Malformed("character"))?),
+            };
+            Message::KeyDown { keycode, character, modifiers }
+        }
+        KEY_UP => Message::KeyUp {
+            keycode: get_uint(buf)?,
+            modifiers: Modifiers::from_bits(get_u8(buf)?),
+        },
+        _ => return Err(CodecError::Malformed("input message type")),
+    };
+    Ok(message)
+}
+
+fn button_from_u8(value: u8) -> Result<MouseButton, CodecError> {
+    Ok(match value {
+        0 => MouseButton::Left,
+        1 => MouseButton::Right,
+        2 => MouseButton::Middle,
+        3 => MouseButton::Button4,
+        4 => MouseButton::Button5,
+        _ => return Err(CodecError::Malformed("mouse button")),
+    })
+}
+
+fn put_uint(buf: &mut BytesMut, mut value: u32) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn put_sint(buf: &mut BytesMut, value: i32) {
+    put_uint(buf, ((value << 1) ^ (value >> 31)) as u32);
+}
+
+fn get_u8(buf: &mut &[u8]) -> Result<u8, CodecError> {
+    if !buf.has_remaining() {
+        return Err(CodecError::Malformed("truncated input message"));
+    }
+    Ok(buf.get_u8())
+}
+
+fn get_uint(buf: &mut &[u8]) -> Result<u32, CodecError> {
+    let mut value = 0u64;
+    for i in 0..MAX_VARINT_LEN {
+        let byte = get_u8(buf)?;
+        value |= u64::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return u32::try_from(value).map_err(|_| CodecError::Malformed("varint"));
+        }
+    }
+    Err(CodecError::Malformed("varint"))
+}
+
+fn get_sint(buf: &mut &[u8]) -> Result<i32, CodecError> {
+    let value = get_uint(buf)?;
+    Ok((value >> 1) as i32 ^ -((value & 1) as i32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(message: &Message) -> (Message, usize) {
+        let mut buf = BytesMut::new();
+        assert!(encode(message, &mut buf));
+        (decode(message.type_id(), &buf).unwrap(), buf.len())
+    }
+
+    #[test]
+    fn test_input_roundtrip() {
+        let modifiers = Modifiers { shift: true, meta: true, ..Default::default() };
+        let messages = [
+            Message::MouseMoveRelative { dx: -1, dy: 1 },
+            Message::MouseMoveRelative { dx: i32::MIN, dy: i32::MAX },
+            Message::MouseMoveAbsolute { x: 2560, y: 0 },
+            Message::MouseButton { button: MouseButton::Button5, pressed: true },
+            Message::MouseScroll { dx: 0, dy: -120 },
+            Message::KeyDown { keycode: 0x04, character: Some('é'), modifiers },
+            Message::KeyDown { keycode: u32::MAX, character: None, modifiers },
+            Message::KeyUp { keycode: 0xE1, modifiers },
+        ];
+        for message in &messages {
+            let (decoded, _) = roundtrip(message);
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+        }
+
+        // Small motion fits in a byte per axis
+        assert_eq!(roundtrip(&messages[0]).1, 2);
+        assert_eq!(roundtrip(&messages[3]).1, 1);
+        assert!(!encode(&Message::Heartbeat { timestamp: 0 }, &mut BytesMut::new()));
+    }
+
+    #[test]
+    fn test_malformed_payloads() {
+        // Truncated, overlong varint, unknown button, invalid character
+        assert!(decode(0x10, &[0x02]).is_err());
+        assert!(decode(0x21, &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01, 0x00]).is_err());
+        assert!(decode(0x12, &[0x05]).is_err());
+        assert!(decode(0x20, &[0x04, 0x00, 0x81, 0xB0, 0x03]).is_err());
+
+        // Trailing bytes from a later version are ignored
+        assert!(matches!(
+            decode(0x10, &[0x03, 0x04, 0xAA]),
+            Ok(Message::MouseMoveRelative { dx: -2, dy: 2 })
+        ));
+    }
+}
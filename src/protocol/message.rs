@@ -3,6 +3,10 @@
 //! Defines all message types used for communication between CoreNet hosts.
 
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+
+use super::MAX_MESSAGE_SIZE;
 
 /// Mouse button identifiers
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -90,21 +94,152 @@ impl ScreenInfo {
     }
 }
 
+/// UDP channel for pointer motion, granted by the server in HelloAck
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MotionGrant {
+    /// Server's UDP port for the channel
+    pub port: u16,
+    /// Key authenticating datagrams in both directions
+    pub key: Vec<u8>,
+}
+
+/// Protocol versions a host can speak, both ends inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl VersionRange {
+    /// The versions this build speaks
+    pub const SUPPORTED: Self = Self {
+        min: super::MIN_PROTOCOL_VERSION,
+        max: super::PROTOCOL_VERSION,
+    };
+
+    /// Highest version in both ranges, if they overlap
+    pub fn highest_common(&self, other: &VersionRange) -> Option<u32> {
+        let highest = self.max.min(other.max);
+        (highest >= self.min.max(other.min)).then_some(highest)
+    }
+
+    pub fn contains(&self, version: u32) -> bool {
+        (self.min..=self.max).contains(&version)
+    }
+}
+
+impl fmt::Display for VersionRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}-{}", self.min, self.max)
+        }
+    }
+}
+
+/// Optional protocol features, offered in Hello and agreed in HelloAck.
+/// A session uses the features both ends offered; bits a host doesn't
+/// know are never agreed, so new features can be added without a version
+/// bump.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// Clipboard sharing
+    pub const CLIPBOARD: Self = Self(0x01);
+    /// Fractional scroll deltas
+    pub const HIRES_SCROLL: Self = Self(0x02);
+    /// Pointer motion over a UDP side channel
+    pub const UDP_MOTION: Self = Self(0x04);
+    /// Compressed payloads
+    pub const COMPRESSION: Self = Self(0x08);
+
+    /// The features this build implements
+    pub const SUPPORTED: Self = Self(Self::CLIPBOARD.0 | Self::UDP_MOTION.0);
+
+    const NAMES: [(Self, &'static str); 4] = [
+        (Self::CLIPBOARD, "clipboard"),
+        (Self::HIRES_SCROLL, "hires-scroll"),
+        (Self::UDP_MOTION, "udp-motion"),
+        (Self::COMPRESSION, "compression"),
+    ];
+
+    pub fn contains(&self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Features in both sets
+    pub fn intersection(&self, other: Features) -> Features {
+        Self(self.0 & other.0)
+    }
+
+    pub fn remove(&mut self, other: Features) {
+        self.0 &= !other.0;
+    }
+}
+
+impl fmt::Display for Features {
+    /// Names of the known features in the set, comma separated
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = Self::NAMES
+            .iter()
+            .filter(|(feature, _)| self.contains(*feature))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// Which side of a session registers with a relay
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RelayRole {
+    /// Offers a connection for a client to be joined to
+    Server,
+    /// Asks to be joined to a waiting server
+    Client,
+}
+
 /// All possible protocol messages
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
-    /// Protocol handshake - sent on connection establishment
+    /// Protocol handshake - sent on connection establishment. Hello and
+    /// HelloAck must decode the same way in every version in range, so
+    /// that peers can always negotiate.
     Hello {
-        protocol_version: u32,
+        /// Versions the client speaks
+        versions: VersionRange,
+        /// Features the client offers
+        features: Features,
         screen_info: ScreenInfo,
+        /// Token from a previous HelloAck, to resume that session
+        resume_token: Option<String>,
+        /// Client's UDP port, if it would like pointer motion over UDP
+        motion_port: Option<u16>,
     },
 
     /// Acknowledgment of Hello
     HelloAck {
+        /// Version the session uses: the highest both ends speak. When
+        /// the client is turned away, the highest the server speaks.
         protocol_version: u32,
+        /// Features the session uses, out of those the client offered
+        features: Features,
         screen_info: ScreenInfo,
         accepted: bool,
         reason: Option<String>,
+        /// Token the client can present to resume this session after a drop
+        resume_token: Option<String>,
+        /// Whether the session named in Hello was resumed
+        resumed: bool,
+        /// UDP channel for pointer motion, if the client asked and the
+        /// server agreed
+        motion: Option<MotionGrant>,
     },
 
     /// Relative mouse movement
@@ -200,33 +335,88 @@ pub enum Message {
         code: u32,
         message: String,
     },
+
+    /// Start pairing - sent by a new client instead of Hello
+    PairRequest {
+        screen_info: ScreenInfo,
+        /// Client's PAKE message
+        pake: Vec<u8>,
+    },
+
+    /// Server's PAKE message in response to PairRequest
+    PairResponse {
+        pake: Vec<u8>,
+    },
+
+    /// Key confirmation proving the sender derived the same pairing key
+    PairConfirm {
+        mac: Vec<u8>,
+    },
+
+    /// Register with a relay - a server offers a connection under its own
+    /// host ID, a client asks for the server with that host ID
+    RelayRegister {
+        role: RelayRole,
+        host_id: String,
+        /// Secret shared by the server and its clients
+        token: String,
+    },
+
+    /// The relay has joined this connection to a peer; everything after
+    /// this is the peer's stream
+    RelayJoined {
+        /// Address the peer reached the relay from
+        peer_addr: SocketAddr,
+    },
+
+    /// Pointer motion datagrams are arriving over the UDP channel
+    MotionAck,
 }
 
 impl Message {
     /// Get the message type identifier
     pub fn type_id(&self) -> u8 {
         match self {
-            Message::Hello { .. } => 0x01,
-            Message::HelloAck { .. } => 0x02,
-            Message::MouseMoveRelative { .. } => 0x10,
-            Message::MouseMoveAbsolute { .. } => 0x11,
-            Message::MouseButton { .. } => 0x12,
-            Message::MouseScroll { .. } => 0x13,
-            Message::KeyDown { .. } => 0x20,
-            Message::KeyUp { .. } => 0x21,
-            Message::EnterScreen { .. } => 0x30,
-            Message::LeaveScreen { .. } => 0x31,
-            Message::ClipboardData { .. } => 0x40,
-            Message::ClipboardRequest => 0x41,
-            Message::GrabKeyboard => 0x50,
-            Message::ReleaseKeyboard => 0x51,
-            Message::Heartbeat { .. } => 0xF0,
-            Message::HeartbeatAck { .. } => 0xF1,
-            Message::Disconnect { .. } => 0xFE,
-            Message::Error { .. } => 0xFF,
+            Message::Hello { .. } => message_types::HELLO,
+            Message::HelloAck { .. } => message_types::HELLO_ACK,
+            Message::MouseMoveRelative { .. } => message_types::MOUSE_MOVE_RELATIVE,
+            Message::MouseMoveAbsolute { .. } => message_types::MOUSE_MOVE_ABSOLUTE,
+            Message::MouseButton { .. } => message_types::MOUSE_BUTTON,
+            Message::MouseScroll { .. } => message_types::MOUSE_SCROLL,
+            Message::KeyDown { .. } => message_types::KEY_DOWN,
+            Message::KeyUp { .. } => message_types::KEY_UP,
+            Message::EnterScreen { .. } => message_types::ENTER_SCREEN,
+            Message::LeaveScreen { .. } => message_types::LEAVE_SCREEN,
+            Message::ClipboardData { .. } => message_types::CLIPBOARD_DATA,
+            Message::ClipboardRequest => message_types::CLIPBOARD_REQUEST,
+            Message::GrabKeyboard => message_types::GRAB_KEYBOARD,
+            Message::ReleaseKeyboard => message_types::RELEASE_KEYBOARD,
+            Message::PairRequest { .. } => message_types::PAIR_REQUEST,
+            Message::PairResponse { .. } => message_types::PAIR_RESPONSE,
+            Message::PairConfirm { .. } => message_types::PAIR_CONFIRM,
+            Message::RelayRegister { .. } => message_types::RELAY_REGISTER,
+            Message::RelayJoined { .. } => message_types::RELAY_JOINED,
+            Message::Heartbeat { .. } => message_types::HEARTBEAT,
+            Message::HeartbeatAck { .. } => message_types::HEARTBEAT_ACK,
+            Message::MotionAck => message_types::MOTION_ACK,
+            Message::Disconnect { .. } => message_types::DISCONNECT,
+            Message::Error { .. } => message_types::ERROR,
         }
     }
 
+    /// Check if `type_id` is the type of a message this build knows
+    pub fn is_known_type(type_id: u8) -> bool {
+        MessageType::get(type_id).is_some()
+    }
+
+    /// Check if this is pointer motion, which may go over UDP
+    pub fn is_pointer_motion(&self) -> bool {
+        matches!(
+            self,
+            Message::MouseMoveRelative { .. } | Message::MouseMoveAbsolute { .. }
+        )
+    }
+
     /// Check if this is an input event message
     pub fn is_input_event(&self) -> bool {
         matches!(
@@ -241,12 +431,110 @@ impl Message {
     }
 }
 
+/// Message type IDs, as written in the frame header
+pub mod message_types {
+    pub const HELLO: u8 = 0x01;
+    pub const HELLO_ACK: u8 = 0x02;
+    pub const MOUSE_MOVE_RELATIVE: u8 = 0x10;
+    pub const MOUSE_MOVE_ABSOLUTE: u8 = 0x11;
+    pub const MOUSE_BUTTON: u8 = 0x12;
+    pub const MOUSE_SCROLL: u8 = 0x13;
+    pub const KEY_DOWN: u8 = 0x20;
+    pub const KEY_UP: u8 = 0x21;
+    pub const ENTER_SCREEN: u8 = 0x30;
+    pub const LEAVE_SCREEN: u8 = 0x31;
+    pub const CLIPBOARD_DATA: u8 = 0x40;
+    pub const CLIPBOARD_REQUEST: u8 = 0x41;
+    pub const GRAB_KEYBOARD: u8 = 0x50;
+    pub const RELEASE_KEYBOARD: u8 = 0x51;
+    pub const PAIR_REQUEST: u8 = 0x60;
+    pub const PAIR_RESPONSE: u8 = 0x61;
+    pub const PAIR_CONFIRM: u8 = 0x62;
+    pub const RELAY_REGISTER: u8 = 0x70;
+    pub const RELAY_JOINED: u8 = 0x71;
+    pub const HEARTBEAT: u8 = 0xF0;
+    pub const HEARTBEAT_ACK: u8 = 0xF1;
+    pub const MOTION_ACK: u8 = 0xF2;
+    pub const DISCONNECT: u8 = 0xFE;
+    pub const ERROR: u8 = 0xFF;
+}
+
+/// How messages of one type travel on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageType {
+    /// Type ID in the frame header
+    pub id: u8,
+    /// Largest payload accepted, with room to spare over the largest an
+    /// honest peer sends
+    pub max_payload: usize,
+    /// Written in the compact input layout once the session's protocol
+    /// version has it, rather than as bincode
+    pub compact: bool,
+}
+
+impl MessageType {
+    const fn new(id: u8, max_payload: usize, compact: bool) -> Self {
+        Self { id, max_payload, compact }
+    }
+
+    /// Look up a type this build knows by its ID
+    pub fn get(id: u8) -> Option<&'static MessageType> {
+        MESSAGE_TYPES.iter().find(|ty| ty.id == id)
+    }
+}
+
+/// Every message type this build knows
+pub const MESSAGE_TYPES: &[MessageType] = {
+    use message_types::*;
+    &[
+        // Handshake
+        MessageType::new(HELLO, 4096, false),
+        MessageType::new(HELLO_ACK, 4096, false),
+        // Input
+        MessageType::new(MOUSE_MOVE_RELATIVE, 64, true),
+        MessageType::new(MOUSE_MOVE_ABSOLUTE, 64, true),
+        MessageType::new(MOUSE_BUTTON, 64, true),
+        MessageType::new(MOUSE_SCROLL, 64, true),
+        MessageType::new(KEY_DOWN, 64, true),
+        MessageType::new(KEY_UP, 64, true),
+        // Screen transitions
+        MessageType::new(ENTER_SCREEN, 64, false),
+        MessageType::new(LEAVE_SCREEN, 64, false),
+        // Clipboard data may be as large as any frame; a request carries nothing
+        MessageType::new(CLIPBOARD_DATA, MAX_MESSAGE_SIZE, false),
+        MessageType::new(CLIPBOARD_REQUEST, 32, false),
+        // Keyboard grabs
+        MessageType::new(GRAB_KEYBOARD, 32, false),
+        MessageType::new(RELEASE_KEYBOARD, 32, false),
+        // Pairing
+        MessageType::new(PAIR_REQUEST, 4096, false),
+        MessageType::new(PAIR_RESPONSE, 4096, false),
+        MessageType::new(PAIR_CONFIRM, 4096, false),
+        // Relay
+        MessageType::new(RELAY_REGISTER, 4096, false),
+        MessageType::new(RELAY_JOINED, 4096, false),
+        // Heartbeats
+        MessageType::new(HEARTBEAT, 32, false),
+        MessageType::new(HEARTBEAT_ACK, 32, false),
+        // Motion channel acks
+        MessageType::new(MOTION_ACK, 32, false),
+        // Disconnect and error
+        MessageType::new(DISCONNECT, 4096, false),
+        MessageType::new(ERROR, 4096, false),
+    ]
+};
+
 /// Error codes for the Error message
 pub mod error_codes {
     pub const PROTOCOL_MISMATCH: u32 = 1;
     pub const AUTHENTICATION_FAILED: u32 = 2;
     pub const HOST_NOT_FOUND: u32 = 3;
     pub const CONNECTION_REFUSED: u32 = 4;
+    pub const HANDSHAKE_TIMEOUT: u32 = 5;
+    pub const SERVER_FULL: u32 = 6;
+    pub const RATE_LIMITED: u32 = 7;
+    pub const SERVER_BUSY: u32 = 8;
+    pub const DUPLICATE_HOST: u32 = 9;
     pub const INTERNAL_ERROR: u32 = 100;
 }
 
@@ -254,6 +542,28 @@ pub mod error_codes {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_version_negotiation() {
+        let ours = VersionRange { min: 3, max: 5 };
+        assert_eq!(ours.highest_common(&VersionRange { min: 4, max: 7 }), Some(5));
+        assert_eq!(ours.highest_common(&VersionRange { min: 1, max: 3 }), Some(3));
+        assert_eq!(ours.highest_common(&VersionRange { min: 6, max: 6 }), None);
+        assert_eq!(ours.to_string(), "3-5");
+    }
+
+    #[test]
+    fn test_feature_intersection() {
+        let ours = Features(Features::CLIPBOARD.0 | Features::UDP_MOTION.0);
+        // A newer peer may offer bits we don't know
+        let theirs = Features(Features::UDP_MOTION.0 | 0x8000_0000);
+
+        let agreed = ours.intersection(theirs);
+        assert_eq!(agreed, Features::UDP_MOTION);
+        assert!(!agreed.contains(Features::CLIPBOARD));
+        assert_eq!(ours.to_string(), "clipboard, udp-motion");
+        assert_eq!(Features::NONE.to_string(), "none");
+    }
+
     #[test]
     fn test_modifiers_roundtrip() {
         let mods = Modifiers {
@@ -273,5 +583,14 @@ mod tests {
     fn test_message_type_ids() {
         let msg = Message::Heartbeat { timestamp: 0 };
         assert_eq!(msg.type_id(), 0xF0);
+        assert!(Message::is_known_type(msg.type_id()));
+        assert!(!Message::is_known_type(0x03));
+
+        // Each type is listed once
+        for (i, ty) in MESSAGE_TYPES.iter().enumerate() {
+            assert!(MESSAGE_TYPES[i + 1..].iter().all(|other| other.id != ty.id));
+        }
+        assert!(MessageType::get(message_types::KEY_UP).unwrap().compact);
+        assert!(!MessageType::get(message_types::ENTER_SCREEN).unwrap().compact);
     }
 }
@@ -1,19 +1,27 @@
 //! Protocol module - Defines the wire protocol for CoreNet communication
 //!
 //! The protocol uses a simple binary format for efficiency:
+//! - 4 bytes magic ("CNET")
 //! - 1 byte message type
 //! - 4 bytes payload length (big-endian)
 //! - 4 bytes sequence number (big-endian)
-//! - Variable length payload
+//! - Variable length payload: the compact layout in `compact` for input
+//!   messages, bincode for everything else
 
 mod message;
 mod codec;
+mod compact;
 
 pub use message::*;
 pub use codec::*;
 
-/// Protocol version for compatibility checking
-pub const PROTOCOL_VERSION: u32 = 1;
+/// Newest protocol version this build speaks
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// Oldest protocol version this build still speaks. Peers agree on the
+/// highest version both ends have in range, so this only moves up when
+/// support for an old wire format is dropped.
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
 
 /// Default port for CoreNet communication
 pub const DEFAULT_PORT: u16 = 24800;